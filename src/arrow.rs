@@ -0,0 +1,177 @@
+//! Conversion of records to Arrow record batches, behind the `arrow`
+//! feature, for handing extraction results to Arrow-based engines such as
+//! DataFusion or Polars without a round trip through text.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use ::arrow::array::{
+    ArrayRef, BooleanArray, Float64Array, Int64Array, StringArray, TimestampSecondArray,
+};
+use ::arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use ::arrow::error::ArrowError;
+use ::arrow::record_batch::RecordBatch;
+use serde_json::Value;
+
+use crate::format::Format;
+use crate::warehouse::{ColumnType, TypeInference};
+use crate::{time, Record, SubSchema};
+
+/// The Arrow type a column of `ty` is stored as. Timestamps are seconds in
+/// UTC; nested values are JSON text.
+pub fn data_type(ty: ColumnType) -> DataType {
+    match ty {
+        ColumnType::Boolean => DataType::Boolean,
+        ColumnType::Integer => DataType::Int64,
+        ColumnType::Float => DataType::Float64,
+        ColumnType::Timestamp => DataType::Timestamp(TimeUnit::Second, Some("+00:00".into())),
+        ColumnType::String | ColumnType::Json => DataType::Utf8,
+    }
+}
+
+/// An Arrow schema with a nullable field per column.
+pub fn arrow_schema(types: &[(String, ColumnType)]) -> SchemaRef {
+    let fields: Vec<Field> = types
+        .iter()
+        .map(|(name, ty)| Field::new(name.clone(), data_type(*ty), true))
+        .collect();
+    Arc::new(Schema::new(fields))
+}
+
+/// Infers the Arrow schema of `records`: the schema's columns come first,
+/// columns with a `Format` keep its declared type, and the rest are typed
+/// from their values (see `TypeInference`).
+pub fn infer_schema(schema: &SubSchema, records: &[Record]) -> SchemaRef {
+    let mut inference = TypeInference::new(schema.columns(), &schema.formats());
+    for record in records {
+        inference.observe(record);
+    }
+    arrow_schema(&inference.types())
+}
+
+/// Converts `records` to a batch with the fields of `schema`, after
+/// applying `formats`. Columns missing from a record are null; a value that
+/// doesn't fit its field's type is an `InvalidArgumentError`, except that
+/// any value can be written to a string field.
+pub fn record_batch(
+    schema: SchemaRef,
+    records: &[Record],
+    formats: &HashMap<String, Format>,
+) -> Result<RecordBatch, ArrowError> {
+    let columns = schema
+        .fields()
+        .iter()
+        .map(|field| column(records, field.name(), field.data_type(), formats))
+        .collect::<Result<_, _>>()?;
+    RecordBatch::try_new(schema, columns)
+}
+
+/// `infer_schema` and `record_batch` in one step.
+pub fn to_record_batch(schema: &SubSchema, records: &[Record]) -> Result<RecordBatch, ArrowError> {
+    record_batch(infer_schema(schema, records), records, &schema.formats())
+}
+
+/// The values of column `name` across `records` as an array of `ty`.
+fn column(
+    records: &[Record],
+    name: &str,
+    ty: &DataType,
+    formats: &HashMap<String, Format>,
+) -> Result<ArrayRef, ArrowError> {
+    let format = formats.get(name);
+    let values = records.iter().map(|record| {
+        let value = record
+            .iter()
+            .find(|(column, _)| column == name)
+            .and_then(|(_, value)| value.as_ref())
+            .filter(|value| !value.is_null())?;
+        let value = match format {
+            Some(format) => format.apply(value),
+            None => value.clone(),
+        };
+        (!value.is_null()).then_some(value)
+    });
+    Ok(match ty {
+        DataType::Boolean => {
+            Arc::new(fitted(values, name, ty, Value::as_bool).collect::<Result<BooleanArray, _>>()?)
+        }
+        DataType::Int64 => {
+            Arc::new(fitted(values, name, ty, Value::as_i64).collect::<Result<Int64Array, _>>()?)
+        }
+        DataType::Float64 => {
+            Arc::new(fitted(values, name, ty, Value::as_f64).collect::<Result<Float64Array, _>>()?)
+        }
+        DataType::Timestamp(..) => Arc::new(
+            fitted(values, name, ty, time::timestamp_of)
+                .collect::<Result<TimestampSecondArray, _>>()?
+                .with_timezone_utc(),
+        ),
+        _ => Arc::new(
+            values
+                .map(|v| {
+                    v.map(|v| match v {
+                        Value::String(s) => s,
+                        other => other.to_string(),
+                    })
+                })
+                .collect::<StringArray>(),
+        ),
+    })
+}
+
+/// `values` converted by `convert`, where `None` means a value doesn't fit
+/// the field.
+fn fitted<'a, T: 'a>(
+    values: impl Iterator<Item = Option<Value>> + 'a,
+    name: &'a str,
+    ty: &'a DataType,
+    convert: fn(&Value) -> Option<T>,
+) -> impl Iterator<Item = Result<Option<T>, ArrowError>> + 'a {
+    values.map(move |value| match value {
+        Some(value) => convert(&value).map(Some).ok_or_else(|| {
+            ArrowError::InvalidArgumentError(format!("column {name:?}: {value} is not {ty}"))
+        }),
+        None => Ok(None),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{doc, key};
+    use serde_json::json;
+
+    #[test]
+    fn converts_records_to_a_typed_batch() {
+        let schema = doc! { key!("id"), key!("seen"), key!("score"), key!("tags") };
+        let mut records = schema
+            .extract(&json!({
+                "id": 1,
+                "seen": "2024-01-02T03:04:05Z",
+                "score": 1.5,
+                "tags": ["a"],
+            }))
+            .unwrap();
+        records.push(vec![("id".into(), Some(json!("two")))]);
+
+        let batch = to_record_batch(&schema, &records).unwrap();
+        let types: Vec<DataType> = batch
+            .schema()
+            .fields()
+            .iter()
+            .map(|field| field.data_type().clone())
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                DataType::Utf8,
+                data_type(ColumnType::Timestamp),
+                DataType::Float64,
+                DataType::Utf8,
+            ]
+        );
+        assert_eq!(batch.num_rows(), 2);
+        assert!(batch.column(1).is_null(1));
+        assert!(!batch.column(0).is_null(1));
+    }
+}
@@ -0,0 +1,155 @@
+//! Invariants over a whole run's output, checked once the last record is
+//! written so a job can fail instead of publishing bad data.
+
+use std::collections::{HashMap, HashSet};
+
+use serde_json::Value;
+
+use crate::Record;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Assertion {
+    /// At least this many records.
+    MinRecords(usize),
+    /// At most this many records.
+    MaxRecords(usize),
+    /// At most this fraction (0.0 to 1.0) of records have the column
+    /// missing or null.
+    MaxNullRate(String, f64),
+    /// No two records share a non-null value in the column.
+    Unique(String),
+}
+
+/// Accumulates what the assertions need while records stream past.
+#[derive(Debug)]
+pub struct RunAssertions {
+    assertions: Vec<Assertion>,
+    records: usize,
+    nulls: HashMap<String, usize>,
+    seen: HashMap<String, HashSet<String>>,
+    duplicates: HashMap<String, usize>,
+}
+
+impl RunAssertions {
+    pub fn new(assertions: Vec<Assertion>) -> Self {
+        RunAssertions {
+            assertions,
+            records: 0,
+            nulls: HashMap::new(),
+            seen: HashMap::new(),
+            duplicates: HashMap::new(),
+        }
+    }
+
+    pub fn observe(&mut self, record: &Record) {
+        self.records += 1;
+        for assertion in &self.assertions {
+            match assertion {
+                Assertion::MaxNullRate(column, _) => {
+                    if value(record, column).is_none() {
+                        *self.nulls.entry(column.clone()).or_default() += 1;
+                    }
+                }
+                Assertion::Unique(column) => {
+                    if let Some(value) = value(record, column) {
+                        let seen = self.seen.entry(column.clone()).or_default();
+                        if !seen.insert(value.to_string()) {
+                            *self.duplicates.entry(column.clone()).or_default() += 1;
+                        }
+                    }
+                }
+                Assertion::MinRecords(_) | Assertion::MaxRecords(_) => {}
+            }
+        }
+    }
+
+    /// Fails with a message for every violated assertion.
+    pub fn check(&self) -> Result<(), Vec<String>> {
+        let violations: Vec<String> = self
+            .assertions
+            .iter()
+            .filter_map(|assertion| match assertion {
+                Assertion::MinRecords(min) if self.records < *min => Some(format!(
+                    "expected at least {min} records, got {}",
+                    self.records
+                )),
+                Assertion::MaxRecords(max) if self.records > *max => Some(format!(
+                    "expected at most {max} records, got {}",
+                    self.records
+                )),
+                Assertion::MaxNullRate(column, max) => {
+                    let nulls = self.nulls.get(column).copied().unwrap_or(0);
+                    let rate = if self.records == 0 {
+                        0.0
+                    } else {
+                        nulls as f64 / self.records as f64
+                    };
+                    (rate > *max).then(|| {
+                        format!(
+                            "{column}: null rate {rate:.4} exceeds {max} ({nulls} of {})",
+                            self.records
+                        )
+                    })
+                }
+                Assertion::Unique(column) => self
+                    .duplicates
+                    .get(column)
+                    .map(|count| format!("{column}: {count} duplicate values")),
+                _ => None,
+            })
+            .collect();
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+}
+
+fn value<'r>(record: &'r Record, column: &str) -> Option<&'r Value> {
+    record
+        .iter()
+        .find(|(name, _)| name == column)
+        .and_then(|(_, value)| value.as_ref())
+        .filter(|value| !value.is_null())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reports_violations() {
+        let records: Vec<Record> = vec![
+            vec![("id".into(), Some(1.into())), ("email".into(), None)],
+            vec![
+                ("id".into(), Some(1.into())),
+                ("email".into(), Some("a@b".into())),
+            ],
+            vec![("id".into(), None), ("email".into(), Some(Value::Null))],
+        ];
+        let mut run = RunAssertions::new(vec![
+            Assertion::MinRecords(3),
+            Assertion::MaxRecords(2),
+            Assertion::MaxNullRate("email".into(), 0.5),
+            Assertion::Unique("id".into()),
+        ]);
+        for record in &records {
+            run.observe(record);
+        }
+
+        assert_eq!(
+            run.check(),
+            Err(vec![
+                "expected at most 2 records, got 3".to_string(),
+                "email: null rate 0.6667 exceeds 0.5 (2 of 3)".to_string(),
+                "id: 1 duplicate values".to_string(),
+            ])
+        );
+        assert_eq!(
+            RunAssertions::new(vec![Assertion::MaxRecords(0)]).check(),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,1508 @@
+//! Flattens JSON documents from a file into CSV or JSON lines:
+//!
+//! ```text
+//! flatten --schema schema.json --input data.json --output out.csv --format csv
+//! ```
+//!
+//! It is only built with the `cli` feature (`cargo install --features cli`),
+//! so the library alone doesn't pull in its dependencies.
+//!
+//! Schemas ending in `.json`, `.yaml` or `.yml` are `config::SchemaConfig`
+//! files; anything else is read as `dsl` text; `--overlay` layers a
+//! tenant's config over such a schema. A config's `flags` are appended to
+//! each record as boolean columns. The input is one document, an array
+//! of documents or newline-delimited documents; `.jsonl` and `.ndjson`
+//! inputs, and any input with `--ndjson`, stdin included, are streamed a
+//! line at a time; other inputs are read whole. `-` or no
+//! `--input`/`--output` means stdin/stdout. The format defaults to the
+//! output's extension, else CSV, whose `--delimiter`, `--quote-all`,
+//! `--crlf`, `--bom`, `--no-header` and `--null` marker can be set for
+//! picky loaders (see `csv::Dialect`).
+//!
+//! `--geoip` opens a MaxMind database, e.g. `GeoLite2-City.mmdb`, whose
+//! lookups JSON schemas can then use as `geoip_country`, `geoip_region`,
+//! `geoip_asn` or `geoip_asn_org` transforms; it can be given once per
+//! database and needs the `geoip` feature.
+//!
+//! With `--checksums`, the input is verified against a `sha256sum`-style
+//! manifest before anything is read from it.
+//!
+//! `--filter-doc` skips documents not matching a `filter` expression over
+//! the whole document before they are extracted, and `--since` and
+//! `--until` those whose `--time-field` timestamp is out of range, or
+//! missing (see `stage::TimeRange`), for partial backfills. `--sparse`
+//! writes one `(row_id, column, value)` row per non-null value instead of
+//! wide rows, with `document-record` row ids (see `stage::Eav`).
+//! `--sample-rate` keeps a fraction of the records, the same ones for the
+//! same `--seed` (see `stage::Sampler`).
+//!
+//! `--no-raw-pii`, for outputs not approved for raw PII, fails with 4
+//! before reading anything unless every `pii` key of a config has an
+//! `anonymize` transform (see `SubSchema::check_pii`).
+//!
+//! Documents that aren't JSON or fail extraction are logged and skipped,
+//! failing the run once more than `--max-failures` are, or when all of
+//! them are. `--strict` fails it on the first one instead, and also checks
+//! documents for missing `required` keys and keys repeated within an
+//! object (see `strict::duplicate_keys`). The exit code says how a run
+//! ended, so orchestration can branch on it: 0 for success; 1 for other
+//! errors, e.g. an unreadable input or a changed checksum; 2 for bad
+//! arguments; 3 for a document that isn't JSON under `--strict`, or an
+//! input with none that are; 4 for a schema that can't be loaded or built;
+//! 5 for a document failing extraction or repeating keys under `--strict`,
+//! or too many or all of them failing; 6 for output that can't be written;
+//! and 7 for a partial success, where everything but the skipped documents
+//! was written.
+//!
+//! Strings from untrusted documents can be made safe to open in a
+//! spreadsheet: `--sanitize-formulas` keeps them from being evaluated as
+//! formulas and `--strip-control-chars` drops control characters (see
+//! `sanitize::Sanitize`). For systems that choke on combining characters,
+//! `--nfc` composes them (needing the `unicode` feature), and CSV can be
+//! written as `--encoding latin1` or `ascii` instead of UTF-8.
+//!
+//! `--warehouse` names the warehouse the output is loaded into, e.g.
+//! `bigquery`; CSV values are then written the way its bulk loader reads
+//! them (see `warehouse::Warehouse::render`). With `--previous-schema`,
+//! migration hints for the columns that changed since that schema are
+//! written to stderr after the run, as `--warehouse` SQL (default
+//! Postgres) for `--table` (default the output's file stem).
+//!
+//! `--run-manifest` records the run for audits: its arguments, working
+//! directory, the SHA-256 of every file it read and the schema's
+//! fingerprint. The run can then be repeated, to an `--output` of its own
+//! if given, after checking that none of them changed:
+//!
+//! ```text
+//! flatten replay --manifest runs/2024-05-01.json --output replayed.csv
+//! ```
+//!
+//! `--append` adds to the `--output` file instead of replacing it. When a
+//! CSV file's header differs from the schema's columns, `--evolution`
+//! decides what happens: `fail`, the default, refuses to append;
+//! `add-null-column` leaves the columns the schema no longer has empty;
+//! `drop-extra` also leaves out the columns the file lacks (see
+//! `csv::Evolution`).
+//!
+//! Concurrent runs, e.g. one per input under an orchestrator, can share an
+//! `--output-dir` instead: each writes its output to a part file of its
+//! own, `part-{run}-NNNNN.csv` for its `--run-id`, and once the run
+//! succeeds lists it with its record count in the directory's
+//! `_manifest.jsonl`, appended to under a lock file (see
+//! `manifest::OutputDir`). Loaders should only read the listed parts.
+//!
+//! A config with a `route` or `tables` splits its records between tables
+//! in one pass over the input (see `config::SchemaConfig::table`), which
+//! `--tables-dir` writes to a file each, `{table}.csv` or `{table}.jsonl`.
+//!
+//! `--idempotent` names a state file of the inputs already processed,
+//! by their SHA-256 and the schema's fingerprint, skipping the run if its
+//! input is listed, so a retried task doesn't write its output twice.
+//! Runs are listed once their output is written and assertions pass,
+//! unless all their documents failed.
+//!
+//! `--output-manifest` lists the `--output` file once it is written, with
+//! its record count, size in bytes and SHA-256 (see `checksum::describe`),
+//! so downstream loaders can verify they received all of it intact. A
+//! config's `assertions` are checked over the records once they are all
+//! written; if any fails, the run fails with 5 and writes no output
+//! manifest.
+//!
+//! `--report` writes a JSON summary of the run: its `documents`, `records`
+//! and `skipped` counts, and under `nodes` how often each schema node
+//! matched, was missing or had its transform fail (see `Metrics`), for
+//! spotting paths the data never reaches. Under `columns` it estimates
+//! each column's distinct values and numeric quantiles (see
+//! `sketch::ColumnSketches`), with `--report-examples` values of each;
+//! examples of `pii` columns are masked.
+//!
+//! `--log-format json` writes diagnostics as one JSON object per line, for
+//! log aggregation: skipped documents with their `document` offset, error
+//! `code` (the exit code their failure would have under `--strict`) and
+//! `error`, the run's counts once it finishes, and what ended a failed run.
+//!
+//! `test` runs the `tests` a JSON or YAML schema embeds, printing each
+//! one's outcome, and fails with 5 if any does, so schema changes can be
+//! checked in CI:
+//!
+//! ```text
+//! flatten test schema.yaml
+//! ```
+//!
+//! `test-transform` runs a transform of the registry on sample JSON values,
+//! printing what each becomes, and fails if one is rejected or differs from
+//! `--expect`. `missing` stands for an absent value, the input when none is
+//! given:
+//!
+//! ```text
+//! flatten test-transform parse_int --input '"42"' --input '"x"' --expect 42
+//! ```
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Read, Write};
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use serde_json::{json, Value};
+
+use serde_test::assertion::RunAssertions;
+use serde_test::checksum::{self, Checksums};
+use serde_test::config::{ConfigError, Registry, SchemaConfig};
+use serde_test::csv::{CsvWriter, Dialect, Quoting};
+use serde_test::encoding::Encoding;
+use serde_test::filter::Filter;
+#[cfg(feature = "geoip")]
+use serde_test::geoip::GeoIp;
+use serde_test::json::{JsonLinesWriter, JsonOptions};
+use serde_test::manifest::OutputDir;
+use serde_test::ndjson::{DuplicateKeys, NdjsonReader};
+use serde_test::sanitize::Sanitize;
+use serde_test::sketch::{ColumnSketches, PiiExamples};
+use serde_test::stage::{DerivedFlags, Eav, Router, RowId, RowIdKind, Sampler, TimeRange};
+use serde_test::warehouse::{ColumnDiff, TypeInference, Warehouse};
+use serde_test::{dsl, strict, time, Metrics, Record, SubSchema};
+
+/// Flattens JSON documents into CSV or JSON lines.
+#[derive(Debug, Parser)]
+#[command(
+    name = "flatten",
+    args_conflicts_with_subcommands = true,
+    arg_required_else_help = true
+)]
+struct Cli {
+    /// How diagnostics are written to stderr.
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    run: Option<Args>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+enum LogFormat {
+    Text,
+    /// One JSON object per event, for log aggregation.
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Repeats a run recorded with `--run-manifest`.
+    Replay {
+        #[arg(long)]
+        manifest: String,
+        /// Where to write instead of the recorded run's output.
+        #[arg(long)]
+        output: Option<String>,
+    },
+    /// Runs the tests embedded in a JSON or YAML schema.
+    Test {
+        schema: String,
+        /// A tenant's config layered over the schema.
+        #[arg(long)]
+        overlay: Option<String>,
+    },
+    /// Runs a registered transform on sample values.
+    TestTransform {
+        name: String,
+        /// A JSON value, or `missing` for none; may be repeated.
+        #[arg(long = "input", value_parser = sample)]
+        inputs: Vec<Sample>,
+        /// The value every input should become.
+        #[arg(long, value_parser = sample)]
+        expect: Option<Sample>,
+    },
+}
+
+#[derive(Debug, Parser)]
+struct Args {
+    /// A JSON or YAML config, or schema text.
+    #[arg(long, required = true)]
+    schema: String,
+    /// A tenant's config layered over the schema.
+    #[arg(long)]
+    overlay: Option<String>,
+    /// Defaults to stdin.
+    #[arg(long)]
+    input: Option<String>,
+    /// Streams the input one document per line, as `.jsonl` and `.ndjson`
+    /// inputs are.
+    #[arg(long)]
+    ndjson: bool,
+    /// Defaults to stdout.
+    #[arg(long)]
+    output: Option<String>,
+    /// A directory shared with concurrent runs, to write the output to as
+    /// a part file listed in its manifest.
+    #[arg(long, conflicts_with_all = ["output", "output_manifest"])]
+    output_dir: Option<String>,
+    /// Names the run's parts in `--output-dir`; defaults to the start time
+    /// and process id.
+    #[arg(long, requires = "output_dir")]
+    run_id: Option<String>,
+    /// Defaults to the output's extension, else CSV.
+    #[arg(long, value_parser = ["csv", "json"])]
+    format: Option<String>,
+    /// Separates CSV fields; defaults to `,`.
+    #[arg(long)]
+    delimiter: Option<char>,
+    /// Quotes every CSV field, not just those that need it.
+    #[arg(long)]
+    quote_all: bool,
+    /// Ends CSV rows with CRLF instead of LF.
+    #[arg(long)]
+    crlf: bool,
+    /// Starts CSV output with a byte order mark.
+    #[arg(long)]
+    bom: bool,
+    /// Leaves out the CSV header row.
+    #[arg(long)]
+    no_header: bool,
+    /// Written for JSON `null` in CSV, e.g. `\N`, unlike missing values.
+    #[arg(long)]
+    null: Option<String>,
+    /// Prefixes strings starting with `=`, `+`, `-` or `@` with `'`, so
+    /// spreadsheets don't evaluate them as formulas.
+    #[arg(long)]
+    sanitize_formulas: bool,
+    /// Removes control characters other than tab and line breaks from
+    /// strings.
+    #[arg(long)]
+    strip_control_chars: bool,
+    /// Composes strings to Unicode Normalization Form C; needs the
+    /// `unicode` feature.
+    #[arg(long)]
+    nfc: bool,
+    /// Encodes CSV output, writing `?` for characters outside Latin-1 or
+    /// ASCII.
+    #[arg(long, value_parser = ["utf8", "latin1", "ascii"])]
+    encoding: Option<String>,
+    /// A `sha256sum` manifest to verify the input against.
+    #[arg(long)]
+    checksums: Option<String>,
+    /// Skips documents not matching this filter expression.
+    #[arg(long)]
+    filter_doc: Option<String>,
+    /// Dot-separated path to the timestamp `--since` and `--until` read.
+    #[arg(long)]
+    time_field: Option<String>,
+    /// Skips documents stamped before this RFC 3339 timestamp or date.
+    #[arg(long, requires = "time_field", value_parser = timestamp)]
+    since: Option<i64>,
+    /// Skips documents stamped at or after this RFC 3339 timestamp or date.
+    #[arg(long, requires = "time_field", value_parser = timestamp)]
+    until: Option<i64>,
+    /// Fails on the first document that isn't JSON, misses a required key
+    /// or repeats a key within an object, instead of skipping it.
+    #[arg(long)]
+    strict: bool,
+    /// Fails once more than this many documents have been skipped.
+    #[arg(long)]
+    max_failures: Option<usize>,
+    /// Writes one row per non-null value.
+    #[arg(long)]
+    sparse: bool,
+    /// Keeps this fraction of records, from 0 to 1, chosen by `--seed`.
+    #[arg(long, value_parser = fraction)]
+    sample_rate: Option<f64>,
+    /// Seeds `--sample-rate`, so the same seed keeps the same records;
+    /// defaults to 0.
+    #[arg(long, requires = "sample_rate")]
+    seed: Option<u64>,
+    /// Fails unless every `pii` column is anonymized, for outputs not
+    /// approved for raw PII.
+    #[arg(long)]
+    no_raw_pii: bool,
+    /// A schema to write migration hints from.
+    #[arg(long)]
+    previous_schema: Option<String>,
+    /// The warehouse the output is loaded into, rendering CSV values for
+    /// its bulk loader; migration hints default to Postgres.
+    #[arg(long)]
+    warehouse: Option<String>,
+    /// The table of migration hints; defaults to the output's file stem.
+    #[arg(long)]
+    table: Option<String>,
+    /// A state file listing the inputs already processed, to skip the run
+    /// if it lists this one with the same schema, e.g. when orchestration
+    /// retries a task that had succeeded.
+    #[arg(long, requires = "input")]
+    idempotent: Option<String>,
+    /// Where to record the run for `replay`.
+    #[arg(long)]
+    run_manifest: Option<String>,
+    /// Where to list the output with its record count, size and SHA-256.
+    #[arg(long, conflicts_with = "append")]
+    output_manifest: Option<String>,
+    /// A directory to write each table of the config's `route` or `tables`
+    /// to, as `{table}.csv` or `{table}.jsonl`.
+    #[arg(
+        long,
+        conflicts_with_all = ["output", "output_dir", "append", "sparse", "previous_schema"],
+    )]
+    tables_dir: Option<String>,
+    /// Appends to the `--output` file instead of replacing it.
+    #[arg(long, requires = "output", conflicts_with = "output_dir")]
+    append: bool,
+    /// What to do when an appended CSV file's header differs from the
+    /// schema's columns.
+    #[arg(
+        long,
+        requires = "append",
+        value_parser = ["fail", "add-null-column", "drop-extra"],
+    )]
+    evolution: Option<String>,
+    /// Where to write the run's counts and per-node metrics.
+    #[arg(long)]
+    report: Option<String>,
+    /// Example values per column in the report.
+    #[arg(long, default_value_t = 0)]
+    report_examples: usize,
+    /// A MaxMind database; may be repeated.
+    #[arg(long)]
+    geoip: Vec<String>,
+}
+
+impl Args {
+    /// Every file the run reads.
+    fn files(&self) -> Vec<&str> {
+        let mut files = vec![self.schema.as_str()];
+        files.extend(self.overlay.as_deref());
+        files.extend(self.previous_schema.as_deref());
+        files.extend(self.checksums.as_deref());
+        files.extend(self.input.as_deref());
+        files.extend(self.geoip.iter().map(String::as_str));
+        files
+    }
+
+    fn dialect(&self) -> Dialect {
+        let default = Dialect::default();
+        Dialect {
+            delimiter: self.delimiter.unwrap_or(default.delimiter),
+            quoting: match self.quote_all {
+                true => Quoting::Always,
+                false => Quoting::Necessary,
+            },
+            terminator: if self.crlf { "\r\n" } else { "\n" }.to_string(),
+            bom: self.bom,
+            header: !self.no_header,
+            null: self.null.clone().unwrap_or_default(),
+            // `--encoding` only takes these.
+            encoding: match self.encoding.as_deref() {
+                Some("latin1") => Encoding::Latin1,
+                Some("ascii") => Encoding::Ascii,
+                _ => Encoding::Utf8,
+            },
+            ..default
+        }
+    }
+
+    fn sanitize(&self) -> Result<Sanitize, String> {
+        if self.nfc && cfg!(not(feature = "unicode")) {
+            return Err("--nfc needs flatten built with the unicode feature".to_string());
+        }
+        Ok(Sanitize {
+            formulas: self.sanitize_formulas,
+            control_chars: self.strip_control_chars,
+            nfc: self.nfc,
+        })
+    }
+
+    /// The arguments to repeat the run with, i.e. all but `--run-manifest`,
+    /// `--output-manifest`, `--report`, `--report-examples`, `--run-id`,
+    /// so a replay into an `--output-dir` commits parts of its own, and
+    /// `--idempotent`, which would skip it.
+    fn recorded(&self) -> Vec<String> {
+        let options = [
+            ("--schema", Some(&self.schema)),
+            ("--overlay", self.overlay.as_ref()),
+            ("--input", self.input.as_ref()),
+            ("--output", self.output.as_ref()),
+            ("--output-dir", self.output_dir.as_ref()),
+            ("--tables-dir", self.tables_dir.as_ref()),
+            ("--format", self.format.as_ref()),
+            ("--checksums", self.checksums.as_ref()),
+            ("--filter-doc", self.filter_doc.as_ref()),
+            ("--time-field", self.time_field.as_ref()),
+            ("--previous-schema", self.previous_schema.as_ref()),
+            ("--warehouse", self.warehouse.as_ref()),
+            ("--table", self.table.as_ref()),
+        ];
+        let max_failures = self.max_failures.map(|max| max.to_string());
+        let sample_rate = self.sample_rate.map(|rate| rate.to_string());
+        let seed = self.seed.map(|seed| seed.to_string());
+        let delimiter = self.delimiter.map(String::from);
+        let stamp = |secs| time::format_timestamp(secs, "%Y-%m-%dT%H:%M:%SZ");
+        let (since, until) = (self.since.map(stamp), self.until.map(stamp));
+        let options = options.into_iter().chain([
+            ("--since", since.as_ref()),
+            ("--until", until.as_ref()),
+            ("--delimiter", delimiter.as_ref()),
+            ("--null", self.null.as_ref()),
+            ("--encoding", self.encoding.as_ref()),
+            ("--evolution", self.evolution.as_ref()),
+            ("--max-failures", max_failures.as_ref()),
+            ("--sample-rate", sample_rate.as_ref()),
+            ("--seed", seed.as_ref()),
+        ]);
+        let geoip = self.geoip.iter().map(|path| ("--geoip", Some(path)));
+        let mut args: Vec<String> = options
+            .chain(geoip)
+            .filter_map(|(flag, value)| Some([flag.to_string(), value?.clone()]))
+            .flatten()
+            .collect();
+        let switches = [
+            ("--ndjson", self.ndjson),
+            ("--strict", self.strict),
+            ("--sparse", self.sparse),
+            ("--append", self.append),
+            ("--quote-all", self.quote_all),
+            ("--crlf", self.crlf),
+            ("--bom", self.bom),
+            ("--sanitize-formulas", self.sanitize_formulas),
+            ("--strip-control-chars", self.strip_control_chars),
+            ("--nfc", self.nfc),
+            ("--no-header", self.no_header),
+            ("--no-raw-pii", self.no_raw_pii),
+        ];
+        for (flag, set) in switches {
+            if set {
+                args.push(flag.to_string());
+            }
+        }
+        args
+    }
+}
+
+/// One document, an array of documents, or one document per line when
+/// every line is one; otherwise the input fails as a whole, rather than a
+/// pretty-printed document failing line by line. With `strict`, keys
+/// repeated within an object fail validation.
+fn documents(text: &str, strict: bool) -> Vec<Result<Value, Failure>> {
+    let check = |text: &str, at: &str| match strict.then(|| strict::duplicate_keys(text)) {
+        Some(duplicates) if !duplicates.is_empty() => Err(failed(Kind::Validation)(format!(
+            "{at}: duplicate keys {}",
+            duplicates.join(", ")
+        ))),
+        _ => Ok(()),
+    };
+    let e = match serde_json::from_str(text) {
+        Ok(value) => {
+            return match (check(text, "input"), value) {
+                (Err(e), _) => vec![Err(e)],
+                (Ok(()), Value::Array(documents)) => documents.into_iter().map(Ok).collect(),
+                (Ok(()), document) => vec![Ok(document)],
+            }
+        }
+        Err(e) => e,
+    };
+    let lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| Ok((i, line, serde_json::from_str(line)?)))
+        .collect::<Result<Vec<(usize, &str, Value)>, serde_json::Error>>();
+    match lines {
+        Ok(lines) => lines
+            .into_iter()
+            .map(|(i, line, document)| {
+                check(line, &format!("input line {}", i + 1))?;
+                Ok(document)
+            })
+            .collect(),
+        Err(_) => vec![Err(failed(Kind::Parse)(format!("input: {e}")))],
+    }
+}
+
+/// What kind of failure ended a run, as its exit code; bad arguments exit
+/// with clap's 2.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Other = 1,
+    Parse = 3,
+    Schema = 4,
+    Validation = 5,
+    Sink = 6,
+    Partial = 7,
+}
+
+#[derive(Debug, PartialEq)]
+struct Failure {
+    kind: Kind,
+    message: String,
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Self {
+        Failure {
+            kind: Kind::Other,
+            message,
+        }
+    }
+}
+
+/// Tags an error message with the kind of failure it is.
+fn failed(kind: Kind) -> impl Fn(String) -> Failure {
+    move |message| Failure { kind, message }
+}
+
+fn sink_failed(e: io::Error) -> Failure {
+    failed(Kind::Sink)(format!("writing output: {e}"))
+}
+
+/// What a schema is built from, kept alive while the schema borrows it.
+enum Source {
+    Text(String),
+    Config(Box<SchemaConfig>),
+}
+
+impl Source {
+    /// Schemas ending in `.json`, `.yaml` or `.yml` are configs, optionally
+    /// with an overlay in either format; anything else is schema text.
+    fn load(path: &str, overlay: Option<&str>) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("reading {path}: {e}"))?;
+        let Some(base) = config(path, &text)? else {
+            return match overlay {
+                Some(_) => Err("--overlay needs a JSON or YAML schema".to_string()),
+                None => Ok(Source::Text(text)),
+            };
+        };
+        let config = match overlay {
+            Some(overlay) => {
+                let text =
+                    fs::read_to_string(overlay).map_err(|e| format!("reading {overlay}: {e}"))?;
+                let top = config(overlay, &text)?.unwrap_or(Value::Null);
+                SchemaConfig::with_overlay(&base, &top)
+                    .map_err(|e| format!("{path} with {overlay}: {e}"))?
+            }
+            None => SchemaConfig::from_value(&base).map_err(|e| format!("{path}: {e}"))?,
+        };
+        Ok(Source::Config(Box::new(config)))
+    }
+
+    fn schema(&self, path: &str, registry: &Registry) -> Result<SubSchema<'_>, String> {
+        match self {
+            Source::Text(text) => dsl::parse(text).map_err(|e| format!("{path}: {e}")),
+            Source::Config(config) => config.schema(registry).map_err(|e| format!("{path}: {e}")),
+        }
+    }
+
+    /// The flag columns of a config's `flags` section; schema text has none.
+    fn flags(&self) -> DerivedFlags {
+        match self {
+            Source::Text(_) => DerivedFlags::new(),
+            Source::Config(config) => config.flags(),
+        }
+    }
+
+    fn assertions(&self) -> RunAssertions {
+        match self {
+            Source::Text(_) => RunAssertions::new(vec![]),
+            Source::Config(config) => config.assertions(),
+        }
+    }
+
+    /// A config's `route` section as a stage; schema text has none.
+    fn router(&self) -> Option<Router> {
+        match self {
+            Source::Text(_) => None,
+            Source::Config(config) => config.router(),
+        }
+    }
+
+    /// The configs of a config's `tables`, by table.
+    fn tables(&self) -> Vec<(String, SchemaConfig)> {
+        let Source::Config(config) = self else {
+            return vec![];
+        };
+        config
+            .tables
+            .keys()
+            .filter_map(|table| Some((table.clone(), config.table(table)?)))
+            .collect()
+    }
+
+    /// The schema's columns, then its flags'.
+    fn columns(&self, path: &str, registry: &Registry) -> Result<Vec<String>, String> {
+        let mut columns = self.schema(path, registry)?.columns();
+        columns.extend(self.flags().columns());
+        Ok(columns)
+    }
+}
+
+/// `text` parsed by `path`'s extension if it is a JSON or YAML config.
+fn config(path: &str, text: &str) -> Result<Option<Value>, String> {
+    let value = match Path::new(path).extension().and_then(|e| e.to_str()) {
+        Some("json") => serde_json::from_str(text).map_err(|e| e.to_string()),
+        Some("yaml" | "yml") => serde_yaml::from_str(text).map_err(|e| e.to_string()),
+        _ => return Ok(None),
+    };
+    value.map(Some).map_err(|e| format!("{path}: {e}"))
+}
+
+/// The built-in transforms, and those of any `--geoip` databases.
+fn registry(args: &Args) -> Result<Registry, String> {
+    #[cfg(feature = "geoip")]
+    {
+        let mut registry = Registry::builtin();
+        for path in args.geoip.iter() {
+            let geoip = GeoIp::open(Path::new(path)).map_err(|e| e.to_string())?;
+            registry = registry.geoip(&geoip);
+        }
+        Ok(registry)
+    }
+    #[cfg(not(feature = "geoip"))]
+    match args.geoip.is_empty() {
+        true => Ok(Registry::builtin()),
+        false => Err("--geoip needs flatten built with the geoip feature".to_string()),
+    }
+}
+
+fn run(args: Args) -> Result<(), Failure> {
+    let registry = registry(&args)?;
+    let source =
+        Source::load(&args.schema, args.overlay.as_deref()).map_err(failed(Kind::Schema))?;
+    let schema = source
+        .schema(&args.schema, &registry)
+        .map_err(failed(Kind::Schema))?;
+    schema
+        .check_columns()
+        .map_err(|columns| format!("{}: colliding columns {columns:?}", args.schema))
+        .map_err(failed(Kind::Schema))?;
+    if args.no_raw_pii {
+        schema
+            .check_pii()
+            .map_err(|columns| format!("{}: PII columns not anonymized {columns:?}", args.schema))
+            .map_err(failed(Kind::Schema))?;
+    }
+    let router = source.router();
+    let tables = source.tables();
+    let routed = router.is_some() || !tables.is_empty();
+    if routed != args.tables_dir.is_some() {
+        let schema = &args.schema;
+        return Err(match routed {
+            true => format!("{schema} splits records between tables, so needs --tables-dir"),
+            false => format!("--tables-dir needs {schema} to have a route or tables"),
+        }
+        .into());
+    }
+    let tables = tables
+        .iter()
+        .map(|(table, config)| Ok((table.as_str(), config.schema(&registry)?)))
+        .collect::<Result<Vec<_>, ConfigError>>()
+        .map_err(|e| format!("{}: {e}", args.schema))
+        .map_err(failed(Kind::Schema))?;
+    // With `tables`, each document is extracted once per table.
+    let schemas: Vec<(Option<&str>, &SubSchema)> = match tables.is_empty() {
+        true => vec![(None, &schema)],
+        false => tables
+            .iter()
+            .map(|(table, schema)| (Some(*table), schema))
+            .collect(),
+    };
+    let previous = match args.previous_schema.as_deref() {
+        Some(path) => Some(
+            Source::load(path, None)
+                .and_then(|source| source.columns(path, &registry))
+                .map_err(failed(Kind::Schema))?,
+        ),
+        None => None,
+    };
+    let filter: Option<Filter> = match args.filter_doc.as_deref() {
+        Some(expression) => Some(
+            expression
+                .parse()
+                .map_err(|e| format!("--filter-doc: {e}"))?,
+        ),
+        None => None,
+    };
+    let range = args
+        .time_field
+        .as_deref()
+        .map(|field| TimeRange::new(field, args.since, args.until));
+    let target: Option<Warehouse> = args.warehouse.as_deref().map(str::parse).transpose()?;
+
+    if args.run_manifest.is_some() && matches!(args.input.as_deref(), None | Some("-")) {
+        return Err("--run-manifest needs an --input file".to_string().into());
+    }
+    if args.output_manifest.is_some() && matches!(args.output.as_deref(), None | Some("-")) {
+        return Err("--output-manifest needs an --output file"
+            .to_string()
+            .into());
+    }
+    if let Some(manifest) = args.checksums.as_deref() {
+        let input = match args.input.as_deref() {
+            None | Some("-") => return Err("--checksums needs an --input file".to_string().into()),
+            Some(path) => path,
+        };
+        Checksums::load(Path::new(manifest))
+            .and_then(|checksums| checksums.verify(Path::new(input)))
+            .map_err(|e| format!("verifying input: {e}"))?;
+    }
+    // The input is known by its content rather than its path, so a file
+    // rewritten in place is processed again.
+    let identity = match (args.idempotent.as_deref(), args.input.as_deref()) {
+        (Some(_), None | Some("-")) => {
+            return Err("--idempotent needs an --input file".to_string().into())
+        }
+        (Some(state), Some(input)) => {
+            let sha256 = checksum::sha256(Path::new(input)).map_err(|e| format!("{input}: {e}"))?;
+            let identity = json!({
+                "input": input,
+                "sha256": sha256,
+                "schema_fingerprint": schema.fingerprint(),
+            });
+            if processed(state, &identity)? {
+                tracing::info!(input, state, "skipping an input already processed");
+                return Ok(());
+            }
+            Some((state, identity))
+        }
+        (None, _) => None,
+    };
+
+    let mut input: Box<dyn BufRead> = match args.input.as_deref() {
+        None | Some("-") => Box::new(io::stdin().lock()),
+        Some(path) => Box::new(BufReader::new(
+            File::open(path).map_err(|e| format!("{path}: {e}"))?,
+        )),
+    };
+    let streamed = args.ndjson
+        || args
+            .input
+            .as_deref()
+            .is_some_and(|path| path.ends_with(".jsonl") || path.ends_with(".ndjson"));
+    let documents: Box<dyn Iterator<Item = Result<Value, Failure>>> = if streamed {
+        let reader = NdjsonReader::new(input);
+        let reader = if args.strict { reader.strict() } else { reader };
+        Box::new(reader.map(|document| {
+            document.map_err(|e| match e.kind() {
+                io::ErrorKind::InvalidData
+                    if e.get_ref().is_some_and(|e| e.is::<DuplicateKeys>()) =>
+                {
+                    failed(Kind::Validation)(e.to_string())
+                }
+                io::ErrorKind::InvalidData => failed(Kind::Parse)(e.to_string()),
+                _ => format!("reading input: {e}").into(),
+            })
+        }))
+    } else {
+        let mut text = String::new();
+        input
+            .read_to_string(&mut text)
+            .map_err(|e| format!("reading input: {e}"))?;
+        Box::new(documents(&text, args.strict).into_iter())
+    };
+
+    let format = args
+        .format
+        .as_deref()
+        .unwrap_or(match args.output.as_deref() {
+            Some(path) if path.ends_with(".json") || path.ends_with(".jsonl") => "json",
+            _ => "csv",
+        });
+    let extension = if format == "json" { "jsonl" } else { "csv" };
+    let run_id = args.run_id.clone().unwrap_or_else(|| {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        format!("{}-{}", started.as_secs(), std::process::id())
+    });
+    let mut part = None;
+    // Some when appending CSV to a file that isn't empty, with its header
+    // unless it was written with `--no-header`.
+    let appended = match args.output.as_deref() {
+        Some(path) if args.append && format == "csv" => match fs::metadata(path) {
+            Ok(metadata) if metadata.len() > 0 => Some(match args.dialect().header {
+                true => File::open(path)
+                    .and_then(|file| args.dialect().read_header(BufReader::new(file)))
+                    .map(Some)
+                    .map_err(|e| format!("{path}: {e}"))
+                    .map_err(failed(Kind::Sink))?,
+                false => None,
+            }),
+            _ => None,
+        },
+        _ => None,
+    };
+    let out: Box<dyn Write> = match (args.output_dir.as_deref(), args.output.as_deref()) {
+        (Some(dir), _) => {
+            let output_dir = OutputDir::new(dir)
+                .map_err(|e| format!("{dir}: {e}"))
+                .map_err(failed(Kind::Sink))?;
+            let (path, file) = output_dir
+                .create_part(&run_id, extension)
+                .map_err(|e| format!("{dir}: {e}"))
+                .map_err(failed(Kind::Sink))?;
+            part = Some((output_dir, path));
+            Box::new(file)
+        }
+        (None, None | Some("-")) => Box::new(io::stdout().lock()),
+        (None, Some(path)) => Box::new(
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(args.append)
+                .truncate(!args.append)
+                .open(path)
+                .map_err(|e| format!("{path}: {e}"))
+                .map_err(failed(Kind::Sink))?,
+        ),
+    };
+    if format == "json" && args.encoding.is_some() {
+        return Err("--encoding only applies to CSV output".to_string().into());
+    }
+    let sanitize = args.sanitize()?;
+    let flags = source.flags();
+    let columns = source
+        .columns(&args.schema, &registry)
+        .map_err(failed(Kind::Schema))?;
+    let mut sparse = args
+        .sparse
+        .then(|| (RowId::new("row_id", RowIdKind::Offset), Eav::new("row_id")));
+    // Formats are keyed by column, so they don't reach the `value` column of
+    // sparse output.
+    let (written, formats) = match &sparse {
+        Some((_, eav)) => (eav.columns(), HashMap::new()),
+        None => (columns.clone(), schema.formats()),
+    };
+    let options = JsonOptions {
+        columns: written.clone(),
+        formats: formats.clone(),
+        sanitize,
+        ..JsonOptions::default()
+    };
+    // `--format` only takes `csv` or `json`.
+    let open = |out: Box<dyn Write>,
+                written: Vec<String>,
+                appended: Option<Option<Vec<String>>>| {
+        let out = BufWriter::new(out);
+        Ok::<_, Failure>(match format {
+            "json" => Sink::Json(JsonLinesWriter::new(
+                out,
+                JsonOptions {
+                    columns: written,
+                    ..options.clone()
+                },
+            )),
+            _ => {
+                let csv = match appended {
+                    Some(header) => {
+                        // `--evolution` only takes policies `Evolution` parses.
+                        let evolution = args.evolution.as_deref().unwrap_or("fail").parse()?;
+                        let header = header.unwrap_or_else(|| written.clone());
+                        CsvWriter::append(out, header, &written, args.dialect(), evolution)
+                            .map_err(|e| format!("appending to the output: {e}"))
+                            .map_err(failed(Kind::Sink))?
+                    }
+                    None => CsvWriter::new(out, written, args.dialect()).map_err(sink_failed)?,
+                }
+                .with_formats(formats.clone())
+                .with_sanitize(sanitize);
+                Sink::Csv(match target {
+                    Some(warehouse) => csv.for_warehouse(warehouse),
+                    None => csv,
+                })
+            }
+        })
+    };
+    // The output's sink, or with `--tables-dir` each table's.
+    let mut sinks = BTreeMap::new();
+    match args.tables_dir.as_deref() {
+        Some(dir) => {
+            fs::create_dir_all(dir)
+                .map_err(|e| format!("{dir}: {e}"))
+                .map_err(failed(Kind::Sink))?;
+            let names: Vec<&str> = match &router {
+                Some(router) => router.tables().into_iter().collect(),
+                None => tables.iter().map(|(table, _)| *table).collect(),
+            };
+            for table in names {
+                if Path::new(table).file_name() != Some(table.as_ref()) {
+                    return Err(failed(Kind::Schema)(format!(
+                        "table {table:?} is not a file name"
+                    )));
+                }
+                let columns = match tables.iter().find(|(name, _)| *name == table) {
+                    Some((_, schema)) => {
+                        let mut columns = schema.columns();
+                        columns.extend(flags.columns());
+                        columns
+                    }
+                    None => written.clone(),
+                };
+                let path = Path::new(dir).join(format!("{table}.{extension}"));
+                let file = File::create(&path)
+                    .map_err(|e| format!("{}: {e}", path.display()))
+                    .map_err(failed(Kind::Sink))?;
+                sinks.insert(Some(table), open(Box::new(file), columns, None)?);
+            }
+        }
+        None => {
+            sinks.insert(None, open(out, written, appended)?);
+        }
+    }
+
+    let mut types = TypeInference::new(columns.clone(), &schema.formats());
+    let mut sampler = args
+        .sample_rate
+        .map(|rate| Sampler::new(rate, args.seed.unwrap_or(0)));
+    let mut metrics = Metrics::default();
+    let mut assertions = source.assertions();
+    let pii = schema.pii_columns().into_iter().map(|(column, _)| column);
+    let mut sketches =
+        ColumnSketches::default().examples(args.report_examples, pii.collect(), PiiExamples::Mask);
+    let (mut read, mut skipped, mut emitted) = (0, 0, 0);
+    let mut last = Kind::Partial;
+    for (i, document) in documents.enumerate() {
+        read += 1;
+        let records = document.and_then(|document| {
+            if filter
+                .as_ref()
+                .is_some_and(|filter| !filter.matches(&document))
+                || range
+                    .as_ref()
+                    .is_some_and(|range| !range.contains(&document))
+            {
+                return Ok(vec![]);
+            }
+            let mut records = vec![];
+            for (table, schema) in schemas.iter() {
+                let extracted = if args.strict {
+                    schema.extract_strict_with_metrics(&document, &mut metrics)
+                } else {
+                    schema.extract_with_metrics(&document, &mut metrics)
+                }
+                .map_err(|e| failed(Kind::Validation)(format!("document {i}: {e}")))?;
+                let extracted = flags.apply(extracted).into_iter();
+                records.extend(extracted.map(|record| (*table, record)));
+            }
+            Ok(records)
+        });
+        let records = match records {
+            Ok(records) => records,
+            Err(failure) if args.strict || failure.kind == Kind::Other => return Err(failure),
+            Err(failure) => {
+                tracing::warn!(
+                    document = i,
+                    code = failure.kind as u8,
+                    error = failure.message,
+                    "skipped document"
+                );
+                skipped += 1;
+                last = failure.kind;
+                match args.max_failures {
+                    Some(max) if skipped > max => {
+                        return Err(failed(Kind::Validation)(format!(
+                            "more than {max} documents failed"
+                        )))
+                    }
+                    _ => continue,
+                }
+            }
+        };
+        // Keep each record's index, so sparse row ids name its place in the
+        // document even when sampling drops the ones before it. A `route`
+        // drops the records it has no table for.
+        let mut records: Vec<(usize, Option<&str>, Record)> = records
+            .into_iter()
+            .enumerate()
+            .filter_map(|(index, (table, record))| match &router {
+                Some(router) => Some((index, Some(router.table(&record)?), record)),
+                None => Some((index, table, record)),
+            })
+            .filter(|_| sampler.as_mut().is_none_or(Sampler::keep))
+            .collect();
+        for (_, _, record) in records.iter() {
+            types.observe(record);
+            sketches.observe(record);
+            assertions.observe(record);
+        }
+        let records: Vec<(Option<&str>, Record)> = match &mut sparse {
+            Some((row_id, eav)) => {
+                for (index, _, record) in records.iter_mut() {
+                    row_id.append(i as u64, *index, record);
+                }
+                let records = records.into_iter().map(|(_, _, record)| record);
+                let triples = eav.apply(records.collect()).into_iter();
+                triples.map(|triple| (None, triple)).collect()
+            }
+            None => records
+                .into_iter()
+                .map(|(_, table, record)| (table, record))
+                .collect(),
+        };
+        for (table, record) in records.iter() {
+            let sink = sinks.get_mut(table);
+            match sink.expect("every table a record can be sent to has a sink") {
+                Sink::Csv(csv) => csv.write(record),
+                Sink::Json(json) => json.write(record),
+            }
+            .map_err(sink_failed)?;
+        }
+        emitted += records.len();
+    }
+
+    for sink in sinks.into_values() {
+        let mut out = match sink {
+            Sink::Csv(csv) => csv.into_inner(),
+            Sink::Json(json) => json.into_inner(),
+        };
+        out.flush().map_err(sink_failed)?;
+    }
+
+    if let Some(previous) = previous {
+        let diff = ColumnDiff::new(&previous, &columns);
+        if !diff.is_empty() {
+            let table = args.table.as_deref().unwrap_or_else(|| {
+                args.output
+                    .as_deref()
+                    .and_then(|path| Path::new(path).file_stem()?.to_str())
+                    .unwrap_or("output")
+            });
+            let warehouse = target.unwrap_or(Warehouse::Postgres);
+            eprint!("{}", warehouse.migration(table, &diff, &types.types()));
+        }
+    }
+
+    if let Some(path) = args.run_manifest.as_deref() {
+        record_run(path, &args, &schema.fingerprint())?;
+    }
+    // Without a manifest, loaders waiting for one don't pick up the output.
+    let violations = assertions.check();
+    let manifest = args
+        .output_manifest
+        .as_deref()
+        .filter(|_| violations.is_ok());
+    if let (Some(path), Some(output)) = (manifest, args.output.as_deref()) {
+        let file =
+            checksum::describe(Path::new(output), emitted).map_err(|e| format!("{output}: {e}"))?;
+        let text =
+            serde_json::to_string_pretty(&json!({"files": [file]})).map_err(|e| e.to_string())?;
+        // The manifest is part of what loaders receive, like the output.
+        fs::write(path, text + "\n")
+            .map_err(|e| format!("{path}: {e}"))
+            .map_err(failed(Kind::Sink))?;
+    }
+    if let Some((output_dir, path)) = part.filter(|_| violations.is_ok()) {
+        output_dir
+            .commit(&run_id, &[(path, emitted)])
+            .map_err(|e| format!("committing the output: {e}"))
+            .map_err(failed(Kind::Sink))?;
+    }
+    if let Some(path) = args.report.as_deref() {
+        let report = json!({
+            "documents": read,
+            "records": emitted,
+            "skipped": skipped,
+            "nodes": metrics.report(),
+            "columns": sketches.report(),
+        });
+        let text = serde_json::to_string_pretty(&report).map_err(|e| e.to_string())?;
+        fs::write(path, text + "\n").map_err(|e| format!("{path}: {e}"))?;
+    }
+    tracing::info!(documents = read, records = emitted, skipped, "finished");
+    // Retrying a run whose documents all failed might go better, so only
+    // runs that wrote something count as processed.
+    let wrote = skipped == 0 || skipped < read;
+    if let Some((state, mut identity)) = identity.filter(|_| violations.is_ok() && wrote) {
+        identity["run"] = json!(run_id);
+        identity["records"] = json!(emitted);
+        mark_processed(state, &identity)?;
+    }
+    if let Err(violations) = violations {
+        return Err(failed(Kind::Validation)(format!(
+            "assertions failed: {}",
+            violations.join("; ")
+        )));
+    }
+    match skipped {
+        0 => Ok(()),
+        // Nothing made it through, so the run failed the way its documents did.
+        n if n == read => Err(failed(last)(format!("all {n} documents failed"))),
+        n => Err(failed(Kind::Partial)(format!(
+            "skipped {n} failed documents"
+        ))),
+    }
+}
+
+/// Writes the `--run-manifest` that `replay` repeats a run from.
+fn record_run(path: &str, args: &Args, fingerprint: &str) -> Result<(), String> {
+    let cwd = env::current_dir().map_err(|e| format!("recording run: {e}"))?;
+    let files = args
+        .files()
+        .into_iter()
+        .map(|file| {
+            let sha256 = checksum::sha256(Path::new(file)).map_err(|e| format!("{file}: {e}"))?;
+            Ok(json!({"path": file, "sha256": sha256}))
+        })
+        .collect::<Result<Vec<Value>, String>>()?;
+    let manifest = json!({
+        "args": args.recorded(),
+        "cwd": cwd.to_string_lossy().into_owned(),
+        "files": files,
+        "schema_fingerprint": fingerprint,
+    });
+    let text = serde_json::to_string_pretty(&manifest).map_err(|e| e.to_string())?;
+    fs::write(path, text + "\n").map_err(|e| format!("{path}: {e}"))
+}
+
+/// Whether the `--idempotent` state file lists an input with the same
+/// content and schema as `identity`.
+fn processed(state: &str, identity: &Value) -> Result<bool, String> {
+    let text = match fs::read_to_string(state) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(format!("{state}: {e}")),
+    };
+    let same = |entry: &Value| {
+        ["sha256", "schema_fingerprint"]
+            .iter()
+            .all(|field| entry[field] == identity[field])
+    };
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        let entry: Value = serde_json::from_str(line).map_err(|e| format!("{state}: {e}"))?;
+        if same(&entry) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Appends a processed input to the `--idempotent` state file as one JSON
+/// line, a single write so concurrent runs don't interleave their entries.
+fn mark_processed(state: &str, entry: &Value) -> Result<(), String> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(state)
+        .and_then(|mut file| file.write_all(format!("{entry}\n").as_bytes()))
+        .map_err(|e| format!("{state}: {e}"))
+}
+
+/// Repeats the run recorded in `--manifest` from its working directory,
+/// failing if any file it read or its schema's fingerprint changed.
+fn replay(path: &str, output: Option<String>) -> Result<(), Failure> {
+    let text = fs::read_to_string(path).map_err(|e| format!("reading {path}: {e}"))?;
+    let manifest: Value = serde_json::from_str(&text).map_err(|e| format!("{path}: {e}"))?;
+    let invalid = |field: &str| format!("{path}: missing or invalid {field:?}");
+
+    let recorded: Vec<String> = manifest["args"]
+        .as_array()
+        .ok_or_else(|| invalid("args"))?
+        .iter()
+        .map(|arg| {
+            arg.as_str()
+                .map(str::to_string)
+                .ok_or_else(|| invalid("args"))
+        })
+        .collect::<Result<_, _>>()?;
+    // A new output is relative to where replay runs, not the recorded run.
+    let output = match output {
+        Some(output) if output != "-" => Some(
+            env::current_dir()
+                .map_err(|e| e.to_string())?
+                .join(output)
+                .to_string_lossy()
+                .into_owned(),
+        ),
+        output => output,
+    };
+
+    let cwd = manifest["cwd"].as_str().ok_or_else(|| invalid("cwd"))?;
+    env::set_current_dir(cwd).map_err(|e| format!("{cwd}: {e}"))?;
+    for file in manifest["files"]
+        .as_array()
+        .ok_or_else(|| invalid("files"))?
+    {
+        let (file, expected) = match (file["path"].as_str(), file["sha256"].as_str()) {
+            (Some(file), Some(expected)) => (file, expected),
+            _ => return Err(invalid("files").into()),
+        };
+        let actual = checksum::sha256(Path::new(file)).map_err(|e| format!("{file}: {e}"))?;
+        if actual != expected {
+            return Err(format!("{file} changed since the recorded run").into());
+        }
+    }
+
+    let mut args = Args::try_parse_from(["flatten".to_string()].into_iter().chain(recorded))
+        .map_err(|e| format!("{path}: {e}"))?;
+    if output.is_some() {
+        args.output = output;
+        args.output_dir = None;
+    }
+    let source = Source::load(&args.schema, args.overlay.as_deref())?;
+    let fingerprint = source
+        .schema(&args.schema, &registry(&args)?)?
+        .fingerprint();
+    if manifest["schema_fingerprint"].as_str() != Some(fingerprint.as_str()) {
+        return Err("the schema's fingerprint differs from the recorded run's; \
+                    the crate's schema handling may have changed since"
+            .to_string()
+            .into());
+    }
+    run(args)
+}
+
+/// Runs a schema's embedded tests, failing if any does.
+fn test_schema(path: &str, overlay: Option<&str>) -> Result<(), Failure> {
+    let source = Source::load(path, overlay).map_err(failed(Kind::Schema))?;
+    let schema = source
+        .schema(path, &Registry::builtin())
+        .map_err(failed(Kind::Schema))?;
+    let Source::Config(config) = &source else {
+        let message = format!("{path}: only JSON and YAML schemas embed tests");
+        return Err(failed(Kind::Schema)(message));
+    };
+    let results = config.run_tests(&schema);
+    let mut failures = 0;
+    for (name, result) in results.iter() {
+        match result {
+            Ok(()) => println!("ok {name}"),
+            Err(e) => {
+                println!("FAILED {name}: {e}");
+                failures += 1;
+            }
+        }
+    }
+    match failures {
+        0 => Ok(()),
+        n => Err(failed(Kind::Validation)(format!(
+            "{n} of {} schema tests failed",
+            results.len()
+        ))),
+    }
+}
+
+/// Unix seconds of a `--since` or `--until` timestamp.
+fn timestamp(text: &str) -> Result<i64, String> {
+    time::parse_timestamp(text).ok_or_else(|| "expected an RFC 3339 timestamp or date".to_string())
+}
+
+/// A `--sample-rate` between 0 and 1.
+fn fraction(text: &str) -> Result<f64, String> {
+    match text.parse() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err("expected a number from 0 to 1".to_string()),
+    }
+}
+
+/// A sample value for `test-transform`: JSON, or `missing` for none.
+#[derive(Debug, Clone)]
+struct Sample(Option<Value>);
+
+fn sample(text: &str) -> Result<Sample, String> {
+    match text {
+        "missing" => Ok(Sample(None)),
+        _ => serde_json::from_str(text)
+            .map(|value| Sample(Some(value)))
+            .map_err(|e| e.to_string()),
+    }
+}
+
+/// Runs a registered transform on each input, failing if any is rejected
+/// or differs from `expected`.
+fn test_transform(name: &str, inputs: Vec<Sample>, expected: Option<Sample>) -> Result<(), String> {
+    let mut inputs: Vec<Option<Value>> = inputs.into_iter().map(|sample| sample.0).collect();
+    let expected = expected.map(|sample| sample.0);
+    if inputs.is_empty() {
+        inputs.push(None);
+    }
+
+    let registry = Registry::builtin();
+    let show = |value: &Option<Value>| match value {
+        Some(value) => value.to_string(),
+        None => "missing".to_string(),
+    };
+    let mut failures = 0;
+    for input in inputs {
+        let shown = show(&input);
+        match registry.apply(name, input) {
+            None => {
+                return Err(format!(
+                    "unknown transform {name:?}; registered: {}",
+                    registry.names().join(", ")
+                ))
+            }
+            Some(Err(e)) => {
+                println!("{shown} -> error: {e}");
+                failures += 1;
+            }
+            Some(Ok(output)) => match &expected {
+                Some(expected) if *expected != output => {
+                    println!("{shown} -> {}, expected {}", show(&output), show(expected));
+                    failures += 1;
+                }
+                _ => println!("{shown} -> {}", show(&output)),
+            },
+        }
+    }
+    match failures {
+        0 => Ok(()),
+        n => Err(format!("{n} of the transform's inputs failed")),
+    }
+}
+
+enum Sink<W: Write> {
+    Csv(CsvWriter<W>),
+    Json(JsonLinesWriter<W>),
+}
+
+/// Diagnostics go to stderr, leaving stdout to the output.
+fn init_logging(format: LogFormat) {
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .with_target(false);
+    match format {
+        LogFormat::Text => subscriber.without_time().init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    init_logging(cli.log_format);
+    let result = match (cli.command, cli.run) {
+        (Some(Command::Replay { manifest, output }), _) => replay(&manifest, output),
+        (
+            Some(Command::TestTransform {
+                name,
+                inputs,
+                expect,
+            }),
+            _,
+        ) => test_transform(&name, inputs, expect).map_err(Failure::from),
+        (Some(Command::Test { schema, overlay }), _) => test_schema(&schema, overlay.as_deref()),
+        (None, Some(args)) => run(args),
+        (None, None) => unreachable!("clap shows the help without arguments"),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            let code = failure.kind as u8;
+            tracing::error!(code, kind = ?failure.kind, "{}", failure.message);
+            ExitCode::from(code)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_args_and_inputs() {
+        let cli = Cli::try_parse_from([
+            "flatten",
+            "--schema",
+            "s.yaml",
+            "--format",
+            "json",
+            "--strict",
+            "--ndjson",
+            "--run-manifest",
+            "run.json",
+            "--sample-rate",
+            "0.25",
+            "--seed",
+            "7",
+            "--since",
+            "2024-05-01",
+            "--time-field",
+            "ts",
+        ])
+        .unwrap();
+        let args = cli.run.unwrap();
+        assert_eq!(args.format.as_deref(), Some("json"));
+        assert_eq!(
+            args.recorded(),
+            [
+                "--schema",
+                "s.yaml",
+                "--format",
+                "json",
+                "--time-field",
+                "ts",
+                "--since",
+                "2024-05-01T00:00:00Z",
+                "--sample-rate",
+                "0.25",
+                "--seed",
+                "7",
+                "--ndjson",
+                "--strict"
+            ]
+        );
+        assert!(Cli::try_parse_from(["flatten", "--input", "x"]).is_err());
+        let cli = Cli::try_parse_from([
+            "flatten",
+            "--schema",
+            "s",
+            "--delimiter",
+            ";",
+            "--null",
+            "\\N",
+            "--quote-all",
+            "--no-header",
+        ]);
+        let args = cli.unwrap().run.unwrap();
+        let dialect = args.dialect();
+        assert_eq!((dialect.delimiter, dialect.null.as_str()), (';', "\\N"));
+        assert_eq!((dialect.quoting, dialect.header), (Quoting::Always, false));
+        assert_eq!(args.sanitize(), Ok(Sanitize::default()));
+        assert_eq!(
+            args.recorded(),
+            [
+                "--schema",
+                "s",
+                "--delimiter",
+                ";",
+                "--null",
+                "\\N",
+                "--quote-all",
+                "--no-header"
+            ]
+        );
+        let cli = Cli::try_parse_from(["flatten", "--log-format", "json", "--schema", "s"]);
+        assert_eq!(cli.unwrap().log_format, LogFormat::Json);
+        assert!(Cli::try_parse_from(["flatten", "--schema", "s", "--format", "xml"]).is_err());
+        let cli = Cli::try_parse_from(["flatten", "test", "schema.yaml"]).unwrap();
+        assert!(
+            matches!(cli.command, Some(Command::Test { schema, .. }) if schema == "schema.yaml")
+        );
+        let cli = Cli::try_parse_from(["flatten", "test-transform", "trim", "--input", "missing"]);
+        assert!(matches!(
+            cli.unwrap().command,
+            Some(Command::TestTransform { inputs, .. }) if inputs[0].0.is_none()
+        ));
+
+        assert_eq!(documents("[{}, {}]", false).len(), 2);
+        let lines = documents("{\"a\": 1}\n\n{\"a\": 2}\n", false);
+        assert_eq!(lines, [Ok(json!({"a": 1})), Ok(json!({"a": 2}))]);
+        let pretty = documents("{\"a\": 1}\n{\n  \"a\": 2\n}\n", false);
+        assert_eq!(pretty.len(), 1);
+        let failure = pretty[0].as_ref().unwrap_err();
+        assert_eq!(failure.kind, Kind::Parse);
+        assert!(failure.message.starts_with("input: "));
+        let repeated = "[{\"a\": 1}, {\"a\": 1, \"a\": 2}]";
+        assert_eq!(documents(repeated, false)[1], Ok(json!({"a": 2})));
+        assert_eq!(
+            documents(repeated, true),
+            [Err(failed(Kind::Validation)(
+                "input: duplicate keys /1/a".to_string()
+            ))]
+        );
+        assert_eq!(
+            documents("{}\n{\"b\": {\"c\": 1, \"c\": 1}}", true)[1],
+            Err(failed(Kind::Validation)(
+                "input line 2: duplicate keys /b/c".to_string()
+            ))
+        );
+    }
+}
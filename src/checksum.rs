@@ -0,0 +1,161 @@
+//! SHA-256 verification of input files against a manifest, so a truncated
+//! or corrupted transfer fails the run before anything is extracted.
+//!
+//! Manifests use the `sha256sum` format, one `<hex digest>  <path>` line per
+//! file, with relative paths resolved against the manifest's directory.
+//! Outputs are described the other way round, for downstream loaders to
+//! verify the transfer of what a run wrote.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+/// Hex SHA-256 digest of the file at `path`.
+pub fn sha256(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+/// An output file as an output manifest lists it: its `path`, how many
+/// `records` were written to it, its size in `bytes` and its `sha256`.
+pub fn describe(path: &Path, records: usize) -> io::Result<Value> {
+    Ok(json!({
+        "path": path.to_string_lossy(),
+        "records": records,
+        "bytes": fs::metadata(path)?.len(),
+        "sha256": sha256(path)?,
+    }))
+}
+
+/// Expected digests per input file.
+#[derive(Debug, Clone, Default)]
+pub struct Checksums {
+    entries: Vec<(PathBuf, String)>,
+}
+
+impl Checksums {
+    /// Reads a manifest; a malformed line is an `InvalidData` error naming
+    /// it.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut entries = vec![];
+        for (i, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = line.split_once(' ').and_then(|(digest, file)| {
+                // `sha256sum` marks binary-mode entries with `*`.
+                let file = file.trim_start_matches(' ').trim_start_matches('*');
+                let valid = digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit());
+                (valid && !file.is_empty()).then(|| (dir.join(file), digest.to_ascii_lowercase()))
+            });
+            match entry {
+                Some(entry) => entries.push(entry),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "{}: line {}: expected `<sha256>  <path>`",
+                            path.display(),
+                            i + 1
+                        ),
+                    ))
+                }
+            }
+        }
+        Ok(Checksums { entries })
+    }
+
+    /// Checks `path` against its listed digest. A file the manifest doesn't
+    /// list, or whose contents differ, is an `InvalidData` error.
+    pub fn verify(&self, path: &Path) -> io::Result<()> {
+        let canonical = fs::canonicalize(path)?;
+        let expected = self
+            .entries
+            .iter()
+            .find(|(listed, _)| fs::canonicalize(listed).is_ok_and(|listed| listed == canonical))
+            .map(|(_, digest)| digest)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not listed in the checksum manifest", path.display()),
+                )
+            })?;
+        let actual = sha256(path)?;
+        if &actual != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}: expected sha256 {expected}, found {actual}",
+                    path.display()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Verifies every listed file, stopping at the first failure.
+    pub fn verify_all(&self) -> io::Result<()> {
+        for (path, _) in self.entries.iter() {
+            self.verify(path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn verifies_inputs_against_manifest() {
+        let dir = std::env::temp_dir().join(format!("checksum-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.json");
+        fs::write(&input, "abc").unwrap();
+        assert_eq!(
+            sha256(&input).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        let manifest = dir.join("SHA256SUMS");
+        fs::write(
+            &manifest,
+            "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD *input.json\n",
+        )
+        .unwrap();
+        assert_eq!(
+            describe(&input, 1).unwrap(),
+            json!({
+                "path": input.to_string_lossy(),
+                "records": 1,
+                "bytes": 3,
+                "sha256": "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            })
+        );
+
+        let checksums = Checksums::load(&manifest).unwrap();
+        checksums.verify(&input).unwrap();
+        assert!(checksums.verify(&manifest).is_err());
+
+        fs::write(&input, "ab").unwrap();
+        let error = checksums.verify_all().unwrap_err();
+        assert!(
+            error.to_string().contains("expected sha256 ba78"),
+            "{error}"
+        );
+
+        fs::write(&manifest, "abc input.json\n").unwrap();
+        assert!(Checksums::load(&manifest).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
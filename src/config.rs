@@ -0,0 +1,1018 @@
+//! Extraction schemas loaded from JSON or YAML at runtime instead of built
+//! with the macros, with transforms referenced by name from a registry:
+//!
+//! ```json
+//! {"children": [
+//!     {"key": "id", "name": "human_id", "transform": "parse_int"},
+//!     {"sub": "family", "filter": "relation == \"mom\"", "children": [
+//!         {"key": "name", "pii": "name"}
+//!     ]}
+//! ], "flags": [{"name": "is_adult", "filter": "age >= 18"}]}
+//! ```
+//!
+//! The root holds `children`, `rest`, `flags`, boolean columns appended to
+//! each record (see `stage::DerivedFlags`), and `tests`, sample `input`
+//! documents with the records they should `expect` (see
+//! `SchemaConfig::run_tests`), and `assertions` over the whole run's
+//! records: `min_records`, `max_records`, `max_null_rate` (a rate per
+//! column) and `unique` (a list of columns; see `assertion::Assertion`).
+//! Records can be split between output tables in one pass, either by a
+//! `route`, whose `column` value picks one of its `tables` (falling back
+//! to its `default`, see `stage::Router`), or by `tables`, each naming the
+//! root children it extracts, so that e.g. two arrays each get a table
+//! instead of being joined (see `SchemaConfig::table`); not both.
+//! Each child is either a `sub`, which also takes `children`, `index` (see
+//! `SubSchema::at`), `offset`, `limit`, `filter`, `separator`, `prefix`
+//! (`false` for bare column names, see `SubSchema::unprefixed`), `merge`
+//! (`"cartesian"`, `"zip"` or `"first"`) and `rest`, or a `key`, which
+//! also takes `name`, `transform` (a `Registry` name), `anonymize` (a
+//! `Registry` name, for a transform that clears a `pii` column for
+//! `SubSchema::check_pii`; a key has either it or `transform`),
+//! `fallback`, `pii`, `natural_key`, `required`,
+//! `default` (any JSON value, `null` included), `explode`, `pattern`,
+//! `jsonpath` (with the `jsonpath` feature), `max_length` and
+//! `max_cardinality`. Other fields mean what the `SubSchema` or `KeySchema`
+//! builder of the same name does. Unknown fields, and fields of the other
+//! kind of node, are errors, so typos don't go unnoticed.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use serde_path_to_error::Segment;
+
+use crate::assertion::{Assertion, RunAssertions};
+use crate::filter::Filter;
+use crate::stage::{DerivedFlags, Router};
+use crate::{
+    escape_pointer, transforms, KeyOptions, KeySchema, Limits, Merge, Node, Rest, SubOptions,
+    SubSchema, TransformError,
+};
+
+/// Why a schema config couldn't be loaded or built.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    /// JSON Pointer to the offending part of the config.
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at {:?}", self.message, self.path)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn error(path: &str, message: impl Into<String>) -> ConfigError {
+    ConfigError {
+        path: path.to_string(),
+        message: message.into(),
+    }
+}
+
+type Shared = Arc<dyn Fn(Option<Value>) -> Result<Option<Value>, TransformError> + Send + Sync>;
+
+/// Named transforms a config can refer to.
+#[derive(Clone, Default)]
+pub struct Registry {
+    transforms: HashMap<String, Shared>,
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Registry")
+            .field("transforms", &self.names())
+            .finish()
+    }
+}
+
+impl Registry {
+    /// The parameterless transforms of the `transforms` module, under their
+    /// function names.
+    pub fn builtin() -> Self {
+        let registry = Registry::default()
+            .register("trim", transforms::trim)
+            .register("lowercase", transforms::lowercase)
+            .register("uppercase", transforms::uppercase)
+            .register("to_string", transforms::to_string)
+            .register("parse_int", transforms::parse_int)
+            .register("parse_float", transforms::parse_float)
+            .register("parse_bool", transforms::parse_bool)
+            .register("parse_seconds", transforms::parse_seconds)
+            .register("parse_millis", transforms::parse_millis)
+            .register("parse_bytes", transforms::parse_bytes)
+            .register("null_if_empty", transforms::null_if_empty)
+            .register("collapse_whitespace", transforms::collapse_whitespace)
+            .register("strip_html", transforms::strip_html);
+        #[cfg(feature = "unicode")]
+        let registry = registry.register("fold_accents", transforms::fold_accents);
+        #[cfg(feature = "language")]
+        let registry = registry.register("detect_language", transforms::detect_language);
+        #[cfg(feature = "faker")]
+        let registry = registry.faker("");
+        registry
+    }
+
+    pub fn register<F>(self, name: &str, func: F) -> Self
+    where
+        F: Fn(Option<Value>) -> Option<Value> + Send + Sync + 'static,
+    {
+        self.register_fallible(name, move |value| Ok(func(value)))
+    }
+
+    pub fn register_fallible<F>(mut self, name: &str, func: F) -> Self
+    where
+        F: Fn(Option<Value>) -> Result<Option<Value>, TransformError> + Send + Sync + 'static,
+    {
+        self.transforms.insert(name.to_string(), Arc::new(func));
+        self
+    }
+
+    /// Memoizes the transform registered as `name` by input value; see
+    /// `transforms::cached`. Does nothing if there is no such transform.
+    pub fn cache(mut self, name: &str, capacity: usize) -> Self {
+        if let Some(func) = self.transforms.remove(name) {
+            let func = transforms::cached(move |value| func(value), capacity);
+            self.transforms.insert(name.to_string(), Arc::new(func));
+        }
+        self
+    }
+
+    /// Registers a `geoip_<field>` transform, e.g. `geoip_country`, for
+    /// each field of `geoip`'s database; see `geoip::GeoIp::transform`.
+    #[cfg(feature = "geoip")]
+    pub fn geoip(mut self, geoip: &crate::geoip::GeoIp) -> Self {
+        for field in geoip.fields() {
+            let name = format!("geoip_{}", field.name());
+            self = self.register(&name, geoip.transform(field));
+        }
+        self
+    }
+
+    /// Registers a `fake_<kind>` transform, e.g. `fake_email`, for each
+    /// `faker::Kind`, seeded by `salt`; see `faker::fake`. The built-in
+    /// ones have an empty salt.
+    #[cfg(feature = "faker")]
+    pub fn faker(mut self, salt: &str) -> Self {
+        for kind in crate::faker::Kind::ALL {
+            let name = format!("fake_{}", kind.name());
+            self = self.register(&name, crate::faker::fake(kind, salt));
+        }
+        self
+    }
+
+    /// Registered transform names, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.transforms.keys().map(String::as_str).collect();
+        names.sort();
+        names
+    }
+
+    /// Runs the transform registered as `name` on `value`, to check it in
+    /// isolation; `None` if there is no such transform.
+    pub fn apply(
+        &self,
+        name: &str,
+        value: Option<Value>,
+    ) -> Option<Result<Option<Value>, TransformError>> {
+        self.transforms.get(name).map(|func| func(value))
+    }
+}
+
+/// An owned schema description; `schema` builds the `SubSchema` it
+/// describes, borrowing names from it.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RootConfig")]
+pub struct SchemaConfig {
+    pub root: SubConfig,
+    pub flags: Vec<FlagConfig>,
+    pub tests: Vec<TestConfig>,
+    pub assertions: AssertionsConfig,
+    pub route: Option<RouteConfig>,
+    /// The root children, by `key` or `sub` name, extracted into each
+    /// table; see `SchemaConfig::table`.
+    pub tables: BTreeMap<String, Vec<String>>,
+}
+
+/// Splits records between tables by a discriminator column; see
+/// `stage::Router`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub column: String,
+    /// The table of each discriminator value.
+    pub tables: BTreeMap<String, String>,
+    pub default: Option<String>,
+}
+
+/// Invariants over a run's records; see `assertion::Assertion`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AssertionsConfig {
+    pub min_records: Option<usize>,
+    pub max_records: Option<usize>,
+    /// Most missing or null records per column, as a fraction.
+    #[serde(default)]
+    pub max_null_rate: BTreeMap<String, f64>,
+    #[serde(default)]
+    pub unique: Vec<String>,
+}
+
+/// A boolean column computed from the extracted ones; see
+/// `stage::DerivedFlags`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FlagConfig {
+    pub name: String,
+    #[serde(deserialize_with = "filter")]
+    pub filter: Filter,
+}
+
+/// A sample document and the records it should flatten to, flags
+/// included, as objects keyed by column without the absent values.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TestConfig {
+    pub name: String,
+    pub input: Value,
+    pub expect: Vec<Map<String, Value>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SubConfig {
+    pub name: String,
+    pub children: Vec<NodeConfig>,
+    pub index: Option<usize>,
+    pub offset: usize,
+    pub limit: Option<usize>,
+    pub filter: Option<Filter>,
+    pub separator: Option<String>,
+    /// Set by `"prefix": false`.
+    pub unprefixed: bool,
+    pub rest: bool,
+    pub merge: Merge,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct KeyConfig {
+    pub key: String,
+    pub name: Option<String>,
+    /// Name of a transform in the `Registry`.
+    pub transform: Option<String>,
+    /// Name of a transform in the `Registry` that anonymizes the column;
+    /// see `KeySchema::anonymize`.
+    pub anonymize: Option<String>,
+    pub fallback: Option<String>,
+    pub pii: Option<String>,
+    pub natural_key: bool,
+    pub required: bool,
+    pub default: Option<Value>,
+    pub explode: bool,
+    pub pattern: bool,
+    /// Needs the `jsonpath` feature to build.
+    pub jsonpath: Option<String>,
+    pub limits: Limits,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "NodeFields")]
+pub enum NodeConfig {
+    Sub(SubConfig),
+    Key(KeyConfig),
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RootConfig {
+    #[serde(default)]
+    children: Vec<NodeConfig>,
+    #[serde(default)]
+    rest: bool,
+    #[serde(default)]
+    flags: Vec<FlagConfig>,
+    #[serde(default)]
+    tests: Vec<TestConfig>,
+    #[serde(default)]
+    assertions: AssertionsConfig,
+    route: Option<RouteConfig>,
+    #[serde(default)]
+    tables: BTreeMap<String, Vec<String>>,
+}
+
+impl TryFrom<RootConfig> for SchemaConfig {
+    type Error = String;
+
+    fn try_from(root: RootConfig) -> Result<Self, Self::Error> {
+        if root.route.is_some() && !root.tables.is_empty() {
+            return Err("a config has a \"route\" or \"tables\" field, not both".into());
+        }
+        for (table, children) in root.tables.iter() {
+            let unknown = children
+                .iter()
+                .find(|name| !root.children.iter().any(|child| child.name() == *name));
+            if let Some(name) = unknown {
+                return Err(format!("table {table:?} lists {name:?}, not a root child"));
+            }
+        }
+        Ok(SchemaConfig {
+            root: SubConfig {
+                children: root.children,
+                rest: root.rest,
+                ..SubConfig::default()
+            },
+            flags: root.flags,
+            tests: root.tests,
+            assertions: root.assertions,
+            route: root.route,
+            tables: root.tables,
+        })
+    }
+}
+
+impl NodeConfig {
+    /// The `key` or `sub` name overlays and `tables` know the node by.
+    fn name(&self) -> &str {
+        match self {
+            NodeConfig::Sub(sub) => &sub.name,
+            NodeConfig::Key(key) => &key.key,
+        }
+    }
+}
+
+/// Every field a node can have; `sub` or `key` says which kind of node it
+/// is, and the other kind's fields are errors.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NodeFields {
+    sub: Option<String>,
+    children: Option<Vec<NodeConfig>>,
+    index: Option<usize>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+    #[serde(default, deserialize_with = "parsed")]
+    filter: Option<Filter>,
+    separator: Option<String>,
+    prefix: Option<bool>,
+    rest: Option<bool>,
+    #[serde(default, deserialize_with = "parsed")]
+    merge: Option<Merge>,
+    key: Option<String>,
+    name: Option<String>,
+    transform: Option<String>,
+    anonymize: Option<String>,
+    fallback: Option<String>,
+    pii: Option<String>,
+    natural_key: Option<bool>,
+    required: Option<bool>,
+    /// Unlike the other fields, `null` is a value here rather than absence.
+    #[serde(default, deserialize_with = "present")]
+    default: Option<Value>,
+    explode: Option<bool>,
+    pattern: Option<bool>,
+    jsonpath: Option<String>,
+    max_length: Option<usize>,
+    max_cardinality: Option<usize>,
+}
+
+impl TryFrom<NodeFields> for NodeConfig {
+    type Error = String;
+
+    fn try_from(node: NodeFields) -> Result<Self, Self::Error> {
+        let sub_fields = [
+            ("children", node.children.is_some()),
+            ("index", node.index.is_some()),
+            ("offset", node.offset.is_some()),
+            ("limit", node.limit.is_some()),
+            ("filter", node.filter.is_some()),
+            ("separator", node.separator.is_some()),
+            ("prefix", node.prefix.is_some()),
+            ("rest", node.rest.is_some()),
+            ("merge", node.merge.is_some()),
+        ];
+        let key_fields = [
+            ("name", node.name.is_some()),
+            ("transform", node.transform.is_some()),
+            ("anonymize", node.anonymize.is_some()),
+            ("fallback", node.fallback.is_some()),
+            ("pii", node.pii.is_some()),
+            ("natural_key", node.natural_key.is_some()),
+            ("required", node.required.is_some()),
+            ("default", node.default.is_some()),
+            ("explode", node.explode.is_some()),
+            ("pattern", node.pattern.is_some()),
+            ("jsonpath", node.jsonpath.is_some()),
+            ("max_length", node.max_length.is_some()),
+            ("max_cardinality", node.max_cardinality.is_some()),
+        ];
+        let misplaced = |fields: &[(&str, bool)], kind: &str| match fields.iter().find(|f| f.1) {
+            Some((field, _)) => Err(format!("{field:?} is not a {kind} field")),
+            None => Ok(()),
+        };
+        match (node.sub, node.key) {
+            (Some(name), None) => {
+                misplaced(&key_fields, "sub")?;
+                Ok(NodeConfig::Sub(SubConfig {
+                    name,
+                    children: node.children.unwrap_or_default(),
+                    index: node.index,
+                    offset: node.offset.unwrap_or_default(),
+                    limit: node.limit,
+                    filter: node.filter,
+                    separator: node.separator,
+                    unprefixed: node.prefix == Some(false),
+                    rest: node.rest.unwrap_or_default(),
+                    merge: node.merge.unwrap_or_default(),
+                }))
+            }
+            (None, Some(key)) => {
+                misplaced(&sub_fields, "key")?;
+                if node.transform.is_some() && node.anonymize.is_some() {
+                    return Err(
+                        "a key has a \"transform\" or an \"anonymize\" field, not both".into(),
+                    );
+                }
+                Ok(NodeConfig::Key(KeyConfig {
+                    key,
+                    name: node.name,
+                    transform: node.transform,
+                    anonymize: node.anonymize,
+                    fallback: node.fallback,
+                    pii: node.pii,
+                    natural_key: node.natural_key.unwrap_or_default(),
+                    required: node.required.unwrap_or_default(),
+                    default: node.default,
+                    explode: node.explode.unwrap_or_default(),
+                    pattern: node.pattern.unwrap_or_default(),
+                    jsonpath: node.jsonpath,
+                    limits: Limits {
+                        max_length: node.max_length,
+                        max_cardinality: node.max_cardinality,
+                    },
+                }))
+            }
+            (Some(_), Some(_)) => Err("a node has a \"sub\" or a \"key\" field, not both".into()),
+            (None, None) => Err("expected a \"sub\" or \"key\" field".into()),
+        }
+    }
+}
+
+/// Deserializes a string field through `T`'s `FromStr`.
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+fn filter<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Filter, D::Error> {
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+impl FromStr for SchemaConfig {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value: Value = serde_json::from_str(s).map_err(|e| error("", e.to_string()))?;
+        Self::from_value(&value)
+    }
+}
+
+impl SchemaConfig {
+    /// Deserializes a config already parsed from JSON or YAML; errors point
+    /// at the offending field.
+    pub fn from_value(value: &Value) -> Result<Self, ConfigError> {
+        serde_path_to_error::deserialize(value).map_err(|e| {
+            let path = e
+                .path()
+                .iter()
+                .map(|segment| match segment {
+                    Segment::Seq { index } => format!("/{index}"),
+                    Segment::Map { key } => format!("/{}", escape_pointer(key)),
+                    Segment::Enum { variant } => format!("/{}", escape_pointer(variant)),
+                    Segment::Unknown => "/?".to_string(),
+                })
+                .collect::<String>();
+            error(&path, e.into_inner().to_string())
+        })
+    }
+
+    /// Parses a YAML config, in the same shape as JSON ones.
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml(s: &str) -> Result<Self, ConfigError> {
+        let value: Value = serde_yaml::from_str(s).map_err(|e| error("", e.to_string()))?;
+        Self::from_value(&value)
+    }
+
+    /// Loads `base` with a tenant `overlay` in the same format layered on
+    /// top. Overlay nodes replace the fields they set on the base node with
+    /// the same `key` or `sub` name in the same place, recursing into
+    /// `children`; overlay nodes the base lacks are appended.
+    pub fn with_overlay(base: &Value, overlay: &Value) -> Result<Self, ConfigError> {
+        let mut merged = base.clone();
+        layer(&mut merged, overlay, "")?;
+        Self::from_value(&merged)
+    }
+
+    /// Builds the schema, resolving transform names in `registry`.
+    pub fn schema(&self, registry: &Registry) -> Result<SubSchema<'_>, ConfigError> {
+        self.root.schema(registry, "")
+    }
+
+    /// Runs the embedded `tests` on `schema`, built from this config, with
+    /// each test's name and why it failed, if it did.
+    pub fn run_tests(&self, schema: &SubSchema) -> Vec<(&str, Result<(), String>)> {
+        let flags = self.flags();
+        self.tests
+            .iter()
+            .map(|test| {
+                let result = schema.extract(&test.input).map_err(|e| e.to_string());
+                let result = result.and_then(|records| {
+                    let actual: Vec<Map<String, Value>> = flags
+                        .apply(records)
+                        .into_iter()
+                        .map(|record| {
+                            record
+                                .into_iter()
+                                .filter_map(|(column, value)| Some((column, value?)))
+                                .collect()
+                        })
+                        .collect();
+                    if actual == test.expect {
+                        return Ok(());
+                    }
+                    Err(format!(
+                        "expected {}, got {}",
+                        Value::from(test.expect.clone()),
+                        Value::from(actual)
+                    ))
+                });
+                (test.name.as_str(), result)
+            })
+            .collect()
+    }
+
+    /// The checks of the `assertions`, to observe a run's records with.
+    pub fn assertions(&self) -> RunAssertions {
+        let config = &self.assertions;
+        let mut assertions = vec![];
+        assertions.extend(config.min_records.map(Assertion::MinRecords));
+        assertions.extend(config.max_records.map(Assertion::MaxRecords));
+        for (column, rate) in config.max_null_rate.iter() {
+            assertions.push(Assertion::MaxNullRate(column.clone(), *rate));
+        }
+        for column in config.unique.iter() {
+            assertions.push(Assertion::Unique(column.clone()));
+        }
+        RunAssertions::new(assertions)
+    }
+
+    /// The stage appending the `flags` columns to extracted records.
+    /// The `route` section as a stage, if the config has one.
+    pub fn router(&self) -> Option<Router> {
+        let route = self.route.as_ref()?;
+        let router = route
+            .tables
+            .iter()
+            .fold(Router::new(&route.column), |router, (value, table)| {
+                router.route(value, table)
+            });
+        Some(match route.default.as_deref() {
+            Some(table) => router.default_table(table),
+            None => router,
+        })
+    }
+
+    /// The config of one of the `tables`, extracting only the root children
+    /// it lists, so that e.g. two arrays land in tables of their own instead
+    /// of being joined into one; its `flags` and `assertions` are kept.
+    pub fn table(&self, name: &str) -> Option<SchemaConfig> {
+        let children = self.tables.get(name)?;
+        let mut table = self.clone();
+        table
+            .root
+            .children
+            .retain(|child| children.iter().any(|name| child.name() == name));
+        table.tests.clear();
+        table.tables.clear();
+        Some(table)
+    }
+
+    pub fn flags(&self) -> DerivedFlags {
+        self.flags
+            .iter()
+            .map(|flag| (flag.name.clone(), flag.filter.clone()))
+            .collect()
+    }
+}
+
+impl SubConfig {
+    fn schema(&self, registry: &Registry, path: &str) -> Result<SubSchema<'_>, ConfigError> {
+        let children = self
+            .children
+            .iter()
+            .enumerate()
+            .map(|(i, child)| {
+                let path = format!("{path}/children/{i}");
+                Ok(match child {
+                    NodeConfig::Sub(sub) => Node::Sub(sub.schema(registry, &path)?),
+                    NodeConfig::Key(key) => Node::Key(key.schema(registry, &path)?),
+                })
+            })
+            .collect::<Result<_, ConfigError>>()?;
+        let schema = SubSchema {
+            name: &self.name,
+            children,
+            options: SubOptions {
+                transform: None,
+                index: self.index,
+                offset: self.offset,
+                limit: self.limit,
+                filter: self.filter.clone(),
+                separator: self.separator.clone(),
+                rest: self.rest.then_some(Rest::Prefixed),
+                merge: self.merge,
+            },
+        };
+        Ok(if self.unprefixed {
+            schema.unprefixed()
+        } else {
+            schema
+        })
+    }
+}
+
+impl KeyConfig {
+    fn schema(&self, registry: &Registry, path: &str) -> Result<KeySchema<'_>, ConfigError> {
+        let (field, name) = match (&self.transform, &self.anonymize) {
+            (_, Some(name)) => ("anonymize", Some(name)),
+            (name, None) => ("transform", name.as_ref()),
+        };
+        let transform = match name {
+            Some(name) => {
+                let func = registry
+                    .transforms
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| error(&format!("{path}/{field}"), "unknown transform"))?;
+                Some(Box::new(move |value| func(value)) as crate::TryTransform)
+            }
+            None => None,
+        };
+        let schema = KeySchema {
+            key: &self.key,
+            name: self.name.as_deref(),
+            transform,
+            options: KeyOptions {
+                fallback: self.fallback.as_deref(),
+                pii: self.pii.as_deref(),
+                anonymized: self.anonymize.is_some(),
+                natural_key: self.natural_key,
+                required: self.required,
+                default: self.default.clone(),
+                explode: self.explode,
+                pattern: self.pattern,
+                limits: self.limits,
+                ..KeyOptions::default()
+            },
+        };
+        match &self.jsonpath {
+            #[cfg(feature = "jsonpath")]
+            Some(selector) => selector
+                .parse()
+                .map(|selector| schema.jsonpath(selector))
+                .map_err(|e: serde_json_path::ParseError| {
+                    error(&format!("{path}/jsonpath"), e.to_string())
+                }),
+            #[cfg(not(feature = "jsonpath"))]
+            Some(_) => Err(error(
+                &format!("{path}/jsonpath"),
+                "needs the jsonpath feature",
+            )),
+            None => Ok(schema),
+        }
+    }
+}
+
+/// Merges overlay node `top` into `base`; `path` points into the overlay.
+fn layer(base: &mut Value, top: &Value, path: &str) -> Result<(), ConfigError> {
+    let top = object(top, path)?;
+    let base = match base.as_object_mut() {
+        Some(base) => base,
+        None => return Err(error(path, "overlaid node is not an object")),
+    };
+    for (field, value) in top {
+        let path = format!("{path}/{}", escape_pointer(field));
+        match (field.as_str(), base.get_mut(field)) {
+            ("children", Some(Value::Array(children))) => {
+                let overlay = value
+                    .as_array()
+                    .ok_or_else(|| error(&path, "expected an array"))?;
+                for (i, node) in overlay.iter().enumerate() {
+                    let path = format!("{path}/{i}");
+                    let same = |child: &&mut Value| match node.get("sub") {
+                        Some(sub) => child.get("sub") == Some(sub),
+                        None => child.get("sub").is_none() && child.get("key") == node.get("key"),
+                    };
+                    match children.iter_mut().find(same) {
+                        Some(child) => layer(child, node, &path)?,
+                        None => children.push(node.clone()),
+                    }
+                }
+            }
+            _ => {
+                base.insert(field.clone(), value.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+fn object<'v>(value: &'v Value, path: &str) -> Result<&'v Map<String, Value>, ConfigError> {
+    value
+        .as_object()
+        .ok_or_else(|| error(path, "expected an object"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn builds_schema_from_json() {
+        let config: SchemaConfig = r#"{"children": [
+            {"key": "id", "name": "human_id", "transform": "parse_int"},
+            {"sub": "family", "filter": "relation == \"mom\"", "children": [
+                {"key": "name", "pii": "name"}
+            ]}
+        ]}"#
+        .parse()
+        .unwrap();
+        let schema = config.schema(&Registry::builtin()).unwrap();
+
+        let data = json!({"id": "7", "family": [
+            {"relation": "dad", "name": "Father Dearest"},
+            {"relation": "mom", "name": "Mother Superior"}
+        ]});
+        assert_eq!(
+            schema.extract(&data).unwrap(),
+            vec![vec![
+                ("human_id".to_string(), Some(json!(7))),
+                ("family_name".to_string(), Some(json!("Mother Superior"))),
+            ]]
+        );
+        assert_eq!(
+            schema.pii_columns(),
+            vec![("family_name".to_string(), "name")]
+        );
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn builds_schema_from_yaml() {
+        let config = SchemaConfig::from_yaml(
+            "children:\n  - key: id\n    default: null\n  - sub: phone\n    prefix: false\n    children:\n      - key: number\n",
+        )
+        .unwrap();
+        let schema = config.schema(&Registry::builtin()).unwrap();
+
+        assert_eq!(schema.columns(), vec!["id", "number"]);
+        assert_eq!(
+            schema
+                .extract(&json!({"phone": {"number": "555"}}))
+                .unwrap(),
+            vec![vec![
+                ("id".to_string(), Some(Value::Null)),
+                ("number".to_string(), Some(json!("555"))),
+            ]]
+        );
+    }
+
+    #[test]
+    fn derives_flags() {
+        let config: SchemaConfig = r#"{
+            "children": [{"key": "age"}],
+            "flags": [{"name": "is_minor", "filter": "age < 18"}]
+        }"#
+        .parse()
+        .unwrap();
+        let schema = config.schema(&Registry::builtin()).unwrap();
+
+        let records = config
+            .flags()
+            .apply(schema.extract(&json!({"age": 12})).unwrap());
+        assert_eq!(records[0][1], ("is_minor".to_string(), Some(json!(true))));
+
+        let error = r#"{"flags": [{"name": "bad", "filter": "age <"}]}"#
+            .parse::<SchemaConfig>()
+            .unwrap_err();
+        assert_eq!(error.path, "/flags/0/filter");
+    }
+
+    #[test]
+    fn builds_assertions() {
+        let config: SchemaConfig = r#"{
+            "children": [{"key": "id"}],
+            "assertions": {"min_records": 2, "max_null_rate": {"id": 0.5}, "unique": ["id"]}
+        }"#
+        .parse()
+        .unwrap();
+        let schema = config.schema(&Registry::builtin()).unwrap();
+
+        let mut assertions = config.assertions();
+        for document in [json!({"id": 1}), json!({"id": 1})] {
+            assertions.observe(&schema.extract(&document).unwrap()[0]);
+        }
+        assert_eq!(
+            assertions.check(),
+            Err(vec!["id: 1 duplicate values".into()])
+        );
+        assert!("{}"
+            .parse::<SchemaConfig>()
+            .unwrap()
+            .assertions()
+            .check()
+            .is_ok());
+
+        let error = r#"{"assertions": {"unique": "id"}}"#.parse::<SchemaConfig>().unwrap_err();
+        assert_eq!(error.path, "/assertions/unique");
+    }
+
+    #[test]
+    fn routes_to_tables() {
+        let config: SchemaConfig = r#"{
+            "children": [{"key": "kind"}],
+            "route": {"column": "kind", "tables": {"click": "clicks"}, "default": "other"}
+        }"#
+        .parse()
+        .unwrap();
+        let router = config.router().unwrap();
+        let record = |kind: &str| vec![("kind".to_string(), Some(json!(kind)))];
+        assert_eq!(router.table(&record("click")), Some("clicks"));
+        assert_eq!(router.table(&record("view")), Some("other"));
+
+        let config: SchemaConfig = r#"{
+            "children": [
+                {"key": "id"},
+                {"sub": "items", "children": [{"key": "sku"}]},
+                {"sub": "payments", "children": [{"key": "amount"}]}
+            ],
+            "tables": {"items": ["id", "items"], "payments": ["id", "payments"]}
+        }"#
+        .parse()
+        .unwrap();
+        assert!(config.router().is_none());
+        let items = config.table("items").unwrap();
+        let schema = items.schema(&Registry::builtin()).unwrap();
+        assert_eq!(schema.columns(), ["id", "items_sku"]);
+        assert!(config.table("orders").is_none());
+
+        let error = r#"{"children": [{"key": "id"}], "tables": {"t": ["name"]}}"#
+            .parse::<SchemaConfig>()
+            .unwrap_err();
+        assert_eq!(
+            error.message,
+            "table \"t\" lists \"name\", not a root child"
+        );
+        let both = r#"{"route": {"column": "kind", "tables": {}}, "tables": {"t": []}}"#;
+        assert!(both.parse::<SchemaConfig>().is_err());
+    }
+
+    #[test]
+    fn runs_embedded_tests() {
+        let config: SchemaConfig = r#"{
+            "children": [{"key": "age", "transform": "parse_int"}],
+            "flags": [{"name": "is_minor", "filter": "age < 18"}],
+            "tests": [
+                {"name": "minor", "input": {"age": "12"},
+                 "expect": [{"age": 12, "is_minor": true}]},
+                {"name": "no age", "input": {}, "expect": [{"is_minor": true}]}
+            ]
+        }"#
+        .parse()
+        .unwrap();
+        let schema = config.schema(&Registry::builtin()).unwrap();
+
+        let results = config.run_tests(&schema);
+        assert_eq!(results[0], ("minor", Ok(())));
+        assert_eq!(
+            results[1],
+            (
+                "no age",
+                Err(r#"expected [{"is_minor":true}], got [{"is_minor":false}]"#.to_string())
+            )
+        );
+    }
+
+    #[test]
+    fn overlays_tenant_changes() {
+        let base = json!({"children": [
+            {"key": "id", "name": "human_id"},
+            {"sub": "phone", "children": [{"key": "number"}]}
+        ]});
+        let overlay = json!({"children": [
+            {"key": "id", "transform": "parse_int"},
+            {"sub": "phone", "children": [{"key": "number", "name": "tel"}, {"key": "type"}]},
+            {"key": "tenant_code"}
+        ]});
+        let config = SchemaConfig::with_overlay(&base, &overlay).unwrap();
+        let schema = config.schema(&Registry::builtin()).unwrap();
+
+        assert_eq!(
+            schema.columns(),
+            vec!["human_id", "tel", "phone_type", "tenant_code"]
+        );
+        let records = schema.extract(&json!({"id": "3"})).unwrap();
+        assert_eq!(records[0][0].1, Some(json!(3)));
+    }
+
+    #[test]
+    fn reports_config_errors() {
+        let error =
+            r#"{"children": [{"key": "id", "nmae": "x"}]}"#.parse::<SchemaConfig>().unwrap_err();
+        assert_eq!(error.path, "/children/0/nmae");
+        let error = r#"{"children": [{"sub": "a", "children": [{"key": "id", "limit": 1}]}]}"#
+            .parse::<SchemaConfig>()
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "\"limit\" is not a key field at \"/children/0/children/0\""
+        );
+        let error = r#"{"children": [{"sub": "a", "merge": "outer"}]}"#
+            .parse::<SchemaConfig>()
+            .unwrap_err();
+        assert_eq!(error.path, "/children/0/merge");
+
+        let config: SchemaConfig = r#"{"children": [{"key": "id", "transform": "inc"}]}"#
+            .parse()
+            .unwrap();
+        let error = config.schema(&Registry::builtin()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "unknown transform at \"/children/0/transform\""
+        );
+    }
+
+    #[test]
+    fn anonymizes_pii_keys() {
+        let config: SchemaConfig = r#"{"children": [
+            {"key": "name", "pii": "name", "anonymize": "uppercase"},
+            {"key": "email", "pii": "email", "transform": "lowercase"}
+        ]}"#
+        .parse()
+        .unwrap();
+        let schema = config.schema(&Registry::builtin()).unwrap();
+        assert_eq!(schema.check_pii(), Err(vec!["email".to_string()]));
+        let records = schema.extract(&json!({"name": "ann"})).unwrap();
+        assert_eq!(records[0][0], ("name".into(), Some(json!("ANN"))));
+
+        let error = r#"{"children": [{"key": "a", "transform": "trim", "anonymize": "trim"}]}"#
+            .parse::<SchemaConfig>()
+            .unwrap_err();
+        assert_eq!(error.path, "/children/0");
+    }
+
+    #[test]
+    fn applies_registered_transforms() {
+        let registry = Registry::builtin()
+            .register_fallible("positive", |value| match value {
+                Some(Value::Number(n)) if n.as_f64().unwrap_or(0.0) <= 0.0 => {
+                    Err(TransformError::new("not positive"))
+                }
+                value => Ok(value),
+            })
+            .cache("parse_int", 10);
+
+        assert_eq!(
+            registry.apply("parse_int", Some(json!("7"))),
+            Some(Ok(Some(json!(7))))
+        );
+        assert_eq!(registry.apply("trim", None), Some(Ok(None)));
+        assert_eq!(
+            registry.apply("positive", Some(json!(-1))),
+            Some(Err(TransformError::new("not positive")))
+        );
+        assert_eq!(registry.apply("parse_datetime", Some(json!("x"))), None);
+        let names = registry.names();
+        assert!(names
+            .windows(2)
+            .any(|pair| pair == ["lowercase", "null_if_empty"]));
+    }
+}
@@ -0,0 +1,516 @@
+//! CSV output with configurable dialects for picky downstream loaders.
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+
+use serde_json::Value;
+
+use crate::encoding::Encoding;
+use crate::format::Format;
+use crate::sanitize::Sanitize;
+use crate::warehouse::{ColumnType, Warehouse};
+use crate::Record;
+
+/// How a quote character inside a quoted field is escaped.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Escape {
+    /// `"` becomes `""` (RFC 4180).
+    #[default]
+    Double,
+    /// `"` becomes `\"` and `\` becomes `\\`.
+    Backslash,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Quoting {
+    /// Quote only fields containing the delimiter, quote or a line break.
+    #[default]
+    Necessary,
+    /// Quote every field, including the header.
+    Always,
+}
+
+#[derive(Debug, Clone)]
+pub struct Dialect {
+    pub delimiter: char,
+    pub quote: char,
+    pub escape: Escape,
+    pub quoting: Quoting,
+    pub terminator: String,
+    /// Start the output with a byte order mark; only written for UTF-8.
+    pub bom: bool,
+    pub header: bool,
+    pub encoding: Encoding,
+    /// Written unquoted for JSON `null` values, e.g. `\N` for Postgres or
+    /// MySQL loads, so they can be told apart from missing values, which are
+    /// always empty fields; a string equal to it is always quoted. Empty by
+    /// default.
+    pub null: String,
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Dialect {
+            delimiter: ',',
+            quote: '"',
+            escape: Escape::Double,
+            quoting: Quoting::Necessary,
+            terminator: "\n".to_string(),
+            bom: false,
+            header: true,
+            encoding: Encoding::Utf8,
+            null: String::new(),
+        }
+    }
+}
+
+impl Dialect {
+    /// Splits the first line of `input` into column names, skipping a
+    /// leading byte order mark. Quoted names may not contain line breaks.
+    pub fn read_header<R: BufRead>(&self, mut input: R) -> io::Result<Vec<String>> {
+        let mut line = String::new();
+        input.read_line(&mut line)?;
+        let line = line.trim_start_matches('\u{feff}');
+        let line = line.strip_suffix(self.terminator.as_str()).unwrap_or(line);
+        let line = line.trim_end_matches(['\r', '\n']);
+
+        let mut columns = vec![];
+        let mut column = String::new();
+        let mut quoted = false;
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                c if quoted && self.escape == Escape::Backslash && c == '\\' => {
+                    column.extend(chars.next());
+                }
+                c if quoted && c == self.quote => {
+                    if self.escape == Escape::Double && chars.peek() == Some(&self.quote) {
+                        column.push(c);
+                        chars.next();
+                    } else {
+                        quoted = false;
+                    }
+                }
+                c if !quoted && c == self.quote => quoted = true,
+                c if !quoted && c == self.delimiter => columns.push(std::mem::take(&mut column)),
+                c => column.push(c),
+            }
+        }
+        if !line.is_empty() {
+            columns.push(column);
+        }
+        Ok(columns)
+    }
+
+    fn field(&self, field: &str) -> String {
+        let needs_quotes = self.quoting == Quoting::Always
+            || field.contains([self.delimiter, self.quote, '\n', '\r'])
+            || (!self.null.is_empty() && field == self.null);
+        if !needs_quotes {
+            return field.to_string();
+        }
+
+        let mut quoted = String::with_capacity(field.len() + 2);
+        quoted.push(self.quote);
+        for c in field.chars() {
+            match self.escape {
+                Escape::Double if c == self.quote => quoted.push(self.quote),
+                Escape::Backslash if c == self.quote || c == '\\' => quoted.push('\\'),
+                _ => {}
+            }
+            quoted.push(c);
+        }
+        quoted.push(self.quote);
+        quoted
+    }
+}
+
+/// What to do when appending to a file whose header differs from the
+/// schema's columns.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Evolution {
+    /// Refuse to append unless the columns match, in the same order.
+    #[default]
+    Fail,
+    /// Keep the file's columns and leave ones the schema no longer has
+    /// empty. Columns the file lacks still fail, since a CSV file can't gain
+    /// a column in place.
+    AddNullColumn,
+    /// Like `AddNullColumn`, but leave out columns the file lacks instead of
+    /// failing.
+    DropExtra,
+}
+
+impl FromStr for Evolution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(Self::Fail),
+            "add-null-column" => Ok(Self::AddNullColumn),
+            "drop-extra" => Ok(Self::DropExtra),
+            _ => Err(format!("unknown evolution policy {s:?}")),
+        }
+    }
+}
+
+/// Writes records as CSV rows with a fixed column order; columns a record
+/// lacks are left empty.
+pub struct CsvWriter<W: Write> {
+    out: W,
+    columns: Vec<String>,
+    dialect: Dialect,
+    formats: HashMap<String, Format>,
+    sanitize: Sanitize,
+    warehouse: Option<Warehouse>,
+}
+
+impl<W: Write> CsvWriter<W> {
+    /// Creates the writer, emitting the BOM and header right away if the
+    /// dialect asks for them.
+    pub fn new(mut out: W, columns: Vec<String>, dialect: Dialect) -> io::Result<Self> {
+        if dialect.bom && dialect.encoding == Encoding::Utf8 {
+            out.write_all("\u{feff}".as_bytes())?;
+        }
+
+        let mut writer = CsvWriter {
+            out,
+            columns,
+            dialect,
+            formats: HashMap::new(),
+            sanitize: Sanitize::default(),
+            warehouse: None,
+        };
+        if writer.dialect.header {
+            let header = writer.columns.clone();
+            writer.write_row(header.iter().map(|name| Some(name.as_str())))?;
+        }
+
+        Ok(writer)
+    }
+
+    /// Creates a writer appending to output that already starts with
+    /// `header` (see `Dialect::read_header`), so no BOM or header is
+    /// written. Rows follow the file's columns, reconciled with `columns`
+    /// according to `evolution`; a refused change is an `InvalidData` error
+    /// naming the added and removed columns.
+    pub fn append(
+        out: W,
+        header: Vec<String>,
+        columns: &[String],
+        dialect: Dialect,
+        evolution: Evolution,
+    ) -> io::Result<Self> {
+        let added: Vec<&str> = columns
+            .iter()
+            .filter(|column| !header.contains(column))
+            .map(String::as_str)
+            .collect();
+        let removed: Vec<&str> = header
+            .iter()
+            .filter(|column| !columns.contains(column))
+            .map(String::as_str)
+            .collect();
+
+        let refused = match evolution {
+            Evolution::Fail => header != columns,
+            Evolution::AddNullColumn => !added.is_empty(),
+            Evolution::DropExtra => false,
+        };
+        if refused {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "columns changed: added [{}], removed [{}]",
+                    added.join(", "),
+                    removed.join(", ")
+                ),
+            ));
+        }
+
+        Ok(CsvWriter {
+            out,
+            columns: header,
+            dialect,
+            formats: HashMap::new(),
+            sanitize: Sanitize::default(),
+            warehouse: None,
+        })
+    }
+
+    /// Presents the given columns with their `Format`, usually
+    /// `SubSchema::formats()`.
+    pub fn with_formats(mut self, formats: HashMap<String, Format>) -> Self {
+        self.formats = formats;
+        self
+    }
+
+    /// Cleans string values before they are written. Header names are
+    /// left alone.
+    pub fn with_sanitize(mut self, sanitize: Sanitize) -> Self {
+        self.sanitize = sanitize;
+        self
+    }
+
+    /// Renders values the way `warehouse`'s bulk loader reads them (see
+    /// `Warehouse::render`), each typed by its own value, e.g. a string
+    /// that parses as a timestamp is one. Columns with a `Format` keep it.
+    pub fn for_warehouse(mut self, warehouse: Warehouse) -> Self {
+        self.warehouse = Some(warehouse);
+        self
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        let row: Vec<Option<String>> = self
+            .columns
+            .iter()
+            .map(|column| {
+                let value = record
+                    .iter()
+                    .find(|(name, _)| name == column)
+                    .and_then(|(_, value)| value.as_ref());
+                let text = match (value, self.formats.get(column)) {
+                    (Some(Value::Null), _) if !self.dialect.null.is_empty() => return None,
+                    (Some(value), Some(format)) => {
+                        format.render(value).unwrap_or_else(|| format_value(value))
+                    }
+                    (Some(value), None) => self
+                        .warehouse
+                        .zip(ColumnType::of(value))
+                        .and_then(|(warehouse, ty)| warehouse.render(ty, value))
+                        .unwrap_or_else(|| format_value(value)),
+                    (None, _) => String::new(),
+                };
+                Some(match value {
+                    Some(Value::String(_)) => self.sanitize.string(&text).into_owned(),
+                    _ => text,
+                })
+            })
+            .collect();
+        self.write_row(row.iter().map(Option::as_deref))
+    }
+
+    /// Writes `fields` quoted as needed; `None` is a `null`.
+    fn write_row<'s>(&mut self, fields: impl Iterator<Item = Option<&'s str>>) -> io::Result<()> {
+        let mut line = String::new();
+        for (i, field) in fields.enumerate() {
+            if i > 0 {
+                line.push(self.dialect.delimiter);
+            }
+            match field {
+                Some(field) => line.push_str(&self.dialect.field(field)),
+                None => line.push_str(&self.dialect.null),
+            }
+        }
+        line.push_str(&self.dialect.terminator);
+        self.out.write_all(&self.dialect.encoding.encode(&line))
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// Renders a value as CSV text: strings verbatim, nested values as JSON.
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn write(dialect: Dialect) -> String {
+        let records: Vec<Record> = vec![
+            vec![
+                ("id".into(), Some(json!(1))),
+                ("name".into(), Some(json!("Alonso, \"Felix\""))),
+            ],
+            vec![("id".into(), Some(json!(2)))],
+        ];
+        let columns = vec!["id".to_string(), "name".to_string()];
+
+        let mut writer = CsvWriter::new(vec![], columns, dialect).unwrap();
+        for record in records.iter() {
+            writer.write(record).unwrap();
+        }
+        String::from_utf8(writer.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn default_dialect() {
+        assert_eq!(
+            write(Dialect::default()),
+            "id,name\n1,\"Alonso, \"\"Felix\"\"\"\n2,\n"
+        );
+    }
+
+    #[test]
+    fn custom_dialect() {
+        let dialect = Dialect {
+            delimiter: ';',
+            quote: '\'',
+            escape: Escape::Backslash,
+            quoting: Quoting::Always,
+            terminator: "\r\n".to_string(),
+            bom: true,
+            header: false,
+            encoding: Encoding::Utf8,
+            null: String::new(),
+        };
+        assert_eq!(
+            write(dialect),
+            "\u{feff}'1';'Alonso, \"Felix\"'\r\n'2';''\r\n"
+        );
+    }
+
+    #[test]
+    fn column_formats() {
+        let record: Record = vec![
+            ("amount".into(), Some(json!(2.34567))),
+            ("active".into(), Some(json!(true))),
+        ];
+        let columns = vec!["amount".to_string(), "active".to_string()];
+        let formats = HashMap::from([
+            ("amount".to_string(), Format::Precision(2)),
+            ("active".to_string(), Format::BoolAsInt),
+        ]);
+        let dialect = Dialect {
+            header: false,
+            ..Dialect::default()
+        };
+
+        let mut writer = CsvWriter::new(vec![], columns, dialect)
+            .unwrap()
+            .with_formats(formats);
+        writer.write(&record).unwrap();
+        assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), "2.35,1\n");
+    }
+
+    #[test]
+    fn renders_for_a_warehouse() {
+        let record: Record = vec![
+            ("at".into(), Some(json!("2024-05-01T12:30:00+02:00"))),
+            ("ok".into(), Some(json!(true))),
+            ("tags".into(), Some(json!(["a", "b"]))),
+            ("day".into(), Some(json!("2024-05-01"))),
+        ];
+        let columns: Vec<String> = record.iter().map(|(name, _)| name.clone()).collect();
+        let formats = HashMap::from([("day".to_string(), Format::Date("%d/%m/%Y".into()))]);
+        let dialect = Dialect {
+            header: false,
+            ..Dialect::default()
+        };
+
+        let mut writer = CsvWriter::new(vec![], columns, dialect)
+            .unwrap()
+            .with_formats(formats)
+            .for_warehouse(Warehouse::Postgres);
+        writer.write(&record).unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "2024-05-01 10:30:00+00,t,\"[\"\"a\"\",\"\"b\"\"]\",01/05/2024\n"
+        );
+    }
+
+    #[test]
+    fn null_marker_differs_from_missing() {
+        let record: Record = vec![
+            ("a".into(), Some(Value::Null)),
+            ("b".into(), None),
+            ("c".into(), Some(json!("\\N"))),
+        ];
+        let columns = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        for quoting in [Quoting::Always, Quoting::Necessary] {
+            let dialect = Dialect {
+                header: false,
+                quoting,
+                null: "\\N".to_string(),
+                ..Dialect::default()
+            };
+
+            let mut writer = CsvWriter::new(vec![], columns.clone(), dialect).unwrap();
+            writer.write(&record).unwrap();
+            let missing = if quoting == Quoting::Always {
+                "\"\""
+            } else {
+                ""
+            };
+            assert_eq!(
+                String::from_utf8(writer.into_inner()).unwrap(),
+                format!("\\N,{missing},\"\\N\"\n")
+            );
+        }
+    }
+
+    #[test]
+    fn sanitized_strings() {
+        let record: Record = vec![("note".into(), Some(json!("=HYPERLINK(\"x\")")))];
+        let sanitize = Sanitize {
+            formulas: true,
+            control_chars: true,
+            nfc: false,
+        };
+
+        let mut writer = CsvWriter::new(vec![], vec!["note".to_string()], Dialect::default())
+            .unwrap()
+            .with_sanitize(sanitize);
+        writer.write(&record).unwrap();
+        assert_eq!(
+            String::from_utf8(writer.into_inner()).unwrap(),
+            "note\n\"'=HYPERLINK(\"\"x\"\")\"\n"
+        );
+    }
+
+    #[test]
+    fn latin1_output() {
+        let record: Record = vec![("name".into(), Some(json!("Zoë ✓")))];
+        let dialect = Dialect {
+            bom: true,
+            encoding: Encoding::Latin1,
+            ..Dialect::default()
+        };
+
+        let mut writer = CsvWriter::new(vec![], vec!["name".to_string()], dialect).unwrap();
+        writer.write(&record).unwrap();
+        assert_eq!(writer.into_inner(), b"name\nZo\xeb ?\n");
+    }
+
+    #[test]
+    fn append_evolves_columns() {
+        let dialect = Dialect::default();
+        let header = dialect
+            .read_header("\u{feff}id,\"full \"\"name\"\"\",old\nignored\n".as_bytes())
+            .unwrap();
+        assert_eq!(header, vec!["id", "full \"name\"", "old"]);
+
+        let columns: Vec<String> = vec!["id".into(), "full \"name\"".into(), "new".into()];
+        let record: Record = vec![
+            ("id".into(), Some(json!(7))),
+            ("new".into(), Some(json!("x"))),
+        ];
+
+        for evolution in [Evolution::Fail, Evolution::AddNullColumn] {
+            let error =
+                CsvWriter::append(vec![], header.clone(), &columns, dialect.clone(), evolution);
+            assert_eq!(
+                error.err().unwrap().to_string(),
+                "columns changed: added [new], removed [old]"
+            );
+        }
+
+        let mut writer =
+            CsvWriter::append(vec![], header, &columns, dialect, Evolution::DropExtra).unwrap();
+        writer.write(&record).unwrap();
+        assert_eq!(String::from_utf8(writer.into_inner()).unwrap(), "7,,\n");
+
+        assert_eq!("drop-extra".parse(), Ok(Evolution::DropExtra));
+        assert!("drop".parse::<Evolution>().is_err());
+    }
+}
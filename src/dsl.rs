@@ -0,0 +1,179 @@
+//! A compact text syntax for schemas, for extraction rules edited outside
+//! Rust code:
+//!
+//! ```text
+//! id -> human_id, name, phone { type, number }, family { relation -> relationship }
+//! ```
+//!
+//! A bare name is a key, `key -> column` renames one and `name { ... }`
+//! descends into a sub-document. Names with characters other than letters,
+//! digits and `_-.$@` are written in double quotes, without escapes.
+//! Transforms and options aren't expressible; `config` covers those.
+
+use std::fmt;
+
+use crate::{KeyOptions, KeySchema, Node, SubOptions, SubSchema};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    /// Byte offset into the text where parsing failed.
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parses `text` into a root schema whose names borrow from it. (A
+/// `FromStr` impl isn't possible since schemas borrow their names.)
+pub fn parse(text: &str) -> Result<SubSchema<'_>, ParseError> {
+    let mut parser = Parser { text, pos: 0 };
+    let children = parser.list()?;
+    match parser.peek() {
+        None => Ok(SubSchema {
+            name: "",
+            children,
+            options: SubOptions::default(),
+        }),
+        Some(_) => Err(parser.error("expected `,`")),
+    }
+}
+
+struct Parser<'t> {
+    text: &'t str,
+    pos: usize,
+}
+
+impl<'t> Parser<'t> {
+    fn error(&self, message: &str) -> ParseError {
+        ParseError {
+            position: self.pos,
+            message: message.to_string(),
+        }
+    }
+
+    /// The rest of the text after whitespace, without consuming it.
+    fn peek(&mut self) -> Option<&'t str> {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+        Some(&self.text[self.pos..]).filter(|rest| !rest.is_empty())
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        let found = self.peek().is_some_and(|rest| rest.starts_with(token));
+        if found {
+            self.pos += token.len();
+        }
+        found
+    }
+
+    /// Comma-separated items up to the end of the text or a `}`, with an
+    /// optional trailing comma.
+    fn list(&mut self) -> Result<Vec<Node<'t>>, ParseError> {
+        let mut nodes = vec![];
+        while self.peek().is_some_and(|rest| !rest.starts_with('}')) {
+            nodes.push(self.item()?);
+            if !self.eat(",") {
+                break;
+            }
+        }
+        if nodes.is_empty() {
+            return Err(self.error("expected a name"));
+        }
+        Ok(nodes)
+    }
+
+    fn item(&mut self) -> Result<Node<'t>, ParseError> {
+        let name = self.name()?;
+        if self.eat("{") {
+            let children = self.list()?;
+            if !self.eat("}") {
+                return Err(self.error("expected `}`"));
+            }
+            return Ok(Node::Sub(SubSchema {
+                name,
+                children,
+                options: SubOptions::default(),
+            }));
+        }
+        let column = if self.eat("->") {
+            Some(self.name()?)
+        } else {
+            None
+        };
+        Ok(Node::Key(KeySchema {
+            key: name,
+            name: column,
+            transform: None,
+            options: KeyOptions::default(),
+        }))
+    }
+
+    fn name(&mut self) -> Result<&'t str, ParseError> {
+        let rest = self.peek().unwrap_or("");
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted
+                .find('"')
+                .ok_or_else(|| self.error("unterminated name"))?;
+            self.pos += end + 2;
+            return Ok(&quoted[..end]);
+        }
+        let end = rest
+            .find(|c: char| !(c.is_alphanumeric() || "_-.$@".contains(c)))
+            .unwrap_or(rest.len());
+        // `-` is a name character, but not as the start of `->`.
+        let end = rest.find("->").map_or(end, |arrow| arrow.min(end));
+        if end == 0 {
+            return Err(self.error("expected a name"));
+        }
+        self.pos += end;
+        Ok(&rest[..end])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_schema_text() {
+        let schema = parse(
+            "id -> human_id, name, phone { type, number },\n\
+             family { relation->relationship, \"full name\" }",
+        )
+        .unwrap();
+        assert_eq!(
+            schema.columns(),
+            vec![
+                "human_id",
+                "name",
+                "phone_type",
+                "phone_number",
+                "relationship",
+                "family_full name"
+            ]
+        );
+
+        let records = schema
+            .extract(&json!({"id": 1, "family": [{"relation": "mom"}]}))
+            .unwrap();
+        assert_eq!(records[0][0], ("human_id".to_string(), Some(json!(1))));
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        assert_eq!(parse("a, b {").unwrap_err().position, 6);
+        assert_eq!(
+            parse("a b").unwrap_err().to_string(),
+            "expected `,` at position 2"
+        );
+        assert!(parse("").is_err());
+        assert!(parse("a -> ").is_err());
+    }
+}
@@ -0,0 +1,46 @@
+//! Character encodings for text sinks feeding systems that can't read
+//! UTF-8.
+
+use std::borrow::Cow;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    /// ISO-8859-1; characters above U+00FF become `?`.
+    Latin1,
+    /// 7-bit ASCII; characters above U+007F become `?`.
+    Ascii,
+}
+
+impl Encoding {
+    pub fn encode<'s>(&self, s: &'s str) -> Cow<'s, [u8]> {
+        let limit = match self {
+            Self::Utf8 => return Cow::Borrowed(s.as_bytes()),
+            Self::Latin1 => 0xff,
+            Self::Ascii => 0x7f,
+        };
+
+        if s.is_ascii() {
+            return Cow::Borrowed(s.as_bytes());
+        }
+
+        Cow::Owned(
+            s.chars()
+                .map(|c| if (c as u32) <= limit { c as u8 } else { b'?' })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn replaces_unrepresentable_characters() {
+        assert_eq!(Encoding::Latin1.encode("café €5"), b"caf\xe9 ?5".as_slice());
+        assert_eq!(Encoding::Ascii.encode("café"), b"caf?".as_slice());
+        assert_eq!(Encoding::Utf8.encode("café"), "café".as_bytes());
+    }
+}
@@ -0,0 +1,144 @@
+//! Realistic fake values in place of real ones, behind the `faker` feature,
+//! for production-shaped test datasets:
+//! `key!("email").anonymize(fake(Kind::Email, "staging"))`.
+//!
+//! Fakes are seeded by a hash of the original value, so the same input
+//! always gets the same fake and joins across tables still line up.
+
+use std::fmt;
+use std::str::FromStr;
+
+use fake::faker::address::en::{CityName, StreetName, ZipCode};
+use fake::faker::company::en::CompanyName;
+use fake::faker::internet::en::{IPv4, SafeEmail, Username};
+use fake::faker::name::en::{FirstName, LastName, Name};
+use fake::faker::phone_number::en::PhoneNumber;
+use fake::Fake;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde_json::Value;
+
+use crate::{fnv_str, FNV_OFFSET};
+
+/// What kind of value to fake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Name,
+    FirstName,
+    LastName,
+    Email,
+    Username,
+    Phone,
+    Street,
+    City,
+    Zip,
+    Company,
+    Ip,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 11] = [
+        Kind::Name,
+        Kind::FirstName,
+        Kind::LastName,
+        Kind::Email,
+        Kind::Username,
+        Kind::Phone,
+        Kind::Street,
+        Kind::City,
+        Kind::Zip,
+        Kind::Company,
+        Kind::Ip,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Name => "name",
+            Kind::FirstName => "first_name",
+            Kind::LastName => "last_name",
+            Kind::Email => "email",
+            Kind::Username => "username",
+            Kind::Phone => "phone",
+            Kind::Street => "street",
+            Kind::City => "city",
+            Kind::Zip => "zip",
+            Kind::Company => "company",
+            Kind::Ip => "ip",
+        }
+    }
+
+    fn generate(self, rng: &mut StdRng) -> String {
+        match self {
+            Kind::Name => Name().fake_with_rng(rng),
+            Kind::FirstName => FirstName().fake_with_rng(rng),
+            Kind::LastName => LastName().fake_with_rng(rng),
+            Kind::Email => SafeEmail().fake_with_rng(rng),
+            Kind::Username => Username().fake_with_rng(rng),
+            Kind::Phone => PhoneNumber().fake_with_rng(rng),
+            Kind::Street => StreetName().fake_with_rng(rng),
+            Kind::City => CityName().fake_with_rng(rng),
+            Kind::Zip => ZipCode().fake_with_rng(rng),
+            Kind::Company => CompanyName().fake_with_rng(rng),
+            Kind::Ip => IPv4().fake_with_rng(rng),
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Kind::ALL
+            .into_iter()
+            .find(|kind| kind.name() == s)
+            .ok_or_else(|| format!("unknown fake kind {s:?}"))
+    }
+}
+
+/// A transform replacing values with fakes of `kind`, seeded by `salt` and
+/// the original value: a different salt gives different fakes, so they
+/// can't be matched against a table of fakes of guessed values. Absent and
+/// null values are kept.
+pub fn fake(kind: Kind, salt: &str) -> impl Fn(Option<Value>) -> Option<Value> + Send + Sync {
+    let salt = salt.to_string();
+    move |value| match value {
+        None | Some(Value::Null) => value,
+        Some(value) => {
+            let mut hash = FNV_OFFSET;
+            fnv_str(&mut hash, &salt);
+            fnv_str(&mut hash, &value.to_string());
+            let mut rng = StdRng::seed_from_u64(hash);
+            Some(Value::String(kind.generate(&mut rng)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn fakes_are_deterministic_per_value_and_salt() {
+        let email = fake(Kind::Email, "a");
+        let first = email(Some(json!("felix@example.com")));
+        assert!(first.as_ref().is_some_and(Value::is_string));
+        assert_eq!(email(Some(json!("felix@example.com"))), first);
+        assert_ne!(email(Some(json!("alonso@example.com"))), first);
+        assert_ne!(
+            fake(Kind::Email, "b")(Some(json!("felix@example.com"))),
+            first
+        );
+        assert_eq!(email(Some(Value::Null)), Some(Value::Null));
+        assert_eq!(email(None), None);
+
+        assert_eq!("first_name".parse(), Ok(Kind::FirstName));
+        assert!("ssn".parse::<Kind>().is_err());
+    }
+}
@@ -0,0 +1,436 @@
+//! A small boolean expression language for selecting documents and array
+//! elements, e.g. `relation == "mom" || relation == "dad"` or
+//! `type == "purchase" && country in ["US", "CA"]`.
+//!
+//! Paths are dot-separated object keys resolved against the value being
+//! tested; a path that doesn't resolve compares as `null`.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+use serde_json::{Number, Value};
+
+/// A compiled filter expression. Parse one with `str::parse`.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterError {
+    /// Byte offset into the expression where parsing failed.
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare(Operand, Op, Operand),
+    In(Operand, Vec<Value>),
+    Truthy(Operand),
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Path(String),
+    Literal(Value),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Filter {
+    /// The expression text this filter was parsed from.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Evaluates the filter against `value`, usually a JSON object.
+    pub fn matches(&self, value: &Value) -> bool {
+        self.expr.eval(value)
+    }
+}
+
+impl FromStr for Filter {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            end: s.len(),
+        };
+        let expr = parser.or()?;
+        match parser.peek() {
+            None => Ok(Filter {
+                source: s.to_string(),
+                expr,
+            }),
+            Some(_) => Err(parser.error("unexpected token")),
+        }
+    }
+}
+
+impl Expr {
+    fn eval(&self, value: &Value) -> bool {
+        match self {
+            Self::Or(left, right) => left.eval(value) || right.eval(value),
+            Self::And(left, right) => left.eval(value) && right.eval(value),
+            Self::Not(expr) => !expr.eval(value),
+            Self::Compare(left, op, right) => {
+                let left = left.resolve(value);
+                let right = right.resolve(value);
+                match op {
+                    Op::Eq => equals(left, right),
+                    Op::Ne => !equals(left, right),
+                    Op::Lt => compare(left, right) == Some(Ordering::Less),
+                    Op::Le => {
+                        matches!(compare(left, right), Some(Ordering::Less | Ordering::Equal))
+                    }
+                    Op::Gt => compare(left, right) == Some(Ordering::Greater),
+                    Op::Ge => matches!(
+                        compare(left, right),
+                        Some(Ordering::Greater | Ordering::Equal)
+                    ),
+                }
+            }
+            Self::In(operand, list) => {
+                let needle = operand.resolve(value);
+                list.iter().any(|item| equals(needle, item))
+            }
+            Self::Truthy(operand) => {
+                !matches!(operand.resolve(value), Value::Null | Value::Bool(false))
+            }
+        }
+    }
+}
+
+static NULL: Value = Value::Null;
+
+impl Operand {
+    fn resolve<'v>(&'v self, value: &'v Value) -> &'v Value {
+        match self {
+            Self::Path(path) => crate::lookup(value, path).unwrap_or(&NULL),
+            Self::Literal(literal) => literal,
+        }
+    }
+}
+
+/// Equality that treats `1` and `1.0` as the same number.
+fn equals(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Number(l), Value::Number(r)) => l.as_f64() == r.as_f64(),
+        _ => left == right,
+    }
+}
+
+/// Orders numbers numerically and strings lexically; anything else is
+/// unordered, so every `<`-style comparison on it is false.
+fn compare(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left, right) {
+        (Value::Number(l), Value::Number(r)) => l.as_f64()?.partial_cmp(&r.as_f64()?),
+        (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Str(String),
+    Num(f64),
+    Ident(String),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Comma,
+}
+
+fn tokenize(s: &str) -> Result<Vec<(usize, Token)>, FilterError> {
+    let mut tokens = vec![];
+    let mut chars = s.char_indices().peekable();
+
+    while let Some(&(pos, c)) = chars.peek() {
+        let error = |message: &str| FilterError {
+            position: pos,
+            message: message.to_string(),
+        };
+
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let token = match c {
+            '(' | ')' | '[' | ']' | ',' => {
+                chars.next();
+                match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    '[' => Token::LBracket,
+                    ']' => Token::RBracket,
+                    _ => Token::Comma,
+                }
+            }
+            '=' | '!' | '<' | '>' | '&' | '|' => {
+                chars.next();
+                let next = chars.peek().map(|&(_, c)| c);
+                let (token, double) = match (c, next) {
+                    ('=', Some('=')) => (Token::Op(Op::Eq), true),
+                    ('!', Some('=')) => (Token::Op(Op::Ne), true),
+                    ('<', Some('=')) => (Token::Op(Op::Le), true),
+                    ('>', Some('=')) => (Token::Op(Op::Ge), true),
+                    ('&', Some('&')) => (Token::And, true),
+                    ('|', Some('|')) => (Token::Or, true),
+                    ('!', _) => (Token::Not, false),
+                    ('<', _) => (Token::Op(Op::Lt), false),
+                    ('>', _) => (Token::Op(Op::Gt), false),
+                    _ => return Err(error("unknown operator")),
+                };
+                if double {
+                    chars.next();
+                }
+                token
+            }
+            '"' => {
+                chars.next();
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c)) => string.push(c),
+                            None => return Err(error("unterminated string")),
+                        },
+                        Some((_, c)) => string.push(c),
+                        None => return Err(error("unterminated string")),
+                    }
+                }
+                Token::Str(string)
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut number = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if c.is_ascii_digit() || c == '.' || (c == '-' && number.is_empty()) {
+                        number.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                Token::Num(number.parse().map_err(|_| error("invalid number"))?)
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let mut ident = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if c.is_alphanumeric() || c == '_' || c == '.' {
+                        ident.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                Token::Ident(ident)
+            }
+            _ => return Err(error("unexpected character")),
+        };
+
+        tokens.push((pos, token));
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|(_, token)| token.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn error(&self, message: &str) -> FilterError {
+        FilterError {
+            position: self.tokens.get(self.pos).map_or(self.end, |(pos, _)| *pos),
+            message: message.to_string(),
+        }
+    }
+
+    fn expect(&mut self, token: Token) -> Result<(), FilterError> {
+        if self.peek() == Some(&token) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected {token:?}")))
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, FilterError> {
+        let mut expr = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr, FilterError> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.pos += 1;
+                Ok(Expr::Not(Box::new(self.unary()?)))
+            }
+            Some(Token::LParen) => {
+                self.pos += 1;
+                let expr = self.or()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            _ => self.comparison(),
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr, FilterError> {
+        let left = self.operand()?;
+        match self.peek() {
+            Some(&Token::Op(op)) => {
+                self.pos += 1;
+                Ok(Expr::Compare(left, op, self.operand()?))
+            }
+            Some(Token::Ident(ident)) if ident == "in" => {
+                self.pos += 1;
+                Ok(Expr::In(left, self.list()?))
+            }
+            _ => Ok(Expr::Truthy(left)),
+        }
+    }
+
+    fn list(&mut self) -> Result<Vec<Value>, FilterError> {
+        self.expect(Token::LBracket)?;
+        let mut items = vec![];
+        if self.peek() == Some(&Token::RBracket) {
+            self.pos += 1;
+            return Ok(items);
+        }
+        loop {
+            match self.operand()? {
+                Operand::Literal(value) => items.push(value),
+                Operand::Path(_) => return Err(self.error("expected literal in list")),
+            }
+            match self.next() {
+                Some(Token::Comma) => {}
+                Some(Token::RBracket) => return Ok(items),
+                _ => {
+                    self.pos -= 1;
+                    return Err(self.error("expected , or ]"));
+                }
+            }
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, FilterError> {
+        let operand = match self.peek() {
+            Some(Token::Str(s)) => Operand::Literal(Value::String(s.clone())),
+            Some(Token::Num(n)) => match Number::from_f64(*n) {
+                Some(n) => Operand::Literal(Value::Number(n)),
+                None => return Err(self.error("invalid number")),
+            },
+            Some(Token::Ident(ident)) => match ident.as_str() {
+                "true" => Operand::Literal(Value::Bool(true)),
+                "false" => Operand::Literal(Value::Bool(false)),
+                "null" => Operand::Literal(Value::Null),
+                "in" => return Err(self.error("expected value")),
+                path => Operand::Path(path.to_string()),
+            },
+            _ => return Err(self.error("expected value")),
+        };
+        self.pos += 1;
+        Ok(operand)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn check(filter: &str, value: &Value) -> bool {
+        filter.parse::<Filter>().unwrap().matches(value)
+    }
+
+    #[test]
+    fn compares_paths_and_literals() {
+        let value = json!({"relation": "mom", "age": 42, "phone": {"type": "cell"}});
+
+        assert!(check(r#"relation == "mom" || relation == "dad""#, &value));
+        assert!(check("age >= 18 && age < 65", &value));
+        assert!(check(r#"phone.type != "home""#, &value));
+        assert!(check("age == 42.0", &value));
+        assert!(check("!(age < 18)", &value));
+        assert!(check("email == null", &value));
+        assert!(!check("email", &value));
+    }
+
+    #[test]
+    fn membership() {
+        let value = json!({"type": "purchase", "country": "CA"});
+
+        assert!(check(
+            r#"type == "purchase" && country in ["US", "CA"]"#,
+            &value
+        ));
+        assert!(!check(r#"country in ["US"]"#, &value));
+    }
+
+    #[test]
+    fn reports_parse_errors() {
+        let err = "age >".parse::<Filter>().unwrap_err();
+        assert_eq!(err.position, 5);
+
+        let err = r#"age == 1 relation"#.parse::<Filter>().unwrap_err();
+        assert_eq!(err.position, 9);
+    }
+}
@@ -0,0 +1,124 @@
+//! Presentation rules a key can declare for its column, applied by sinks at
+//! serialization time rather than during extraction.
+
+use serde_json::{Number, Value};
+
+use crate::time;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Format {
+    /// Timestamps (RFC 3339 strings or Unix seconds) rendered with a
+    /// `time::format_timestamp` pattern, e.g. `"%Y-%m-%d"`.
+    Date(String),
+    /// Numbers rounded to this many decimal places.
+    Precision(usize),
+    /// Booleans written as `1`/`0`.
+    BoolAsInt,
+    /// Text right-aligned to at least `width` characters with `fill`.
+    PadLeft(usize, char),
+    /// Text left-aligned to at least `width` characters with `fill`.
+    PadRight(usize, char),
+}
+
+impl Format {
+    /// Formats a value for a typed sink such as JSON, keeping numbers and
+    /// booleans-as-ints numeric. Values the rule doesn't apply to (e.g. a
+    /// string under `Precision`) pass through unchanged.
+    pub fn apply(&self, value: &Value) -> Value {
+        match (self, value) {
+            (Self::Precision(places), Value::Number(n)) => {
+                let factor = 10f64.powi(*places as i32);
+                n.as_f64()
+                    .and_then(|f| Number::from_f64((f * factor).round() / factor))
+                    .map_or_else(|| value.clone(), Value::Number)
+            }
+            (Self::BoolAsInt, Value::Bool(b)) => Value::from(*b as u8),
+            (Self::Date(_) | Self::PadLeft(_, _) | Self::PadRight(_, _), _) => {
+                match self.render(value) {
+                    Some(text) => Value::String(text),
+                    None => value.clone(),
+                }
+            }
+            _ => value.clone(),
+        }
+    }
+
+    /// Formats a value for a text sink such as CSV, or `None` if the rule
+    /// doesn't apply to it.
+    pub fn render(&self, value: &Value) -> Option<String> {
+        match (self, value) {
+            (Self::Date(pattern), _) => {
+                time::timestamp_of(value).map(|secs| time::format_timestamp(secs, pattern))
+            }
+            (Self::Precision(places), Value::Number(n)) => {
+                n.as_f64().map(|f| format!("{f:.places$}"))
+            }
+            (Self::BoolAsInt, Value::Bool(b)) => Some(if *b { "1" } else { "0" }.to_string()),
+            (Self::PadLeft(_, _) | Self::PadRight(_, _), Value::Null) => None,
+            (Self::PadLeft(width, fill), _) => {
+                let text = text(value);
+                let padding = width.saturating_sub(text.chars().count());
+                Some(
+                    std::iter::repeat_n(*fill, padding)
+                        .chain(text.chars())
+                        .collect(),
+                )
+            }
+            (Self::PadRight(width, fill), _) => {
+                let text = text(value);
+                let padding = width.saturating_sub(text.chars().count());
+                Some(
+                    text.chars()
+                        .chain(std::iter::repeat_n(*fill, padding))
+                        .collect(),
+                )
+            }
+            _ => None,
+        }
+    }
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn render_for_text_sinks() {
+        let date = Format::Date("%d/%m/%Y".into());
+        assert_eq!(
+            date.render(&json!("2024-05-01T12:30:00Z")),
+            Some("01/05/2024".into())
+        );
+        assert_eq!(date.render(&json!("soon")), None);
+        assert_eq!(
+            Format::Precision(2).render(&json!(1.5)),
+            Some("1.50".into())
+        );
+        assert_eq!(Format::BoolAsInt.render(&json!(true)), Some("1".into()));
+        assert_eq!(
+            Format::PadLeft(5, '0').render(&json!(42)),
+            Some("00042".into())
+        );
+        assert_eq!(
+            Format::PadRight(4, '.').render(&json!("ab")),
+            Some("ab..".into())
+        );
+    }
+
+    #[test]
+    fn apply_for_typed_sinks() {
+        assert_eq!(Format::Precision(1).apply(&json!(2.46)), json!(2.5));
+        assert_eq!(Format::BoolAsInt.apply(&json!(false)), json!(0));
+        assert_eq!(Format::Precision(1).apply(&json!("n/a")), json!("n/a"));
+        assert_eq!(Format::PadLeft(4, '0').apply(&Value::Null), Value::Null);
+        assert_eq!(Format::PadRight(2, ' ').render(&Value::Null), None);
+    }
+}
@@ -0,0 +1,163 @@
+//! IP address enrichment from a local MaxMind database, behind the `geoip`
+//! feature, e.g. for access logs:
+//!
+//! ```ignore
+//! let geoip = GeoIp::open(Path::new("GeoLite2-City.mmdb"))?;
+//! let schema = doc! { key!("client_ip", "country", geoip.transform(Field::Country)) };
+//! ```
+//!
+//! `config::Registry::geoip` registers the transforms for configs.
+
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+
+use maxminddb::{geoip2, Reader};
+use serde_json::{json, Value};
+
+/// What to look up for an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    /// ISO 3166-1 country code, e.g. `"US"`; from City and Country
+    /// databases.
+    Country,
+    /// ISO 3166-2 code of the largest subdivision, e.g. `"CA"`; from City
+    /// databases.
+    Region,
+    /// Autonomous system number; from ASN databases.
+    Asn,
+    /// Autonomous system organization; from ASN databases.
+    AsnOrganization,
+}
+
+impl Field {
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Country => "country",
+            Field::Region => "region",
+            Field::Asn => "asn",
+            Field::AsnOrganization => "asn_org",
+        }
+    }
+}
+
+/// An open MaxMind database, shared by the transforms made from it.
+#[derive(Clone)]
+pub struct GeoIp {
+    reader: Arc<Reader<Vec<u8>>>,
+}
+
+impl fmt::Debug for GeoIp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("GeoIp")
+            .field("database_type", &self.reader.metadata.database_type)
+            .finish()
+    }
+}
+
+impl GeoIp {
+    /// Reads the database at `path` into memory.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let reader = Reader::open_readfile(path).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {e}", path.display()),
+            )
+        })?;
+        Ok(GeoIp {
+            reader: Arc::new(reader),
+        })
+    }
+
+    /// The fields this database has, going by its type, e.g.
+    /// `GeoLite2-City` or `GeoLite2-ASN`.
+    pub fn fields(&self) -> Vec<Field> {
+        let database_type = self.reader.metadata.database_type.as_str();
+        if database_type.contains("ASN") {
+            vec![Field::Asn, Field::AsnOrganization]
+        } else if database_type.contains("City") {
+            vec![Field::Country, Field::Region]
+        } else if database_type.contains("Country") {
+            vec![Field::Country]
+        } else {
+            vec![]
+        }
+    }
+
+    /// `field` for `ip`, `None` if the database doesn't know the address
+    /// or doesn't have the field.
+    pub fn lookup(&self, ip: IpAddr, field: Field) -> Option<Value> {
+        match field {
+            Field::Country => {
+                let country = match self.reader.lookup::<geoip2::City>(ip) {
+                    Ok(city) => city.country?.iso_code?,
+                    Err(_) => {
+                        self.reader
+                            .lookup::<geoip2::Country>(ip)
+                            .ok()?
+                            .country?
+                            .iso_code?
+                    }
+                };
+                Some(json!(country))
+            }
+            Field::Region => {
+                let city = self.reader.lookup::<geoip2::City>(ip).ok()?;
+                Some(json!(city.subdivisions?.first()?.iso_code?))
+            }
+            Field::Asn => {
+                let asn = self.reader.lookup::<geoip2::Asn>(ip).ok()?;
+                Some(json!(asn.autonomous_system_number?))
+            }
+            Field::AsnOrganization => {
+                let asn = self.reader.lookup::<geoip2::Asn>(ip).ok()?;
+                Some(json!(asn.autonomous_system_organization?))
+            }
+        }
+    }
+
+    /// A key transform looking up `field` for IP address strings, with or
+    /// without a port. Absent values stay absent; anything else the
+    /// database has no answer for becomes null.
+    pub fn transform(&self, field: Field) -> impl Fn(Option<Value>) -> Option<Value> + Send + Sync {
+        let geoip = self.clone();
+        move |value| {
+            let value = value?;
+            Some(
+                ip_of(&value)
+                    .and_then(|ip| geoip.lookup(ip, field))
+                    .unwrap_or(Value::Null),
+            )
+        }
+    }
+}
+
+/// The address in `value`, e.g. `"203.0.113.7"`, `"203.0.113.7:443"` or
+/// `"[2001:db8::1]:443"`.
+fn ip_of(value: &Value) -> Option<IpAddr> {
+    let text = value.as_str()?.trim();
+    text.parse::<IpAddr>()
+        .or_else(|_| text.parse::<SocketAddr>().map(|address| address.ip()))
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reads_addresses_with_and_without_ports() {
+        let ip = |text: &str| ip_of(&json!(text)).map(|ip| ip.to_string());
+        assert_eq!(ip(" 203.0.113.7"), Some("203.0.113.7".into()));
+        assert_eq!(ip("203.0.113.7:443"), Some("203.0.113.7".into()));
+        assert_eq!(ip("[2001:db8::1]:443"), Some("2001:db8::1".into()));
+        assert_eq!(ip("example.com"), None);
+        assert_eq!(ip_of(&json!(7)), None);
+
+        let missing = Path::new("/nonexistent/GeoLite2-City.mmdb");
+        let error = GeoIp::open(missing).unwrap_err();
+        assert!(error.to_string().contains("GeoLite2-City.mmdb"), "{error}");
+    }
+}
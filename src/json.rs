@@ -0,0 +1,250 @@
+//! Conversion of extracted records into JSON objects for JSON sinks.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+
+use serde_json::{Map, Number, Value};
+
+use crate::format::Format;
+use crate::sanitize::Sanitize;
+use crate::Record;
+
+/// How numbers are written, so a column keeps one type across files even
+/// when some values happen to be whole numbers.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum NumberFormat {
+    /// Write numbers exactly as they were extracted.
+    #[default]
+    Preserve,
+    /// Write every number as a float, e.g. `1` becomes `1.0`.
+    Float,
+    /// Write every number as a string, e.g. `1` becomes `"1"`.
+    String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct JsonOptions {
+    /// Columns every object must contain; ones the record lacks are written
+    /// as `null`. Usually `SubSchema::columns()`.
+    pub columns: Vec<String>,
+    /// Write columns whose value is missing as `null` instead of leaving
+    /// them out.
+    pub explicit_nulls: bool,
+    pub numbers: NumberFormat,
+    /// Per-column presentation, usually `SubSchema::formats()`; applied before
+    /// `numbers`.
+    pub formats: HashMap<String, Format>,
+    pub sanitize: Sanitize,
+}
+
+/// Builds a flat JSON object (column name to value) from `record`. Keys are
+/// sorted, or in `columns` then record order with the `preserve_order`
+/// feature.
+pub fn to_object(record: &Record, options: &JsonOptions) -> Value {
+    let mut object = Map::new();
+
+    for column in options.columns.iter() {
+        object.insert(column.clone(), Value::Null);
+    }
+
+    for (name, value) in record.iter() {
+        match value {
+            Some(value) => {
+                let value = match options.formats.get(name) {
+                    Some(format) => format.apply(value),
+                    None => value.clone(),
+                };
+                let value = options.sanitize.value(&value);
+                object.insert(name.clone(), format_number(&value, options.numbers));
+            }
+            None if options.explicit_nulls => {
+                object.insert(name.clone(), Value::Null);
+            }
+            None => {}
+        }
+    }
+
+    Value::Object(object)
+}
+
+/// Writes records as JSON Lines, one flat object per line (see
+/// `to_object`). Missing keys are left out unless `options` lists them in
+/// `columns`; with `explicit_nulls` as well, every value the record
+/// lacks is written as `null`.
+pub struct JsonLinesWriter<W: Write> {
+    out: W,
+    options: JsonOptions,
+}
+
+impl<W: Write> JsonLinesWriter<W> {
+    pub fn new(out: W, options: JsonOptions) -> Self {
+        JsonLinesWriter { out, options }
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        writeln!(self.out, "{}", to_object(record, &self.options))
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+/// Rebuilds nested JSON from prefixed column names, e.g. `phone_number`
+/// becomes `{"phone": {"number": ...}}`.
+pub trait Nest {
+    /// Splits every column name on `separator`. Missing values are left out.
+    /// Where a prefix is itself a column with a value (`phone` next to
+    /// `phone_number`), the longer column is kept flat at that level instead
+    /// of overwriting it or being overwritten, whichever comes first.
+    fn nest(&self, separator: &str) -> Value;
+}
+
+impl Nest for Record {
+    fn nest(&self, separator: &str) -> Value {
+        let present: HashSet<&str> = self
+            .iter()
+            .filter(|(_, value)| value.is_some())
+            .map(|(name, _)| name.as_str())
+            .collect();
+        let mut root = Map::new();
+
+        for (name, value) in self.iter() {
+            let value = match value {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let parts: Vec<&str> = name.split(separator).collect();
+
+            // Nest as deep as possible without a prefix that is a column.
+            let depth = (1..parts.len())
+                .take_while(|&end| !present.contains(parts[..end].join(separator).as_str()))
+                .count();
+
+            let mut object = &mut root;
+            for part in parts[..depth].iter() {
+                object = object
+                    .entry(part.to_string())
+                    .or_insert_with(|| Value::Object(Map::new()))
+                    .as_object_mut()
+                    .unwrap();
+            }
+            object.insert(parts[depth..].join(separator), value.clone());
+        }
+
+        Value::Object(root)
+    }
+}
+
+fn format_number(value: &Value, format: NumberFormat) -> Value {
+    match (value, format) {
+        (Value::Number(n), NumberFormat::Float) => n
+            .as_f64()
+            .and_then(Number::from_f64)
+            .map_or(Value::Null, Value::Number),
+        (Value::Number(n), NumberFormat::String) => Value::String(n.to_string()),
+        _ => value.clone(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn explicit_nulls_and_columns() {
+        let record: Record = vec![("id".into(), Some(json!(1))), ("name".into(), None)];
+
+        assert_eq!(
+            to_object(&record, &JsonOptions::default()),
+            json!({"id": 1})
+        );
+
+        let options = JsonOptions {
+            columns: vec!["id".into(), "name".into(), "phone_number".into()],
+            explicit_nulls: true,
+            ..JsonOptions::default()
+        };
+        assert_eq!(
+            to_object(&record, &options),
+            json!({"id": 1, "name": null, "phone_number": null})
+        );
+    }
+
+    #[test]
+    fn writes_one_object_per_line() {
+        let records: Vec<Record> = vec![
+            vec![("id".into(), Some(json!(1))), ("name".into(), None)],
+            vec![("id".into(), Some(json!(2)))],
+        ];
+        let lines = |options| {
+            let mut writer = JsonLinesWriter::new(vec![], options);
+            for record in records.iter() {
+                writer.write(record).unwrap();
+            }
+            String::from_utf8(writer.into_inner()).unwrap()
+        };
+
+        assert_eq!(lines(JsonOptions::default()), "{\"id\":1}\n{\"id\":2}\n");
+        let options = JsonOptions {
+            columns: vec!["id".into(), "name".into()],
+            explicit_nulls: true,
+            ..JsonOptions::default()
+        };
+        assert_eq!(
+            lines(options),
+            "{\"id\":1,\"name\":null}\n{\"id\":2,\"name\":null}\n"
+        );
+    }
+
+    #[test]
+    fn number_formats() {
+        let record: Record = vec![("n".into(), Some(json!(2)))];
+        let options = |numbers| JsonOptions {
+            numbers,
+            ..JsonOptions::default()
+        };
+
+        assert_eq!(
+            to_object(&record, &options(NumberFormat::Float)),
+            json!({"n": 2.0})
+        );
+        assert_eq!(
+            to_object(&record, &options(NumberFormat::String)),
+            json!({"n": "2"})
+        );
+    }
+
+    #[test]
+    fn nest_by_prefix() {
+        let record: Record = vec![
+            ("id".into(), Some(json!(1))),
+            ("phone_type".into(), Some(json!("cell"))),
+            ("phone_number".into(), Some(json!("661 867 5309"))),
+            ("email".into(), None),
+            ("tag".into(), Some(json!("a"))),
+            ("tag_extra".into(), Some(json!("b"))),
+        ];
+
+        assert_eq!(
+            record.nest("_"),
+            json!({
+                "id": 1,
+                "phone": {"type": "cell", "number": "661 867 5309"},
+                "tag": "a",
+                "tag_extra": "b"
+            })
+        );
+        let reversed: Record = vec![
+            ("phone_number".into(), Some(json!("x"))),
+            ("phone".into(), Some(json!("y"))),
+            ("phone_home_number".into(), Some(json!("z"))),
+        ];
+        assert_eq!(
+            reversed.nest("_"),
+            json!({"phone_number": "x", "phone": "y", "phone_home_number": "z"})
+        );
+    }
+}
@@ -0,0 +1,2598 @@
+//! Flattens hierarchical JSON documents into flat records.
+//!
+//! A [`SubSchema`] is built with the [`doc!`], [`sub!`] and [`key!`] macros
+//! and mirrors the shape of the documents: a `Sub` descends into an object
+//! or explodes an array into one record per element, and a `Key` becomes a
+//! column. [`SubSchema::extract`] turns one document into records, sibling
+//! `Sub`s combining as a cross product:
+//!
+//! ```
+//! use serde_json::json;
+//! use serde_test::{doc, key, sub};
+//!
+//! let schema = doc! {
+//!     key!("id"),
+//!     sub!("phone", {key!("number")})
+//! };
+//! let records = schema
+//!     .extract(&json!({"id": 1, "phone": {"number": "555"}}))
+//!     .unwrap();
+//! assert_eq!(records[0][1], ("phone_number".to_string(), Some(json!("555"))));
+//! ```
+//!
+//! The other modules hold what runs around extraction: sinks (`csv`,
+//! `json`), per-record stages, and run-level checks and statistics.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+use serde_json::{json, Map, Value};
+
+use filter::Filter;
+use format::Format;
+#[cfg(feature = "jsonpath")]
+pub use serde_json_path::JsonPath;
+
+#[cfg(feature = "arrow")]
+pub mod arrow;
+pub mod assertion;
+#[cfg(feature = "checksum")]
+pub mod checksum;
+#[cfg(feature = "config")]
+pub mod config;
+pub mod csv;
+pub mod dsl;
+pub mod encoding;
+#[cfg(feature = "faker")]
+pub mod faker;
+pub mod filter;
+pub mod format;
+#[cfg(feature = "geoip")]
+pub mod geoip;
+pub mod json;
+pub mod manifest;
+pub mod naming;
+pub mod ndjson;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+pub mod sanitize;
+pub mod sketch;
+pub mod snowflake;
+pub mod stage;
+pub mod strict;
+pub mod time;
+pub mod transforms;
+pub mod warehouse;
+
+pub type Name = String;
+/// A column name and its value, `None` when the key was absent and
+/// `Some(Value::Null)` when it was `null`.
+pub type Pair = (Name, Option<Value>);
+/// One flat output row, columns in schema order.
+pub type Record = Vec<Pair>;
+/// Rewrites a single key's value. Closures may capture configuration, e.g.
+/// a date pattern or a lookup table.
+pub type Transform = Box<dyn Fn(Option<Value>) -> Option<Value> + Send + Sync>;
+/// Rewrites a single key's value, or rejects it; see `KeySchema::try_transform`.
+pub type TryTransform =
+    Box<dyn Fn(Option<Value>) -> Result<Option<Value>, TransformError> + Send + Sync>;
+/// Rewrites, splits or drops the records a `Sub` produces.
+pub type RecordTransform = Box<dyn Fn(Record) -> Vec<Record> + Send + Sync>;
+
+/// Counters collected for a single schema node, keyed in [`Metrics`] by the
+/// node's prefixed source path.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct NodeMetrics {
+    /// Times the node found a value in the document.
+    pub matched: usize,
+    /// Times the node was looked up but absent.
+    pub missing: usize,
+    /// Records produced by the node (always zero for keys).
+    pub records: usize,
+    /// Times the key's transform rejected its value (always zero for
+    /// `Sub`s).
+    pub failed: usize,
+}
+
+/// Per-node counters from `SubSchema::extract_with_metrics`, for spotting
+/// schema paths that never match the data.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Metrics(BTreeMap<String, NodeMetrics>);
+
+impl Metrics {
+    pub fn get(&self, path: &str) -> Option<&NodeMetrics> {
+        self.0.get(path)
+    }
+
+    /// Paths of nodes that were visited but never matched anything.
+    pub fn dead(&self) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|(_, m)| m.matched == 0)
+            .map(|(path, _)| path.as_str())
+            .collect()
+    }
+
+    /// Per-node counters for a run report, keyed by path.
+    pub fn report(&self) -> Value {
+        let nodes = self.0.iter().map(|(path, m)| {
+            let counters = json!({
+                "matched": m.matched,
+                "missing": m.missing,
+                "records": m.records,
+                "failed": m.failed,
+            });
+            (path.clone(), counters)
+        });
+        Value::Object(nodes.collect())
+    }
+
+    fn node(&mut self, path: &str) -> &mut NodeMetrics {
+        self.0.entry(path.to_string()).or_default()
+    }
+}
+
+/// Per-`Sub` settings that don't warrant their own macro argument.
+#[derive(Default)]
+pub struct SubOptions {
+    /// Applied to every record the `Sub` produces before it is merged into
+    /// its parent; may rewrite, split or drop (by returning nothing) it.
+    pub transform: Option<RecordTransform>,
+    /// Array element to extract as if it were the sub-document, for arrays
+    /// that are positional tuples rather than repeated records; ignored for
+    /// objects.
+    pub index: Option<usize>,
+    /// Array elements to skip before extracting; ignored for objects.
+    pub offset: usize,
+    /// Maximum array elements to extract after `offset`; ignored for objects.
+    pub limit: Option<usize>,
+    /// Array elements failing this are skipped before `offset` and `limit`
+    /// apply; ignored for objects.
+    pub filter: Option<Filter>,
+    /// Joins the prefixes of the columns below this `Sub`, `_` unless set
+    /// here or on an enclosing `Sub`.
+    pub separator: Option<String>,
+    /// Whether keys no child reads are extracted too, and how their
+    /// columns are named.
+    pub rest: Option<Rest>,
+    /// How the records of the children are combined.
+    pub merge: Merge,
+}
+
+/// How a `Sub` combines the records of its children, e.g. two arrays at the
+/// same level.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Merge {
+    /// Every combination of the children's records.
+    #[default]
+    Cartesian,
+    /// The children's records pairwise, up to the longest child's. Children
+    /// with one record, such as the `Sub`'s own keys, join every pair;
+    /// shorter ones leave their columns out past their end.
+    Zip,
+    /// Only the first record of each child, like `Zip` cut to one record.
+    First,
+}
+
+impl FromStr for Merge {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "cartesian" => Ok(Self::Cartesian),
+            "zip" => Ok(Self::Zip),
+            "first" => Ok(Self::First),
+            _ => Err(format!("unknown merge {s:?}")),
+        }
+    }
+}
+
+/// How a `Sub` extracting the rest of its keys (see `SubSchema::rest`)
+/// names their columns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rest {
+    /// Like a `key!` of the `Sub`.
+    Prefixed,
+    /// By the bare key, as under `SubSchema::unprefixed`.
+    Bare,
+}
+
+impl fmt::Debug for SubOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SubOptions")
+            .field("transform", &self.transform.is_some())
+            .field("index", &self.index)
+            .field("offset", &self.offset)
+            .field("limit", &self.limit)
+            .field("filter", &self.filter)
+            .field("separator", &self.separator)
+            .field("rest", &self.rest)
+            .field("merge", &self.merge)
+            .finish()
+    }
+}
+
+/// Per-`Key` settings that don't warrant their own macro argument.
+#[derive(Debug, Default)]
+pub struct KeyOptions<'a> {
+    /// Dot-separated path from the document root, read when the key is
+    /// absent from its own sub-document.
+    pub fallback: Option<&'a str>,
+    /// Sensitivity class of the column, e.g. `"email"` or `"name"`.
+    pub pii: Option<&'a str>,
+    /// Whether the key's transform was attached with `anonymize`, which
+    /// clears it for `pii` enforcement.
+    pub anonymized: bool,
+    /// How sinks should present the column's values.
+    pub format: Option<Format>,
+    /// Whether the column is part of the record's natural key, used for
+    /// upserts and deduplication.
+    pub natural_key: bool,
+    /// Whether an array value is exploded into one record per element.
+    pub explode: bool,
+    /// Expected bounds on the column's values, enforced by `stage::Guard`.
+    pub limits: Limits,
+    /// Whether `SubSchema::extract_strict` fails when the key is absent.
+    pub required: bool,
+    /// Whether the key is a glob matched against every key of its
+    /// sub-document; see `key_pattern!`.
+    pub pattern: bool,
+    /// Selects the value from the key's sub-document instead of the key.
+    #[cfg(feature = "jsonpath")]
+    pub jsonpath: Option<JsonPath>,
+    /// Value used when the key is absent, after its fallback.
+    pub default: Option<Value>,
+}
+
+/// Bounds a column's values are expected to stay within, e.g. to fit a
+/// `VARCHAR(n)` warehouse column.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    /// Most characters in a string value.
+    pub max_length: Option<usize>,
+    /// Most distinct values over a run.
+    pub max_cardinality: Option<usize>,
+}
+
+/// The root or a nested sub-document of an extraction schema, usually
+/// built with the `doc!` and `sub!` macros. Only a `SubSchema` extracts.
+#[derive(Debug)]
+pub struct SubSchema<'a> {
+    /// Source key of the sub-document; empty for the root.
+    pub name: &'a str,
+    pub children: Vec<Node<'a>>,
+    pub options: SubOptions,
+}
+
+/// A source key that becomes a column, usually built with the `key!` macro.
+pub struct KeySchema<'a> {
+    pub key: &'a str,
+    /// Output column name; by default the key prefixed with its `Sub`s.
+    pub name: Option<&'a str>,
+    /// Plain `Transform`s are stored wrapped with `fallible`.
+    pub transform: Option<TryTransform>,
+    pub options: KeyOptions<'a>,
+}
+
+impl<'a> fmt::Debug for KeySchema<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeySchema")
+            .field("key", &self.key)
+            .field("name", &self.name)
+            .field("transform", &self.transform.is_some())
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+/// A child of a `SubSchema`.
+#[derive(Debug)]
+pub enum Node<'a> {
+    Sub(SubSchema<'a>),
+    Key(KeySchema<'a>),
+}
+
+impl<'a> From<SubSchema<'a>> for Node<'a> {
+    fn from(sub: SubSchema<'a>) -> Self {
+        Node::Sub(sub)
+    }
+}
+
+impl<'a> From<KeySchema<'a>> for Node<'a> {
+    fn from(key: KeySchema<'a>) -> Self {
+        Node::Key(key)
+    }
+}
+
+/// Why `SubSchema::extract` failed. Paths are prefixed source paths, as in
+/// `Metrics`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExtractError {
+    /// A `Sub`'s value is not an object, an array of objects or null.
+    TypeMismatch { path: String, found: &'static str },
+    /// A key's `TryTransform` failed. `element` is the position of the
+    /// innermost array element being exploded, if any.
+    Transform {
+        path: String,
+        element: Option<usize>,
+        error: TransformError,
+    },
+    /// A `required` key is absent (after its fallback) in strict mode.
+    /// `element` is as for `Transform`.
+    Missing {
+        path: String,
+        element: Option<usize>,
+    },
+}
+
+impl fmt::Display for ExtractError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::TypeMismatch { path, found } => {
+                write!(f, "expected an object at {path:?}, found {found}")
+            }
+            Self::Transform {
+                path,
+                element: Some(element),
+                error,
+            } => write!(
+                f,
+                "transform of {path:?} failed at element {element}: {error}"
+            ),
+            Self::Transform {
+                path,
+                element: None,
+                error,
+            } => write!(f, "transform of {path:?} failed: {error}"),
+            Self::Missing {
+                path,
+                element: Some(element),
+            } => write!(f, "required key {path:?} missing at element {element}"),
+            Self::Missing {
+                path,
+                element: None,
+            } => write!(f, "required key {path:?} missing"),
+        }
+    }
+}
+
+impl std::error::Error for ExtractError {}
+
+/// Why a `TryTransform` rejected a value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransformError {
+    pub message: String,
+}
+
+impl TransformError {
+    pub fn new(message: impl Into<String>) -> Self {
+        TransformError {
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for TransformError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for TransformError {}
+
+/// Wraps a `Transform` as a `TryTransform` that never fails.
+pub fn fallible<F>(func: F) -> TryTransform
+where
+    F: Fn(Option<Value>) -> Option<Value> + Send + Sync + 'static,
+{
+    Box::new(move |value| Ok(func(value)))
+}
+
+/// State shared by every node during a single extraction.
+struct Context<'v, 'm> {
+    /// The document being extracted, for lookups that escape the current
+    /// sub-document.
+    root: &'v Value,
+    metrics: Option<&'m mut Metrics>,
+    memo: Option<&'m mut Memo>,
+    element: Option<usize>,
+    /// Whether absent `required` keys are errors.
+    strict: bool,
+}
+
+impl<'v, 'm> Context<'v, 'm> {
+    fn metrics(&mut self) -> Option<&mut Metrics> {
+        self.metrics.as_deref_mut()
+    }
+}
+
+/// Records already extracted from sub-documents, keyed by schema node and
+/// the sub-document's serialized content, so identical subtrees repeated
+/// across a denormalized feed are only walked once.
+///
+/// Entries refer to nodes by address, so a `Memo` must only be used with
+/// the schema it was first used with.
+#[derive(Debug)]
+pub struct Memo {
+    /// Entries kept before the memo is cleared and starts over.
+    capacity: usize,
+    entries: HashMap<(usize, String), Vec<Record>>,
+    hits: usize,
+}
+
+impl Memo {
+    pub fn new(capacity: usize) -> Self {
+        Memo {
+            capacity,
+            entries: HashMap::new(),
+            hits: 0,
+        }
+    }
+
+    /// Sub-documents whose records were reused so far.
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+}
+
+impl<'a> SubSchema<'a> {
+    /// Prints every output column's source path, one per line.
+    pub fn names(&self) {
+        self._names("", SEPARATOR);
+    }
+
+    fn _names(&self, prefix: &str, separator: &str) {
+        let prefix = SubSchema::prefix(prefix, self.name, separator);
+        let separator = self.joined_by(separator);
+        for node in self.children.iter() {
+            match node {
+                Node::Sub(sub) => sub._names(&prefix, separator),
+                Node::Key(key) => println!("{}", SubSchema::prefix(&prefix, key.key, separator)),
+            }
+        }
+    }
+
+    /// Flattens one document into records.
+    pub fn extract(&self, record: &Value) -> Result<Vec<Record>, ExtractError> {
+        let mut ctx = Context {
+            root: record,
+            metrics: None,
+            memo: None,
+            element: None,
+            strict: false,
+        };
+        self._extract_sub(Some(record), "", SEPARATOR, &mut ctx)
+    }
+
+    /// Like `extract`, but fails with `ExtractError::Missing` when a
+    /// `required` key is absent from a sub-document that is present.
+    pub fn extract_strict(&self, record: &Value) -> Result<Vec<Record>, ExtractError> {
+        let mut ctx = Context {
+            root: record,
+            metrics: None,
+            memo: None,
+            element: None,
+            strict: true,
+        };
+        self._extract_sub(Some(record), "", SEPARATOR, &mut ctx)
+    }
+
+    /// Flattens a root array of documents, concatenating each element's
+    /// records in order; any other value is extracted as one document.
+    /// Each element is its own root for `fallback` keys. Stops at the first
+    /// element that fails.
+    pub fn extract_many(&self, documents: &Value) -> Result<Vec<Record>, ExtractError> {
+        match documents {
+            Value::Array(documents) => {
+                let mut records = vec![];
+                for document in documents.iter() {
+                    records.extend(self.extract(document)?);
+                }
+                Ok(records)
+            }
+            document => self.extract(document),
+        }
+    }
+
+    /// Like `extract`, but also accumulates per-node counters into `metrics`,
+    /// so the same `Metrics` can be reused across many documents.
+    pub fn extract_with_metrics(
+        &self,
+        record: &Value,
+        metrics: &mut Metrics,
+    ) -> Result<Vec<Record>, ExtractError> {
+        let mut ctx = Context {
+            root: record,
+            metrics: Some(metrics),
+            memo: None,
+            element: None,
+            strict: false,
+        };
+        self._extract_sub(Some(record), "", SEPARATOR, &mut ctx)
+    }
+
+    /// `extract_strict` with the counters of `extract_with_metrics`.
+    pub fn extract_strict_with_metrics(
+        &self,
+        record: &Value,
+        metrics: &mut Metrics,
+    ) -> Result<Vec<Record>, ExtractError> {
+        let mut ctx = Context {
+            root: record,
+            metrics: Some(metrics),
+            memo: None,
+            element: None,
+            strict: true,
+        };
+        self._extract_sub(Some(record), "", SEPARATOR, &mut ctx)
+    }
+
+    /// Like `extract`, but reuses the records of sub-documents identical to
+    /// ones seen before, from this or earlier documents. Worth it for highly
+    /// redundant inputs; serializing each sub-document to look it up costs
+    /// more than it saves otherwise.
+    pub fn extract_memoized(
+        &self,
+        record: &Value,
+        memo: &mut Memo,
+    ) -> Result<Vec<Record>, ExtractError> {
+        let mut ctx = Context {
+            root: record,
+            metrics: None,
+            memo: Some(memo),
+            element: None,
+            strict: false,
+        };
+        self._extract_sub(Some(record), "", SEPARATOR, &mut ctx)
+    }
+
+    /// `_extract_sub` on a present sub-document, through the memo if there
+    /// is one. Subtrees with fallbacks read outside their sub-document and
+    /// are never memoized.
+    fn _extract_present(
+        &self,
+        record: &Value,
+        prefix: &str,
+        separator: &str,
+        ctx: &mut Context,
+    ) -> Result<Vec<Record>, ExtractError> {
+        if ctx.memo.is_none() || self.reads_root() {
+            return self._extract_sub(Some(record), prefix, separator, ctx);
+        }
+
+        let key = (self as *const SubSchema as usize, record.to_string());
+        if let Some(memo) = ctx.memo.as_deref_mut() {
+            if let Some(records) = memo.entries.get(&key) {
+                memo.hits += 1;
+                return Ok(records.clone());
+            }
+        }
+
+        let records = self._extract_sub(Some(record), prefix, separator, ctx)?;
+        if let Some(memo) = ctx.memo.as_deref_mut() {
+            if memo.entries.len() >= memo.capacity {
+                memo.entries.clear();
+            }
+            memo.entries.insert(key, records.clone());
+        }
+        Ok(records)
+    }
+
+    fn reads_root(&self) -> bool {
+        self.children.iter().any(|node| match node {
+            Node::Sub(sub) => sub.reads_root(),
+            Node::Key(key) => key.options.fallback.is_some(),
+        })
+    }
+
+    fn _extract_sub(
+        &self,
+        record: Option<&Value>,
+        prefix: &str,
+        separator: &str,
+        ctx: &mut Context,
+    ) -> Result<Vec<Record>, ExtractError> {
+        let prefix = SubSchema::prefix(prefix, self.name, separator);
+        let separator = self.joined_by(separator);
+
+        if let Some(found) = record.filter(|v| !v.is_object() && !v.is_null()) {
+            return Err(ExtractError::TypeMismatch {
+                path: prefix,
+                found: type_name(found),
+            });
+        }
+
+        let mut results = vec![];
+        let mut fields = vec![];
+        let mut subdocs = vec![];
+
+        if let Some(record) = record {
+            for item in self.children.iter() {
+                match item {
+                    Node::Sub(k) => match record {
+                        Value::Object(m) => match (m.get(k.name), k.options.index) {
+                            (Some(o @ Value::Object(_)), _) => {
+                                subdocs.push(k._extract_present(o, &prefix, separator, ctx)?)
+                            }
+                            (Some(Value::Array(arr)), Some(i)) => {
+                                match arr.get(i).filter(|v| !v.is_null()) {
+                                    Some(v) => {
+                                        let outer = ctx.element.replace(i);
+                                        subdocs
+                                            .push(k._extract_present(v, &prefix, separator, ctx)?);
+                                        ctx.element = outer;
+                                    }
+                                    None => {
+                                        if let Some(metrics) = ctx.metrics() {
+                                            metrics
+                                                .node(&SubSchema::prefix(
+                                                    &prefix, k.name, separator,
+                                                ))
+                                                .missing += 1;
+                                        }
+                                    }
+                                }
+                            }
+                            (Some(Value::Array(arr)), None) => {
+                                let elements = arr
+                                    .iter()
+                                    .enumerate()
+                                    .filter(|(_, v)| match &k.options.filter {
+                                        Some(filter) => filter.matches(v),
+                                        None => true,
+                                    })
+                                    .skip(k.options.offset)
+                                    .take(k.options.limit.unwrap_or(usize::MAX));
+                                let mut sub = vec![];
+                                let outer = ctx.element;
+                                for (i, v) in elements {
+                                    ctx.element = Some(i);
+                                    sub.extend(k._extract_present(v, &prefix, separator, ctx)?);
+                                }
+                                ctx.element = outer;
+                                subdocs.push(sub);
+                            }
+                            (Some(found), _) if !found.is_null() => {
+                                return Err(ExtractError::TypeMismatch {
+                                    path: SubSchema::prefix(&prefix, k.name, separator),
+                                    found: type_name(found),
+                                });
+                            }
+                            _ => {
+                                if let Some(metrics) = ctx.metrics() {
+                                    metrics
+                                        .node(&SubSchema::prefix(&prefix, k.name, separator))
+                                        .missing += 1;
+                                }
+                            }
+                        },
+                        _ => subdocs.push(k._extract_sub(None, &prefix, separator, ctx)?),
+                    },
+                    Node::Key(k) if k.options.pattern => {
+                        fields.extend(k._extract_matches(record, &prefix, separator, ctx)?);
+                    }
+                    Node::Key(k) if k.options.explode => {
+                        subdocs.push(k._extract_values(Some(record), &prefix, separator, ctx)?);
+                    }
+                    Node::Key(k) => {
+                        fields.push(k._extract_key(Some(record), &prefix, separator, ctx)?);
+                    }
+                }
+            }
+            if let (Some(rest), Value::Object(m)) = (self.options.rest, record) {
+                fields.extend(self._rest(m, rest, &prefix, separator));
+            }
+        }
+
+        if !fields.is_empty() {
+            subdocs.push(vec![fields]);
+        }
+
+        match &self.options.transform {
+            Some(func) => results.extend(
+                merge_with(subdocs, self.options.merge)
+                    .into_iter()
+                    .flat_map(func),
+            ),
+            None => results.append(&mut merge_with(subdocs, self.options.merge)),
+        }
+
+        if let Some(metrics) = ctx.metrics() {
+            let node = metrics.node(&prefix);
+            match record {
+                Some(_) => node.matched += 1,
+                None => node.missing += 1,
+            }
+            node.records += results.len();
+        }
+
+        Ok(results)
+    }
+
+    /// Extracts only the array element at `index` of this `Sub`, like an
+    /// object sub-document; out of range, the `Sub` counts as absent.
+    pub fn at(mut self, index: usize) -> Self {
+        self.options.index = Some(index);
+        self
+    }
+
+    /// Combines the records of this `Sub`'s children by `merge` instead of
+    /// taking every combination of them.
+    pub fn merge(mut self, merge: Merge) -> Self {
+        self.options.merge = merge;
+        self
+    }
+
+    /// Caps how many array elements this `Sub` explodes into records.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.options.limit = Some(limit);
+        self
+    }
+
+    /// Skips the first `offset` array elements before exploding this `Sub`.
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.options.offset = offset;
+        self
+    }
+
+    /// Only explodes array elements of this `Sub` that match `filter`.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.options.filter = Some(filter);
+        self
+    }
+
+    /// Also extracts every key of the sub-document that no child reads, as
+    /// a column named like a `key!` of it, e.g. to pass `metadata` through
+    /// next to a few known fields. Values are taken as they are, nested ones
+    /// included, after the children's columns in document key order.
+    ///
+    /// Their columns depend on the document, so they are missing from
+    /// `columns` and sinks that write a fixed set of columns (CSV) drop
+    /// them; JSON sinks without `columns` keep them.
+    pub fn rest(mut self) -> Self {
+        self.options.rest = Some(Rest::Prefixed);
+        self
+    }
+
+    fn _rest(&self, m: &Map<String, Value>, rest: Rest, prefix: &str, separator: &str) -> Record {
+        m.iter()
+            .filter(|(key, _)| {
+                !self.children.iter().any(|node| match node {
+                    Node::Sub(sub) => sub.name == key.as_str(),
+                    Node::Key(k) if k.options.pattern => glob(k.key, key),
+                    Node::Key(k) => k.key == key.as_str(),
+                })
+            })
+            .map(|(key, value)| {
+                let column = match rest {
+                    Rest::Prefixed => SubSchema::prefix(prefix, key, separator),
+                    Rest::Bare => key.clone(),
+                };
+                (column, Some(value.clone()))
+            })
+            .collect()
+    }
+
+    /// Joins column prefixes below this `Sub` with `separator` instead of
+    /// `_`, e.g. `.` or `::`, so prefixed names split back into paths
+    /// unambiguously when source keys contain underscores. Set on the root
+    /// to apply to the whole schema; nested `Sub`s inherit it.
+    pub fn separator(mut self, separator: &str) -> Self {
+        self.options.separator = Some(separator.to_string());
+        self
+    }
+
+    /// Names the columns of keys below this `Sub` by their bare source keys
+    /// instead of prefixing them with the `Sub` chain; keys with a rename
+    /// keep it. Call on the root to drop prefixes everywhere. Bare names
+    /// collide more easily, so check the result with `check_columns`.
+    pub fn unprefixed(mut self) -> Self {
+        self._unprefix();
+        self
+    }
+
+    fn _unprefix(&mut self) {
+        if self.options.rest.is_some() {
+            self.options.rest = Some(Rest::Bare);
+        }
+        for node in self.children.iter_mut() {
+            match node {
+                Node::Sub(sub) => sub._unprefix(),
+                Node::Key(key) if key.options.pattern => {
+                    key.name.get_or_insert("{}");
+                }
+                Node::Key(key) => {
+                    let bare = match key.key.starts_with('/') {
+                        true => key.key.rsplit('/').next(),
+                        false => steps(key.key).last(),
+                    };
+                    key.name.get_or_insert(bare.unwrap_or(key.key));
+                }
+            }
+        }
+    }
+
+    /// Fails with the output columns more than one key writes to, whose
+    /// values would overwrite each other in sinks.
+    pub fn check_columns(&self) -> Result<(), Vec<String>> {
+        let columns = self.columns();
+        let mut collisions: Vec<String> = vec![];
+        for (i, column) in columns.iter().enumerate() {
+            if columns[..i].contains(column) && !collisions.contains(column) {
+                collisions.push(column.clone());
+            }
+        }
+
+        if collisions.is_empty() {
+            Ok(())
+        } else {
+            Err(collisions)
+        }
+    }
+
+    /// Output columns tagged as PII, with their sensitivity class.
+    pub fn pii_columns(&self) -> Vec<(String, &'a str)> {
+        self.keys()
+            .into_iter()
+            .filter_map(|(column, options)| options.pii.map(|class| (column, class)))
+            .collect()
+    }
+
+    /// Enforcement for sinks not approved for raw PII: fails with the
+    /// columns that are tagged as PII but have no anonymizing transform.
+    pub fn check_pii(&self) -> Result<(), Vec<String>> {
+        let violations: Vec<String> = self
+            .keys()
+            .into_iter()
+            .filter(|(_, options)| options.pii.is_some() && !options.anonymized)
+            .map(|(column, _)| column)
+            .collect();
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// Output columns forming the natural key, in schema order.
+    pub fn natural_key_columns(&self) -> Vec<String> {
+        self.keys()
+            .into_iter()
+            .filter(|(_, options)| options.natural_key)
+            .map(|(column, _)| column)
+            .collect()
+    }
+
+    /// Output columns with a declared `Format`, for handing to sinks.
+    pub fn formats(&self) -> HashMap<String, Format> {
+        self.keys()
+            .into_iter()
+            .filter_map(|(column, options)| options.format.clone().map(|format| (column, format)))
+            .collect()
+    }
+
+    /// Output columns with declared `Limits`, for `stage::Guard`.
+    pub fn limits(&self) -> HashMap<String, Limits> {
+        self.keys()
+            .into_iter()
+            .filter(|(_, options)| options.limits != Limits::default())
+            .map(|(column, options)| (column, options.limits))
+            .collect()
+    }
+
+    /// Every output column name, in schema order.
+    pub fn columns(&self) -> Vec<String> {
+        self.keys().into_iter().map(|(column, _)| column).collect()
+    }
+
+    /// Every key's output column name and options, in schema order. The
+    /// columns of pattern keys depend on the document and are left out.
+    pub fn keys(&self) -> Vec<(String, &KeyOptions<'a>)> {
+        let mut keys = vec![];
+        self._keys("", SEPARATOR, &mut keys);
+        keys
+    }
+
+    fn _keys<'s>(
+        &'s self,
+        prefix: &str,
+        separator: &str,
+        keys: &mut Vec<(String, &'s KeyOptions<'a>)>,
+    ) {
+        let prefix = SubSchema::prefix(prefix, self.name, separator);
+        let separator = self.joined_by(separator);
+        for node in self.children.iter() {
+            match node {
+                Node::Sub(sub) => sub._keys(&prefix, separator, keys),
+                Node::Key(key) if key.options.pattern => {}
+                Node::Key(key) => keys.push((key.column(&prefix, separator), &key.options)),
+            }
+        }
+    }
+
+    /// Column-level lineage as an OpenLineage output dataset with a
+    /// `columnLineage` facet, for registration in a data catalog.
+    ///
+    /// `input` and `output` are `(namespace, name)` pairs for the source
+    /// documents and the sink table. Each column's input field is the JSON
+    /// Pointer of its key with array levels left out, plus the root pointer
+    /// of its fallback if it has one. Key transforms are reported as
+    /// `TRANSFORMATION`, or `MASKED` when attached with `anonymize`; `Sub`
+    /// filters and record transforms as `INDIRECT` on every column below.
+    pub fn lineage(&self, input: (&str, &str), output: (&str, &str)) -> Value {
+        let mut fields = Map::new();
+        self._lineage(input, ("", SEPARATOR), "", &[], &mut fields);
+        json!({
+            "namespace": output.0,
+            "name": output.1,
+            "facets": {
+                "columnLineage": {
+                    "_producer": concat!(
+                        "https://github.com/felix-alonso/serde-test/tree/v",
+                        env!("CARGO_PKG_VERSION")
+                    ),
+                    "_schemaURL": "https://openlineage.io/spec/facets/1-2-0/ColumnLineageDatasetFacet.json#/$defs/ColumnLineageDatasetFacet",
+                    "fields": fields,
+                }
+            }
+        })
+    }
+
+    fn _lineage(
+        &self,
+        input: (&str, &str),
+        (prefix, separator): (&str, &str),
+        pointer: &str,
+        inherited: &[Value],
+        fields: &mut Map<String, Value>,
+    ) {
+        let prefix = SubSchema::prefix(prefix, self.name, separator);
+        let separator = self.joined_by(separator);
+        let pointer = match self.name {
+            "" => pointer.to_string(),
+            name => format!("{pointer}/{}", escape_pointer(name)),
+        };
+        let mut inherited = inherited.to_vec();
+        if let Some(filter) = &self.options.filter {
+            inherited.push(json!({
+                "type": "INDIRECT",
+                "subtype": "FILTER",
+                "description": format!("{pointer}[{}]", filter.source()),
+                "masking": false,
+            }));
+        }
+        if self.options.transform.is_some() {
+            inherited.push(json!({
+                "type": "INDIRECT",
+                "subtype": "TRANSFORMATION",
+                "description": format!("record transform on {pointer}"),
+                "masking": false,
+            }));
+        }
+        for node in self.children.iter() {
+            match node {
+                Node::Sub(sub) => {
+                    sub._lineage(input, (&prefix, separator), &pointer, &inherited, fields)
+                }
+                Node::Key(key) if key.options.pattern => {}
+                Node::Key(key) => {
+                    key._lineage(input, (&prefix, separator), &pointer, &inherited, fields)
+                }
+            }
+        }
+    }
+
+    /// Stable fingerprint of the schema's shape, as 16 hex digits.
+    ///
+    /// Covers every node and option that affects which records and columns
+    /// are produced. Transforms are opaque closures and only count as
+    /// present or absent, so swapping one transform for another does not
+    /// change the fingerprint.
+    pub fn fingerprint(&self) -> String {
+        let mut hash = FNV_OFFSET;
+        self._fingerprint(&mut hash);
+        format!("{hash:016x}")
+    }
+
+    fn _fingerprint(&self, hash: &mut u64) {
+        fnv(hash, b"S");
+        fnv_str(hash, self.name);
+        fnv(hash, &(self.children.len() as u64).to_le_bytes());
+        if self.options.transform.is_some() {
+            fnv(hash, b"T");
+        }
+        if let Some(index) = self.options.index {
+            fnv(hash, b"A");
+            fnv(hash, &(index as u64).to_le_bytes());
+        }
+        if self.options.offset > 0 {
+            fnv(hash, b"O");
+            fnv(hash, &(self.options.offset as u64).to_le_bytes());
+        }
+        if let Some(limit) = self.options.limit {
+            fnv(hash, b"L");
+            fnv(hash, &(limit as u64).to_le_bytes());
+        }
+        if let Some(filter) = &self.options.filter {
+            fnv(hash, b"W");
+            fnv_str(hash, filter.source());
+        }
+        if let Some(separator) = &self.options.separator {
+            fnv(hash, b"P");
+            fnv_str(hash, separator);
+        }
+        match self.options.rest {
+            Some(Rest::Prefixed) => fnv(hash, b"*"),
+            Some(Rest::Bare) => fnv(hash, b"*B"),
+            None => {}
+        }
+        match self.options.merge {
+            Merge::Cartesian => {}
+            Merge::Zip => fnv(hash, b"MZ"),
+            Merge::First => fnv(hash, b"MF"),
+        }
+        for node in self.children.iter() {
+            match node {
+                Node::Sub(sub) => sub._fingerprint(hash),
+                Node::Key(key) => key._fingerprint(hash),
+            }
+        }
+    }
+
+    /// The inverse of `extract`: rebuilds hierarchical documents from flat
+    /// records by grouping child records under their parents.
+    ///
+    /// Consecutive records with the same top-level key values form one
+    /// document, which is how `extract` emits them, so records can be fed
+    /// in as they stream. A `Sub` becomes an object when it has one distinct
+    /// value and an array otherwise; columns are written under their source
+    /// keys and transforms are not undone.
+    pub fn nest(&self, records: &[Record]) -> Vec<Value> {
+        let records: Vec<&Record> = records.iter().collect();
+        self._nest(&records, "", SEPARATOR, true)
+    }
+
+    fn _nest(
+        &self,
+        records: &[&Record],
+        prefix: &str,
+        separator: &str,
+        consecutive: bool,
+    ) -> Vec<Value> {
+        let prefix = SubSchema::prefix(prefix, self.name, separator);
+        let separator = self.joined_by(separator);
+
+        let keys: Vec<(&str, String)> = self
+            .children
+            .iter()
+            .filter_map(|node| match node {
+                Node::Key(key) if !key.options.pattern => {
+                    Some((key.key, key.column(&prefix, separator)))
+                }
+                _ => None,
+            })
+            .collect();
+
+        // Records sharing this node's own key values belong to one object.
+        let mut groups: Vec<(Vec<Option<&Value>>, Vec<&Record>)> = vec![];
+        for record in records.iter() {
+            let values: Vec<Option<&Value>> = keys
+                .iter()
+                .map(|(_, column)| {
+                    record
+                        .iter()
+                        .find(|(name, _)| name == column)
+                        .and_then(|(_, value)| value.as_ref())
+                })
+                .collect();
+            let group = if consecutive {
+                groups.last_mut().filter(|(v, _)| *v == values)
+            } else {
+                groups.iter_mut().find(|(v, _)| *v == values)
+            };
+            match group {
+                Some((_, members)) => members.push(record),
+                None => groups.push((values, vec![record])),
+            }
+        }
+
+        groups
+            .into_iter()
+            .filter_map(|(values, members)| {
+                let mut object = Map::new();
+                for ((key, _), value) in keys.iter().zip(values) {
+                    if let Some(value) = value {
+                        object.insert(key.to_string(), value.clone());
+                    }
+                }
+                for node in self.children.iter() {
+                    if let Node::Sub(sub) = node {
+                        let mut children = sub._nest(&members, &prefix, separator, false);
+                        match children.len() {
+                            0 => {}
+                            1 => {
+                                object.insert(sub.name.to_string(), children.remove(0));
+                            }
+                            _ => {
+                                object.insert(sub.name.to_string(), Value::Array(children));
+                            }
+                        }
+                    }
+                }
+                // Every column empty means the sub-document was absent.
+                (!object.is_empty()).then_some(Value::Object(object))
+            })
+            .collect()
+    }
+
+    /// Like `extract`, with a `schema_version` column holding the schema's
+    /// `fingerprint` appended to every record.
+    pub fn extract_versioned(&self, record: &Value) -> Result<Vec<Record>, ExtractError> {
+        let version = Value::String(self.fingerprint());
+        let mut results = self.extract(record)?;
+        for result in results.iter_mut() {
+            result.push(("schema_version".to_string(), Some(version.clone())));
+        }
+        Ok(results)
+    }
+
+    fn prefix(prefix: &str, name: &str, separator: &str) -> String {
+        if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{prefix}{separator}{name}")
+        }
+    }
+
+    /// This `Sub`'s separator, or the one it inherits.
+    fn joined_by<'s>(&'s self, inherited: &'s str) -> &'s str {
+        self.options.separator.as_deref().unwrap_or(inherited)
+    }
+}
+
+impl<'a> KeySchema<'a> {
+    fn _extract_key(
+        &self,
+        record: Option<&Value>,
+        prefix: &str,
+        separator: &str,
+        ctx: &mut Context,
+    ) -> Result<Pair, ExtractError> {
+        let value = self._read(record, prefix, separator, ctx)?;
+        self._transform(value, prefix, separator, ctx)
+    }
+
+    /// A column per key of `record` matching this pattern key, named by
+    /// substituting the matched key for `{}` in the rename, or prefixed like
+    /// a `key!` of it.
+    fn _extract_matches(
+        &self,
+        record: &Value,
+        prefix: &str,
+        separator: &str,
+        ctx: &mut Context,
+    ) -> Result<Record, ExtractError> {
+        let m = match record {
+            Value::Object(m) => m,
+            _ => return Ok(vec![]),
+        };
+        let mut fields = vec![];
+        for (key, value) in m.iter().filter(|(key, _)| glob(self.key, key)) {
+            let (_, value) = self._transform(Some(value.clone()), prefix, separator, ctx)?;
+            let column = match self.name {
+                Some(name) => name.replace("{}", key),
+                None => SubSchema::prefix(prefix, key, separator),
+            };
+            fields.push((column, value));
+        }
+        Ok(fields)
+    }
+
+    /// One single-column record per element of an array value, or one
+    /// record as with `_extract_key` for anything else; an empty array is
+    /// missing.
+    fn _extract_values(
+        &self,
+        record: Option<&Value>,
+        prefix: &str,
+        separator: &str,
+        ctx: &mut Context,
+    ) -> Result<Vec<Record>, ExtractError> {
+        let elements = match self._read(record, prefix, separator, ctx)? {
+            Some(Value::Array(elements)) if !elements.is_empty() => elements,
+            Some(Value::Array(_)) => {
+                return Ok(vec![vec![self._transform(None, prefix, separator, ctx)?]])
+            }
+            value => return Ok(vec![vec![self._transform(value, prefix, separator, ctx)?]]),
+        };
+        let outer = ctx.element;
+        let mut records = vec![];
+        for (i, element) in elements.into_iter().enumerate() {
+            ctx.element = Some(i);
+            records.push(vec![self._transform(
+                Some(element),
+                prefix,
+                separator,
+                ctx,
+            )?]);
+        }
+        ctx.element = outer;
+        Ok(records)
+    }
+
+    /// The key's value in `record` (at its path, for dotted and JSON
+    /// Pointer keys), at its fallback path, or its default; absent values
+    /// of `required` keys are errors in strict mode.
+    fn _read(
+        &self,
+        record: Option<&Value>,
+        prefix: &str,
+        separator: &str,
+        ctx: &mut Context,
+    ) -> Result<Option<Value>, ExtractError> {
+        let value = match record {
+            Some(Value::Object(m)) => m.get(self.key).cloned(),
+            _ => None,
+        };
+
+        let value = match (value, record) {
+            (None, Some(record)) if self.key.starts_with('/') => record.pointer(self.key).cloned(),
+            (None, Some(record)) if self.key.contains(['.', '[']) => {
+                lookup(record, self.key).cloned()
+            }
+            (value, _) => value,
+        };
+
+        #[cfg(feature = "jsonpath")]
+        let value = match (&self.options.jsonpath, record) {
+            (Some(path), Some(record)) => {
+                let mut nodes = path.query(record).all();
+                match nodes.len() {
+                    0 => None,
+                    1 => nodes.pop().cloned(),
+                    _ => Some(Value::Array(nodes.into_iter().cloned().collect())),
+                }
+            }
+            (Some(_), None) => None,
+            (None, _) => value,
+        };
+
+        let value = match (value, self.options.fallback) {
+            (None, Some(path)) => lookup(ctx.root, path).cloned(),
+            (value, _) => value,
+        };
+
+        if let Some(metrics) = ctx.metrics() {
+            let node = metrics.node(&SubSchema::prefix(prefix, self.key, separator));
+            match value {
+                Some(_) => node.matched += 1,
+                None => node.missing += 1,
+            }
+        }
+
+        if value.is_none() && self.options.required && ctx.strict {
+            return Err(ExtractError::Missing {
+                path: SubSchema::prefix(prefix, self.key, separator),
+                element: ctx.element,
+            });
+        }
+        Ok(value.or_else(|| self.options.default.clone()))
+    }
+
+    fn _transform(
+        &self,
+        value: Option<Value>,
+        prefix: &str,
+        separator: &str,
+        ctx: &mut Context,
+    ) -> Result<Pair, ExtractError> {
+        let k = self.column(prefix, separator);
+        match &self.transform {
+            Some(func) => match func(value) {
+                Ok(value) => Ok((k, value)),
+                Err(error) => {
+                    let path = SubSchema::prefix(prefix, self.key, separator);
+                    if let Some(metrics) = ctx.metrics() {
+                        metrics.node(&path).failed += 1;
+                    }
+                    Err(ExtractError::Transform {
+                        path,
+                        element: ctx.element,
+                        error,
+                    })
+                }
+            },
+            None => Ok((k, value)),
+        }
+    }
+
+    /// Sets a transform applying `steps` in order; see `transforms::chain`.
+    pub fn chain(mut self, steps: Vec<TryTransform>, policy: transforms::ChainPolicy) -> Self {
+        self.transform = Some(transforms::chain(steps, policy));
+        self
+    }
+
+    /// Sets a transform that can reject values, failing the extraction
+    /// with `ExtractError::Transform`.
+    pub fn try_transform<F>(mut self, func: F) -> Self
+    where
+        F: Fn(Option<Value>) -> Result<Option<Value>, TransformError> + Send + Sync + 'static,
+    {
+        self.transform = Some(Box::new(func));
+        self
+    }
+
+    /// Sets the path read from the document root when this key is absent
+    /// from its own sub-document.
+    pub fn fallback(mut self, path: &'a str) -> Self {
+        self.options.fallback = Some(path);
+        self
+    }
+
+    /// Tags this key's column with a PII sensitivity class.
+    pub fn pii(mut self, class: &'a str) -> Self {
+        self.options.pii = Some(class);
+        self
+    }
+
+    /// Sets an anonymizing transform, which allows a `pii` column to be
+    /// written to sinks that aren't approved for raw PII.
+    pub fn anonymize<F>(mut self, func: F) -> Self
+    where
+        F: Fn(Option<Value>) -> Option<Value> + Send + Sync + 'static,
+    {
+        self.transform = Some(fallible(func));
+        self.options.anonymized = true;
+        self
+    }
+
+    /// Declares how sinks should present this key's column.
+    pub fn format(mut self, format: Format) -> Self {
+        self.options.format = Some(format);
+        self
+    }
+
+    /// Marks this key's column as part of the record's natural key.
+    pub fn natural_key(mut self) -> Self {
+        self.options.natural_key = true;
+        self
+    }
+
+    /// Marks the key as required: `extract_strict` fails when it is absent
+    /// from its sub-document and has no fallback value. `null` counts as
+    /// present.
+    pub fn required(mut self) -> Self {
+        self.options.required = true;
+        self
+    }
+
+    /// Sets the value read when the key is absent from its sub-document and
+    /// has no fallback value, before the transform. `null` counts as
+    /// present, and absent keys still count as missing in metrics and for
+    /// `required`.
+    pub fn default(mut self, value: Value) -> Self {
+        self.options.default = Some(value);
+        self
+    }
+
+    /// Reads the value `path` selects from the key's sub-document, with `$`
+    /// standing for the sub-document, instead of reading the key, which
+    /// then only names the column: e.g.
+    /// `key!("family", "mom_name").jsonpath("$.family[?(@.relation=='mom')].name".parse()?)`.
+    /// Several matches are extracted as an array, and none as a missing
+    /// value. Behind the `jsonpath` feature.
+    #[cfg(feature = "jsonpath")]
+    pub fn jsonpath(mut self, path: JsonPath) -> Self {
+        self.options.jsonpath = Some(path);
+        self
+    }
+
+    /// Matches the key as a glob; see `key_pattern!`.
+    pub fn pattern(mut self) -> Self {
+        self.options.pattern = true;
+        self
+    }
+
+    /// Expects string values of at most `length` characters; see
+    /// `stage::Guard`.
+    pub fn max_length(mut self, length: usize) -> Self {
+        self.options.limits.max_length = Some(length);
+        self
+    }
+
+    /// Expects at most `count` distinct values over a run; see
+    /// `stage::Guard`.
+    pub fn max_cardinality(mut self, count: usize) -> Self {
+        self.options.limits.max_cardinality = Some(count);
+        self
+    }
+
+    /// Explodes an array of scalars into one record per element, combined
+    /// with its siblings like a `Sub` over an array of objects. The column
+    /// holds one element per record, after the transform. An empty array
+    /// counts as missing, so the parent's record is kept; a non-array value
+    /// is written as usual.
+    pub fn explode(mut self) -> Self {
+        self.options.explode = true;
+        self
+    }
+
+    fn _lineage(
+        &self,
+        input: (&str, &str),
+        (prefix, separator): (&str, &str),
+        pointer: &str,
+        inherited: &[Value],
+        fields: &mut Map<String, Value>,
+    ) {
+        let mut transformations = vec![json!({
+            "type": "DIRECT",
+            "subtype": match (&self.transform, self.options.anonymized) {
+                (None, _) => "IDENTITY",
+                (Some(_), false) => "TRANSFORMATION",
+                (Some(_), true) => "MASKED",
+            },
+            "description": "",
+            "masking": self.options.anonymized,
+        })];
+        transformations.extend_from_slice(inherited);
+
+        let mut pointers = vec![match self.key.starts_with('/') {
+            true => format!("{pointer}{}", self.key),
+            false => steps(self.key).fold(pointer.to_string(), |pointer, key| {
+                format!("{pointer}/{}", escape_pointer(key))
+            }),
+        }];
+        if let Some(path) = self.options.fallback {
+            pointers.push(
+                steps(path)
+                    .map(|p| format!("/{}", escape_pointer(p)))
+                    .collect(),
+            );
+        }
+
+        let input_fields: Vec<Value> = pointers
+            .into_iter()
+            .map(|field| {
+                json!({
+                    "namespace": input.0,
+                    "name": input.1,
+                    "field": field,
+                    "transformations": transformations.clone(),
+                })
+            })
+            .collect();
+        fields.insert(
+            self.column(prefix, separator),
+            json!({ "inputFields": input_fields }),
+        );
+    }
+
+    fn _fingerprint(&self, hash: &mut u64) {
+        fnv(hash, b"K");
+        fnv_str(hash, self.key);
+        match self.name {
+            Some(name) => {
+                fnv(hash, b"N");
+                fnv_str(hash, name);
+            }
+            None => fnv(hash, b"-"),
+        }
+        fnv(hash, if self.transform.is_some() { b"T" } else { b"-" });
+        if let Some(path) = self.options.fallback {
+            fnv(hash, b"F");
+            fnv_str(hash, path);
+        }
+        if let Some(class) = self.options.pii {
+            fnv(hash, if self.options.anonymized { b"A" } else { b"P" });
+            fnv_str(hash, class);
+        }
+        if let Some(format) = &self.options.format {
+            fnv(hash, b"R");
+            fnv_str(hash, &format!("{format:?}"));
+        }
+        if self.options.natural_key {
+            fnv(hash, b"U");
+        }
+        if self.options.explode {
+            fnv(hash, b"E");
+        }
+        if self.options.pattern {
+            fnv(hash, b"G");
+        }
+        #[cfg(feature = "jsonpath")]
+        if let Some(path) = &self.options.jsonpath {
+            fnv(hash, b"J");
+            fnv_str(hash, &path.to_string());
+        }
+        if let Some(value) = &self.options.default {
+            fnv(hash, b"D");
+            fnv_str(hash, &value.to_string());
+        }
+        if self.options.required {
+            fnv(hash, b"Q");
+        }
+        if let Some(length) = self.options.limits.max_length {
+            fnv(hash, b"X");
+            fnv(hash, &(length as u64).to_le_bytes());
+        }
+        if let Some(count) = self.options.limits.max_cardinality {
+            fnv(hash, b"C");
+            fnv(hash, &(count as u64).to_le_bytes());
+        }
+    }
+
+    /// Output column name: the rename, or the source key prefixed with its
+    /// `Sub`s, with the steps of a dotted or JSON Pointer key joined like
+    /// `Sub`s.
+    fn column(&self, prefix: &str, separator: &str) -> String {
+        let key = match self.key.strip_prefix('/') {
+            Some(pointer) => pointer
+                .split('/')
+                .map(unescape_pointer)
+                .collect::<Vec<_>>()
+                .join(separator),
+            None => steps(self.key).collect::<Vec<_>>().join(separator),
+        };
+        match self.name {
+            Some(name) => name.to_string(),
+            None => SubSchema::prefix(prefix, &key, separator),
+        }
+    }
+}
+
+/// A `KeySchema`: `key!(source)`, `key!(source, column)` or
+/// `key!(source, column, transform)`, where the transform is a function or
+/// closure; `key!(source, default = value)` is `key!(source)` with
+/// `KeySchema::default`.
+///
+/// A dotted source such as `"phone.number"` reaches into nested objects
+/// when no key is literally named that, and its column is named as under
+/// `sub!("phone", { key!("number") })`; `"family[0].name"` reads the first
+/// element of `family`, into the column `family_0_name`.
+///
+/// A JSON Pointer source (RFC 6901) such as `"/phone/number"` or
+/// `"/family/0/name"` reads and is named the same way, with tokens that
+/// may contain dots; unprefixed, it is named after its last token as
+/// written, `~0`/`~1` escapes included.
+#[macro_export]
+macro_rules! key {
+    ($id:expr) => {
+        $crate::KeySchema {
+            key: $id,
+            name: None,
+            transform: None,
+            options: $crate::KeyOptions::default(),
+        }
+    };
+    ($id:expr, default = $default:expr) => {
+        $crate::key!($id).default($default)
+    };
+    ($id:expr, $name:expr) => {
+        $crate::KeySchema {
+            key: $id,
+            name: Some($name),
+            transform: None,
+            options: $crate::KeyOptions::default(),
+        }
+    };
+    ($id:expr, $name:expr, $func:expr) => {
+        $crate::KeySchema {
+            key: $id,
+            name: Some($name),
+            transform: Some($crate::fallible($func)),
+            options: $crate::KeyOptions::default(),
+        }
+    };
+}
+
+/// A `KeySchema` matching every key of its sub-document that fits a glob,
+/// where `*` matches any run of characters and `?` any one:
+/// `key_pattern!("metric_*")`, or `key_pattern!("metric_*", "m_{}")` to
+/// name the columns by substituting each matched key for `{}`, optionally
+/// followed by a transform applied to each value. Columns are otherwise
+/// prefixed like a `key!` of each match, in document key order.
+#[macro_export]
+macro_rules! key_pattern {
+    ($pattern:expr) => {
+        $crate::key!($pattern).pattern()
+    };
+    ($pattern:expr, $name:expr) => {
+        $crate::key!($pattern, $name).pattern()
+    };
+    ($pattern:expr, $name:expr, $func:expr) => {
+        $crate::key!($pattern, $name, $func).pattern()
+    };
+}
+
+/// The root `SubSchema` of a document: `doc! { node, ... }`.
+#[macro_export]
+macro_rules! doc {
+    ($($schema:expr),+) => {
+        $crate::SubSchema {
+            name: "",
+            children: vec![$($crate::Node::from($schema)),+],
+            options: $crate::SubOptions::default(),
+        }
+    };
+}
+
+/// A nested `SubSchema`: `sub!(source, { node, ... })`, optionally followed
+/// by a `RecordTransform`.
+#[macro_export]
+macro_rules! sub {
+    ($id:expr, {$($schema:expr),+}) => {
+        $crate::SubSchema {
+            name: $id,
+            children: vec![$($crate::Node::from($schema)),+],
+            options: $crate::SubOptions::default(),
+        }
+    };
+    ($id:expr, {$($schema:expr),+}, $func:expr) => {
+        $crate::SubSchema {
+            name: $id,
+            children: vec![$($crate::Node::from($schema)),+],
+            options: $crate::SubOptions {
+                transform: Some(Box::new($func)),
+                ..$crate::SubOptions::default()
+            },
+        }
+    };
+}
+
+/// A `SubSchema` of one array element: `sub_at!(source, index, { node, ...
+/// })` is `sub!(source, { node, ... })` with `SubSchema::at`.
+#[macro_export]
+macro_rules! sub_at {
+    ($id:expr, $index:expr, {$($schema:expr),+}) => {
+        $crate::sub!($id, {$($schema),+}).at($index)
+    };
+    ($id:expr, $index:expr, {$($schema:expr),+}, $func:expr) => {
+        $crate::sub!($id, {$($schema),+}, $func).at($index)
+    };
+}
+
+/// Whether `text` matches `pattern`, where `*` matches any run of
+/// characters and `?` any one.
+fn glob(pattern: &str, text: &str) -> bool {
+    let (pattern, text): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), text.chars().collect());
+    // Backtrack to just after the last `*` on a mismatch.
+    let (mut p, mut t) = (0, 0);
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
+/// Follows a dot-separated path of object keys and `[i]` array indices
+/// down from `value`.
+fn lookup<'v>(value: &'v Value, path: &str) -> Option<&'v Value> {
+    steps(path).try_fold(value, |value, step| match value {
+        Value::Array(array) => array.get(step.parse::<usize>().ok()?),
+        value => value.get(step),
+    })
+}
+
+/// The steps of a `lookup` path, array indices included: `family[0].name`
+/// is `family`, `0`, `name`.
+fn steps(path: &str) -> impl Iterator<Item = &str> {
+    path.split('.')
+        .flat_map(|part| part.split('['))
+        .map(|step| step.strip_suffix(']').unwrap_or(step))
+}
+
+/// Escapes a JSON Pointer reference token (RFC 6901).
+fn escape_pointer(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+
+/// Undoes `escape_pointer`, `~1` first so that `~01` becomes `~1`.
+fn unescape_pointer(token: &str) -> String {
+    token.replace("~1", "/").replace("~0", "~")
+}
+
+/// Default separator between column prefixes.
+const SEPARATOR: &str = "_";
+
+pub(crate) const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// FNV-1a, chosen over `DefaultHasher` because its output must not change
+/// between Rust releases.
+pub(crate) fn fnv(hash: &mut u64, bytes: &[u8]) {
+    for byte in bytes {
+        *hash ^= *byte as u64;
+        *hash = hash.wrapping_mul(FNV_PRIME);
+    }
+}
+
+pub(crate) fn fnv_str(hash: &mut u64, s: &str) {
+    fnv(hash, &(s.len() as u64).to_le_bytes());
+    fnv(hash, s.as_bytes());
+}
+
+/// `merge` by `mode`; `Zip` and `First` merge one record of each set at a
+/// time, so columns come in the same order as under `Cartesian`.
+fn merge_with(sets: Vec<Vec<Record>>, mode: Merge) -> Vec<Record> {
+    let longest = sets.iter().map(Vec::len).max().unwrap_or(0);
+    let rows = match mode {
+        Merge::Cartesian => return merge(sets),
+        Merge::Zip => longest,
+        Merge::First => longest.min(1),
+    };
+    (0..rows)
+        .flat_map(|i| {
+            let row = sets
+                .iter()
+                .filter_map(|set| match set.len() {
+                    1 => set.first(),
+                    _ => set.get(i),
+                })
+                .map(|record| vec![record.clone()])
+                .collect();
+            merge(row)
+        })
+        .collect()
+}
+
+fn merge(mut sets: Vec<Vec<Record>>) -> Vec<Record> {
+    match sets.len() {
+        0 => vec![],
+        1 => sets[0].clone(),
+        2 => merge_two(sets[0].clone(), sets[1].clone()),
+        _ => {
+            let head = sets.pop().unwrap();
+            sets.into_iter().fold(head, merge_two)
+        }
+    }
+}
+
+fn merge_two(left: Vec<Record>, right: Vec<Record>) -> Vec<Record> {
+    let s1 = left.into_iter();
+    let s2 = right.into_iter();
+    s1.clone()
+        .flat_map(|x: Record| {
+            s2.clone()
+                .map(move |mut y: Record| {
+                    y.append(&mut x.clone());
+                    y.clone()
+                })
+                .collect::<Vec<Record>>()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn merge_nothing() {
+        let data = vec![];
+        let expected: Vec<Record> = vec![];
+        assert_eq!(merge(data), expected);
+    }
+
+    #[test]
+    fn merge_one() {
+        let data = vec![vec![vec![("test".to_string(), None)]]];
+        let expected: Vec<Record> = vec![vec![("test".to_string(), None)]];
+        assert_eq!(merge(data), expected);
+    }
+
+    #[test]
+    fn merge_two() {
+        let first = ("test1".into(), None);
+        let second = ("test2".into(), None);
+        let data: Vec<Vec<Record>> = vec![vec![vec![first.clone()]], vec![vec![second.clone()]]];
+        let expected: Vec<Record> = vec![vec![second, first]];
+        assert_eq!(merge(data), expected);
+    }
+
+    #[test]
+    fn merge_two_by_one() {
+        let first = ("test1".into(), None);
+        let second = ("test2".into(), None);
+        let third = ("test3".into(), None);
+        let data: Vec<Vec<Record>> = vec![
+            vec![vec![first.clone()]],
+            vec![vec![second.clone()], vec![third.clone()]],
+        ];
+        let expected: Vec<Record> = vec![vec![second, first.clone()], vec![third, first.clone()]];
+        assert_eq!(merge(data), expected);
+    }
+
+    #[test]
+    fn metrics_count_nodes() {
+        let data = json!({
+            "id": 1,
+            "family": [{"name": "Mother Superior"}, {"name": "Father Dearest"}]
+        });
+        let schema = doc! {
+            key!("id"),
+            key!("email"),
+            sub!("phone", { key!("number") }),
+            sub!("family", { key!("name") })
+        };
+
+        let mut metrics = Metrics::default();
+        let results = schema.extract_with_metrics(&data, &mut metrics).unwrap();
+
+        assert_eq!(results.len(), 2);
+        let family = metrics.get("family").unwrap();
+        assert_eq!((family.matched, family.missing, family.records), (2, 0, 2));
+        assert_eq!(metrics.get("family_name").unwrap().matched, 2);
+        assert_eq!(metrics.get("").unwrap().records, 2);
+        assert_eq!(metrics.dead(), vec!["email", "phone"]);
+
+        let schema = doc! {
+            key!("id").try_transform(|_| Err(TransformError::new("rejected")))
+        };
+        assert!(schema
+            .extract_strict_with_metrics(&data, &mut metrics)
+            .is_err());
+        assert_eq!(metrics.get("id").unwrap().failed, 1);
+        assert_eq!(
+            metrics.report()["id"],
+            json!({"matched": 2, "missing": 0, "records": 0, "failed": 1})
+        );
+    }
+
+    #[test]
+    fn fingerprint_is_stable() {
+        let schema = doc! { key!("id"), sub!("phone", { key!("number", "phone") }) };
+        let same = doc! { key!("id"), sub!("phone", { key!("number", "phone") }) };
+        let renamed = doc! { key!("id"), sub!("phone", { key!("number", "number") }) };
+
+        assert_eq!(schema.fingerprint(), "af2977ff478ecebf");
+        assert_eq!(schema.fingerprint(), same.fingerprint());
+        assert_ne!(schema.fingerprint(), renamed.fingerprint());
+        for changed in [
+            doc! { key!("id").required(), sub!("phone", { key!("number", "phone") }) },
+            doc! { key!("id").max_length(8), sub!("phone", { key!("number", "phone") }) },
+            doc! { key!("id").max_cardinality(8), sub!("phone", { key!("number", "phone") }) },
+        ] {
+            assert_ne!(schema.fingerprint(), changed.fingerprint());
+        }
+
+        let results = schema.extract_versioned(&json!({"id": 1})).unwrap();
+        assert_eq!(
+            results[0].last().unwrap().1,
+            Some(json!("af2977ff478ecebf"))
+        );
+    }
+
+    #[test]
+    fn sub_transform_rewrites_records() {
+        fn parents_only(record: Record) -> Vec<Record> {
+            match record.iter().find(|(k, _)| k == "family_relation") {
+                Some((_, Some(v))) if v == "child" => vec![],
+                _ => vec![record],
+            }
+        }
+
+        let data = json!({
+            "id": 1,
+            "family": [
+                {"relation": "mom"},
+                {"relation": "child"},
+                {"relation": "dad"},
+            ]
+        });
+        let schema = doc! {
+            key!("id"),
+            sub!("family", { key!("relation") }, parents_only)
+        };
+
+        let results = schema.extract(&data).unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(
+            results[1][1],
+            ("family_relation".into(), Some(json!("dad")))
+        );
+    }
+
+    #[test]
+    fn key_fallback_reads_from_root() {
+        let data = json!({
+            "billing": {"email": "billing@example.com"},
+            "contacts": [
+                {"name": "a", "email": "a@example.com"},
+                {"name": "b"},
+            ]
+        });
+        let schema = doc! {
+            sub!("contacts", {
+                key!("name"),
+                key!("email").fallback("billing.email")
+            })
+        };
+
+        let results = schema.extract(&data).unwrap();
+        assert_eq!(results[0][1].1, Some(json!("a@example.com")));
+        assert_eq!(results[1][1].1, Some(json!("billing@example.com")));
+    }
+
+    #[test]
+    fn sub_limit_and_offset() {
+        let data = json!({"events": [{"n": 1}, {"n": 2}, {"n": 3}, {"n": 4}]});
+        let schema = doc! { sub!("events", { key!("n") }).offset(1).limit(2) };
+
+        let results = schema.extract(&data).unwrap();
+        let values: Vec<_> = results.iter().map(|r| r[0].1.clone()).collect();
+        assert_eq!(values, vec![Some(json!(2)), Some(json!(3))]);
+    }
+
+    #[test]
+    fn array_elements_by_index() {
+        let data = json!({
+            "id": 7,
+            "family": [{"name": "Ana"}, {"name": "Luis"}],
+            "point": [3, 4]
+        });
+        let schema = doc! {
+            key!("id"),
+            key!("family[0].name"),
+            key!("point[1]", "y"),
+            sub_at!("family", 1, { key!("name") }),
+            sub_at!("family", 5, { key!("relation") })
+        };
+
+        assert_eq!(
+            schema.extract(&data).unwrap(),
+            vec![vec![
+                ("id".to_string(), Some(json!(7))),
+                ("family_0_name".to_string(), Some(json!("Ana"))),
+                ("y".to_string(), Some(json!(4))),
+                ("family_name".to_string(), Some(json!("Luis"))),
+            ]]
+        );
+        assert_ne!(
+            doc! { sub_at!("family", 1, { key!("name") }) }.fingerprint(),
+            doc! { sub!("family", { key!("name") }) }.fingerprint()
+        );
+    }
+
+    #[test]
+    fn merge_modes_combine_sibling_arrays() {
+        let data = json!({
+            "id": 1,
+            "a": [{"x": 1}, {"x": 2}, {"x": 3}],
+            "b": [{"y": 1}, {"y": 2}]
+        });
+        let schema = |merge| {
+            doc! { key!("id"), sub!("a", { key!("x") }), sub!("b", { key!("y") }) }.merge(merge)
+        };
+        let column = |records: &[Record], name: &str| -> Vec<Option<Value>> {
+            records
+                .iter()
+                .map(|r| {
+                    r.iter()
+                        .find(|(c, _)| c == name)
+                        .and_then(|(_, v)| v.clone())
+                })
+                .collect()
+        };
+
+        let cartesian = schema(Merge::Cartesian).extract(&data).unwrap();
+        assert_eq!(cartesian.len(), 6);
+
+        let zip = schema(Merge::Zip).extract(&data).unwrap();
+        assert_eq!(zip.len(), 3);
+        assert_eq!(
+            zip[0].iter().map(|(c, _)| c.as_str()).collect::<Vec<_>>(),
+            ["b_y", "a_x", "id"]
+        );
+        assert_eq!(
+            column(&zip, "a_x"),
+            [Some(json!(1)), Some(json!(2)), Some(json!(3))]
+        );
+        assert_eq!(column(&zip, "b_y"), [Some(json!(1)), Some(json!(2)), None]);
+        assert_eq!(column(&zip, "id"), vec![Some(json!(1)); 3]);
+
+        let first = schema(Merge::First).extract(&data).unwrap();
+        assert_eq!(first, zip[..1]);
+        assert_eq!("Zip".parse(), Ok(Merge::Zip));
+    }
+
+    #[test]
+    fn sub_filter_skips_elements() {
+        let data = json!({
+            "family": [
+                {"relation": "mom"},
+                {"relation": "sister"},
+                {"relation": "dad"},
+                {"relation": "mom"},
+            ]
+        });
+        let filter = r#"relation == "mom" || relation == "dad""#.parse().unwrap();
+        let schema = doc! { sub!("family", { key!("relation") }).filter(filter).limit(2) };
+
+        let results = schema.extract(&data).unwrap();
+        let values: Vec<_> = results.iter().map(|r| r[0].1.clone()).collect();
+        assert_eq!(values, vec![Some(json!("mom")), Some(json!("dad"))]);
+    }
+
+    #[test]
+    fn pii_enforcement() {
+        fn redact(val: Option<Value>) -> Option<Value> {
+            val.map(|_| json!("<redacted>"))
+        }
+
+        let schema = doc! {
+            key!("id"),
+            key!("email").pii("email"),
+            sub!("family", { key!("name").pii("name").anonymize(redact) })
+        };
+
+        assert_eq!(
+            schema.pii_columns(),
+            vec![
+                ("email".to_string(), "email"),
+                ("family_name".to_string(), "name")
+            ]
+        );
+        assert_eq!(schema.check_pii(), Err(vec!["email".to_string()]));
+
+        let results = schema
+            .extract(&json!({"family": [{"name": "Mother Superior"}]}))
+            .unwrap();
+        assert_eq!(results[0][2].1, Some(json!("<redacted>")));
+    }
+
+    #[test]
+    fn key_formats() {
+        let schema = doc! {
+            key!("amount").format(Format::Precision(2)),
+            sub!("phone", { key!("number") })
+        };
+
+        assert_eq!(
+            schema.formats(),
+            HashMap::from([("amount".to_string(), Format::Precision(2))])
+        );
+    }
+
+    #[test]
+    fn nest_inverts_extract() {
+        let data = json!({
+            "id": 1,
+            "name": "Felix Alonso",
+            "phone": {"type": "cell", "number": "661 867 5309"},
+            "family": [
+                {"relation": "mom", "name": "Mother Superior"},
+                {"relation": "dad", "name": "Father Dearest"},
+            ]
+        });
+        let schema = doc! {
+            key!("id", "human_id"),
+            key!("name"),
+            sub!("phone", {
+                key!("type"),
+                key!("number")
+            }),
+            sub!("family", {
+                key!("relation", "relationship"),
+                key!("name", "full_name")
+            })
+        };
+
+        let mut records = schema.extract(&data).unwrap();
+        records.extend(schema.extract(&json!({"id": 2, "name": "Other"})).unwrap());
+
+        assert_eq!(
+            schema.nest(&records),
+            vec![data, json!({"id": 2, "name": "Other"})]
+        );
+    }
+
+    #[test]
+    fn natural_key_dedupe() {
+        let schema = doc! {
+            key!("id", "human_id").natural_key(),
+            key!("version"),
+            sub!("family", { key!("relation").natural_key() })
+        };
+        assert_eq!(
+            schema.natural_key_columns(),
+            vec!["human_id", "family_relation"]
+        );
+
+        let mut dedupe = stage::Dedupe::new(schema.natural_key_columns());
+        let first = dedupe.apply(
+            schema
+                .extract(&json!({
+                    "id": 1, "version": 1, "family": [{"relation": "mom"}, {"relation": "mom"}]
+                }))
+                .unwrap(),
+        );
+        let second = dedupe.apply(
+            schema
+                .extract(&json!({
+                    "id": 1, "version": 2, "family": [{"relation": "mom"}, {"relation": "dad"}]
+                }))
+                .unwrap(),
+        );
+
+        assert_eq!(first.len(), 1);
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0][2].1, Some(json!("dad")));
+    }
+
+    #[test]
+    fn lineage_maps_pointers_to_columns() {
+        fn mask(_: Option<Value>) -> Option<Value> {
+            None
+        }
+
+        let schema = doc! {
+            key!("id").fallback("meta.id"),
+            sub!("phone", {key!("number", "phone").anonymize(mask)}),
+            key!("a/b")
+        };
+        let lineage = schema.lineage(
+            ("kafka://broker", "users"),
+            ("postgres://db", "public.users"),
+        );
+        let fields = &lineage["facets"]["columnLineage"]["fields"];
+
+        assert_eq!(lineage["name"], "public.users");
+        assert_eq!(fields["id"]["inputFields"][0]["field"], "/id");
+        assert_eq!(fields["id"]["inputFields"][1]["field"], "/meta/id");
+        assert_eq!(fields["phone"]["inputFields"][0]["field"], "/phone/number");
+        assert_eq!(
+            fields["phone"]["inputFields"][0]["transformations"][0]["subtype"],
+            "MASKED"
+        );
+        assert_eq!(fields["a/b"]["inputFields"][0]["field"], "/a~1b");
+    }
+
+    #[test]
+    fn memoized_extraction_reuses_subtrees() {
+        let schema = doc! {
+            key!("id"),
+            sub!("items", {
+                key!("sku"),
+                sub!("seller", {key!("name")})
+            })
+        };
+        let data = json!({
+            "id": 1,
+            "items": [
+                {"sku": "a", "seller": {"name": "acme"}},
+                {"sku": "b", "seller": {"name": "acme"}},
+                {"sku": "a", "seller": {"name": "acme"}},
+            ]
+        });
+
+        let mut memo = Memo::new(100);
+        assert_eq!(
+            schema.extract_memoized(&data, &mut memo).unwrap(),
+            schema.extract(&data).unwrap()
+        );
+        assert_eq!(memo.hits, 2);
+        assert_eq!(
+            schema.extract_memoized(&data, &mut memo).unwrap(),
+            schema.extract(&data).unwrap()
+        );
+        assert_eq!(memo.hits, 5);
+    }
+
+    #[test]
+    fn extract_reports_errors() {
+        let schema = doc! {
+            key!("id"),
+            sub!("phone", {key!("number")})
+        };
+
+        assert_eq!(
+            schema.extract(&json!({"id": 1, "phone": "555"})),
+            Err(ExtractError::TypeMismatch {
+                path: "phone".into(),
+                found: "a string",
+            })
+        );
+        assert_eq!(
+            schema.extract(&json!({"id": 1, "phone": [{"number": "1"}, 2]})),
+            Err(ExtractError::TypeMismatch {
+                path: "phone".into(),
+                found: "a number",
+            })
+        );
+        assert!(schema.extract(&json!({"id": 1, "phone": null})).is_ok());
+    }
+
+    #[test]
+    fn strict_mode_requires_keys() {
+        let schema = doc! {
+            key!("id").required(),
+            sub!("phone", { key!("number").required(), key!("type") })
+        };
+        let document = json!({"id": 1, "phone": [{"number": "1"}, {"type": "cell"}]});
+
+        assert_eq!(schema.extract(&document).unwrap().len(), 2);
+        let error = schema.extract_strict(&document).unwrap_err();
+        assert_eq!(
+            error,
+            ExtractError::Missing {
+                path: "phone_number".into(),
+                element: Some(1),
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "required key \"phone_number\" missing at element 1"
+        );
+        assert!(schema.extract_strict(&json!({"id": null})).is_ok());
+        assert!(schema.extract_strict(&json!({"phone": null})).is_err());
+    }
+
+    #[test]
+    fn defaults_fill_absent_keys() {
+        let schema = doc! {
+            key!("country", default = json!("US")),
+            key!("count", "n", |v: Option<Value>| v.map(|v| json!(v.as_i64().unwrap_or(0) + 1)))
+                .default(json!(0))
+        };
+
+        assert_eq!(
+            schema.extract(&json!({})).unwrap(),
+            vec![vec![
+                ("country".to_string(), Some(json!("US"))),
+                ("n".to_string(), Some(json!(1))),
+            ]]
+        );
+        assert_eq!(
+            schema
+                .extract(&json!({"country": null, "count": 2}))
+                .unwrap(),
+            vec![vec![
+                ("country".to_string(), Some(Value::Null)),
+                ("n".to_string(), Some(json!(3))),
+            ]]
+        );
+    }
+
+    #[cfg(feature = "jsonpath")]
+    #[test]
+    fn jsonpath_keys_select_values() {
+        let path = |selector: &str| selector.parse::<JsonPath>().unwrap();
+        let schema = doc! {
+            key!("family", "mom_name").jsonpath(path("$.family[?(@.relation=='mom')].name")),
+            key!("family", "names").jsonpath(path("$.family[*].name")),
+            key!("family", "aunt").jsonpath(path("$.family[?(@.relation=='aunt')].name"))
+        };
+        let document = json!({"family": [
+            {"relation": "dad", "name": "Father Dearest"},
+            {"relation": "mom", "name": "Mother Dearest"}
+        ]});
+
+        assert_eq!(
+            schema.extract(&document).unwrap(),
+            vec![vec![
+                ("mom_name".to_string(), Some(json!("Mother Dearest"))),
+                (
+                    "names".to_string(),
+                    Some(json!(["Father Dearest", "Mother Dearest"]))
+                ),
+                ("aunt".to_string(), None),
+            ]]
+        );
+    }
+
+    #[test]
+    fn dotted_keys_read_nested_values() {
+        let schema = doc! {
+            key!("phone.number"),
+            sub!("user", { key!("address.city") }).separator("."),
+            key!("a.b")
+        };
+        let document = json!({
+            "phone": {"number": "555"},
+            "user": {"address": {"city": "Oslo"}},
+            "a.b": 1,
+            "a": {"b": 2}
+        });
+
+        assert_eq!(
+            schema.extract(&document).unwrap(),
+            vec![vec![
+                ("phone_number".to_string(), Some(json!("555"))),
+                ("a_b".to_string(), Some(json!(1))),
+                ("user.address.city".to_string(), Some(json!("Oslo"))),
+            ]]
+        );
+        assert_eq!(
+            doc! { key!("phone.number") }.unprefixed().columns(),
+            vec!["number"]
+        );
+    }
+
+    #[test]
+    fn pointer_keys_read_nested_values() {
+        let schema = doc! {
+            key!("/phone/number"),
+            key!("/family/0/name"),
+            key!("/a~1b/c.d"),
+            key!("/m~0n")
+        };
+        let document = json!({
+            "phone": {"number": "555"},
+            "family": [{"name": "Ana"}, {"name": "Luis"}],
+            "a/b": {"c.d": 1},
+            "m~n": 2
+        });
+
+        assert_eq!(
+            schema.extract(&document).unwrap(),
+            vec![vec![
+                ("phone_number".to_string(), Some(json!("555"))),
+                ("family_0_name".to_string(), Some(json!("Ana"))),
+                ("a/b_c.d".to_string(), Some(json!(1))),
+                ("m~n".to_string(), Some(json!(2))),
+            ]]
+        );
+        assert_eq!(unescape_pointer("~01"), "~1");
+        assert_eq!(
+            doc! { key!("/family/0/name") }.unprefixed().columns(),
+            vec!["name"]
+        );
+    }
+
+    #[test]
+    fn pattern_keys_emit_a_column_per_match() {
+        assert!(glob("metric_*", "metric_cpu"));
+        assert!(glob("*_?s", "latency_ms"));
+        assert!(glob("a*b*c", "aXbYbZc"));
+        assert!(!glob("metric_*", "metrics"));
+        assert!(!glob("a?", "a"));
+
+        let schema = doc! {
+            key!("host"),
+            sub!("stats", {
+                key_pattern!("metric_*", "{}_value", transforms::to_string),
+                key_pattern!("*_ms")
+            })
+            .rest()
+        };
+        let document = json!({
+            "host": "a",
+            "stats": {"metric_cpu": 1, "metric_mem": 2, "latency_ms": 3, "other": 4}
+        });
+
+        assert_eq!(
+            schema.extract(&document).unwrap(),
+            vec![vec![
+                ("host".to_string(), Some(json!("a"))),
+                ("metric_cpu_value".to_string(), Some(json!("1"))),
+                ("metric_mem_value".to_string(), Some(json!("2"))),
+                ("stats_latency_ms".to_string(), Some(json!(3))),
+                ("stats_other".to_string(), Some(json!(4))),
+            ]]
+        );
+        assert_eq!(schema.columns(), vec!["host"]);
+    }
+
+    #[test]
+    fn rest_extracts_unnamed_keys() {
+        let schema = doc! {
+            key!("id"),
+            sub!("metadata", { key!("source", "origin") }).rest()
+        };
+        let document = json!({
+            "id": 1,
+            "ignored": true,
+            "metadata": {"source": "web", "tags": ["a"], "v": 2}
+        });
+
+        assert_eq!(
+            schema.extract(&document).unwrap(),
+            vec![vec![
+                ("id".to_string(), Some(json!(1))),
+                ("origin".to_string(), Some(json!("web"))),
+                ("metadata_tags".to_string(), Some(json!(["a"]))),
+                ("metadata_v".to_string(), Some(json!(2))),
+            ]]
+        );
+        assert_eq!(schema.columns(), vec!["id", "origin"]);
+
+        let bare = doc! { key!("id") }.rest().unprefixed();
+        assert_eq!(
+            bare.extract(&json!({"id": 1, "x": null})).unwrap(),
+            vec![vec![
+                ("id".to_string(), Some(json!(1))),
+                ("x".to_string(), Some(Value::Null)),
+            ]]
+        );
+    }
+
+    #[test]
+    fn unprefixed_columns_and_collisions() {
+        let schema = doc! {
+            key!("id"),
+            sub!("phone", { key!("number"), key!("type", "phone_type") }).unprefixed(),
+            sub!("family", { key!("name") })
+        };
+        assert_eq!(
+            schema.columns(),
+            vec!["id", "number", "phone_type", "family_name"]
+        );
+        assert_eq!(schema.check_columns(), Ok(()));
+
+        let schema = doc! { key!("id"), sub!("parent", { key!("id") }) }.unprefixed();
+        assert_eq!(schema.columns(), vec!["id", "id"]);
+        assert_eq!(schema.check_columns(), Err(vec!["id".to_string()]));
+    }
+
+    #[test]
+    fn separator_joins_prefixes() {
+        use json::Nest;
+
+        let schema = doc! {
+            key!("user_id"),
+            sub!("home_phone", { key!("number") }),
+            sub!("work", { sub!("phone", { key!("area_code") }).separator("::") })
+        }
+        .separator(".");
+        assert_eq!(
+            schema.columns(),
+            vec!["user_id", "home_phone.number", "work.phone::area_code"]
+        );
+
+        let records = schema
+            .extract(&json!({"user_id": 1, "home_phone": {"number": "555"}}))
+            .unwrap();
+        assert_eq!(
+            records[0].nest("."),
+            json!({"user_id": 1, "home_phone": {"number": "555"}})
+        );
+    }
+
+    #[test]
+    fn explode_scalar_arrays() {
+        let schema = doc! { key!("id"), key!("tags", "tag").explode() };
+
+        let records = schema
+            .extract(&json!({"id": 1, "tags": ["a", "b"]}))
+            .unwrap();
+        assert_eq!(
+            records,
+            vec![
+                vec![
+                    ("id".to_string(), Some(json!(1))),
+                    ("tag".to_string(), Some(json!("a")))
+                ],
+                vec![
+                    ("id".to_string(), Some(json!(1))),
+                    ("tag".to_string(), Some(json!("b")))
+                ],
+            ]
+        );
+        let records = schema.extract(&json!({"id": 2, "tags": []})).unwrap();
+        assert_eq!(
+            records,
+            vec![vec![
+                ("id".to_string(), Some(json!(2))),
+                ("tag".to_string(), None)
+            ]]
+        );
+    }
+
+    #[test]
+    fn extract_many_concatenates_root_array() {
+        let schema = doc! { key!("id"), sub!("phone", { key!("number") }) };
+        let documents = json!([
+            {"id": 1, "phone": [{"number": "1"}, {"number": "2"}]},
+            {"id": 2}
+        ]);
+
+        let records = schema.extract_many(&documents).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[2][0], ("id".to_string(), Some(json!(2))));
+        assert_eq!(
+            schema.extract_many(&json!({"id": 3})).unwrap(),
+            schema.extract(&json!({"id": 3})).unwrap()
+        );
+    }
+
+    #[test]
+    fn closure_transforms_capture_configuration() {
+        let pattern = String::from("%Y/%m/%d");
+        let schema = doc! {
+            key!("ts", "day", move |v: Option<Value>| {
+                v.as_ref()
+                    .and_then(time::timestamp_of)
+                    .map(|secs| json!(time::format_timestamp(secs, &pattern)))
+            })
+        };
+
+        let results = schema
+            .extract(&json!({"ts": "2024-05-01T12:30:00Z"}))
+            .unwrap();
+        assert_eq!(results[0][0].1, Some(json!("2024/05/01")));
+    }
+
+    #[test]
+    fn try_transform_errors_carry_path_and_element() {
+        let schema = doc! {
+            sub!("items", {
+                key!("price").try_transform(|v| match v {
+                    Some(Value::String(s)) => s
+                        .parse::<f64>()
+                        .map(|price| Some(json!(price)))
+                        .map_err(|_| TransformError::new(format!("bad price {s:?}"))),
+                    v => Ok(v),
+                })
+            })
+        };
+
+        let error = schema
+            .extract(&json!({"items": [{"price": "1.5"}, {"price": "n/a"}]}))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "transform of \"items_price\" failed at element 1: bad price \"n/a\""
+        );
+    }
+
+    #[test]
+    fn builtin_transforms_in_key_macro() {
+        let schema = doc! {
+            key!("email", "email", transforms::lowercase),
+            key!("score", "score", transforms::round(1))
+        };
+        let results = schema
+            .extract(&json!({"email": "A@B.C", "score": 2.46}))
+            .unwrap();
+        assert_eq!(results[0][0].1, Some(json!("a@b.c")));
+        assert_eq!(results[0][1].1, Some(json!(2.5)));
+    }
+
+    #[test]
+    fn key_chains_transforms() {
+        let schema = doc! {
+            key!("email").chain(
+                vec![
+                    fallible(transforms::trim),
+                    fallible(transforms::null_if_empty),
+                    fallible(transforms::lowercase),
+                ],
+                transforms::ChainPolicy::StopOnNone,
+            )
+        };
+        let results = schema.extract(&json!({"email": " A@B.C "})).unwrap();
+        assert_eq!(results[0][0].1, Some(json!("a@b.c")));
+    }
+}
@@ -0,0 +1,256 @@
+//! Coordination between flattener processes sharing an output directory:
+//! part files with names no two runs can both claim, and a manifest listing
+//! the committed parts, appended to under a lock file. The lock file names
+//! the process holding it and when it took it, so a lock left behind by a
+//! crashed run is broken once it is older than any commit takes.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+const MANIFEST: &str = "_manifest.jsonl";
+const LOCK: &str = "_manifest.lock";
+
+/// An output directory shared by concurrent runs.
+///
+/// Each run writes its output to parts claimed with `create_part` and then
+/// lists them with `commit`. Readers should only trust parts the manifest
+/// lists; parts of runs that crashed before committing are left behind.
+#[derive(Debug, Clone)]
+pub struct OutputDir {
+    dir: PathBuf,
+    /// How long `commit` waits for another run's lock before giving up.
+    timeout: Duration,
+    /// How old a lock must be to count as left behind by a crashed run.
+    stale: Duration,
+}
+
+impl OutputDir {
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(OutputDir {
+            dir,
+            timeout: Duration::from_secs(30),
+            stale: Duration::from_secs(10),
+        })
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Breaks locks older than this; `commit` only holds the lock for one
+    /// append, so the default of 10 seconds is plenty.
+    pub fn stale(mut self, stale: Duration) -> Self {
+        self.stale = stale;
+        self
+    }
+
+    /// Creates the first free part file `part-{run}-NNNNN.{extension}`.
+    /// Files are created exclusively, so concurrent runs, even ones sharing
+    /// a run id, never open the same part.
+    pub fn create_part(&self, run: &str, extension: &str) -> io::Result<(PathBuf, File)> {
+        for n in 0.. {
+            let path = self.dir.join(format!("part-{run}-{n:05}.{extension}"));
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(file) => return Ok((path, file)),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        unreachable!()
+    }
+
+    /// Appends `parts` (path and record count) to the manifest as one JSON
+    /// line each, holding the directory's lock file while writing.
+    pub fn commit(&self, run: &str, parts: &[(PathBuf, usize)]) -> io::Result<()> {
+        let _lock = self.lock()?;
+        let mut manifest = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.dir.join(MANIFEST))?;
+        let mut lines = String::new();
+        for (path, records) in parts {
+            let file = path.file_name().unwrap_or(path.as_os_str());
+            let entry = json!({
+                "run": run,
+                "file": file.to_string_lossy().into_owned(),
+                "records": *records,
+            });
+            lines.push_str(&entry.to_string());
+            lines.push('\n');
+        }
+        manifest.write_all(lines.as_bytes())?;
+        manifest.sync_all()
+    }
+
+    /// The manifest's entries, in commit order.
+    pub fn manifest(&self) -> io::Result<Vec<Value>> {
+        let file = match File::open(self.dir.join(MANIFEST)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        BufReader::new(file)
+            .lines()
+            .filter(|line| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+            .map(|line| {
+                serde_json::from_str(&line?)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            })
+            .collect()
+    }
+
+    fn lock(&self) -> io::Result<Lock> {
+        let path = self.dir.join(LOCK);
+        let start = Instant::now();
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    // Taken before writing, so the lock is dropped on failure.
+                    let lock = Lock(path);
+                    let holder = json!({"pid": std::process::id(), "locked_at": now()});
+                    file.write_all(format!("{holder}\n").as_bytes())?;
+                    return Ok(lock);
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    if self.break_stale(&path)? {
+                        continue;
+                    }
+                    if start.elapsed() >= self.timeout {
+                        let pid = match fs::read_to_string(&path) {
+                            Ok(text) => serde_json::from_str::<Value>(&text)
+                                .ok()
+                                .and_then(|holder| holder["pid"].as_u64()),
+                            Err(_) => None,
+                        };
+                        let holder = match pid {
+                            Some(pid) => format!("process {pid}"),
+                            None => "another run".to_string(),
+                        };
+                        return Err(io::Error::new(
+                            io::ErrorKind::WouldBlock,
+                            format!("{} is held by {holder}", path.display()),
+                        ));
+                    }
+                    thread::sleep(Duration::from_millis(50));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Removes the lock at `path` if it is stale, returning whether it did.
+    fn break_stale(&self, path: &PathBuf) -> io::Result<bool> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e),
+        };
+        // A lock without its time yet is being written, unless its file is
+        // as old as a stale lock.
+        let locked_at = match serde_json::from_str::<Value>(&text) {
+            Ok(holder) => holder["locked_at"].as_f64(),
+            Err(_) => None,
+        };
+        let locked_at = match locked_at {
+            Some(locked_at) => locked_at,
+            None => match fs::metadata(path)?.modified()?.duration_since(UNIX_EPOCH) {
+                Ok(modified) => modified.as_secs_f64(),
+                Err(_) => return Ok(false),
+            },
+        };
+        if now() - locked_at < self.stale.as_secs_f64() {
+            return Ok(false);
+        }
+        // Move the lock aside rather than removing it, so that if another
+        // run broke it and took a fresh one in the meantime, the fresh one
+        // can be put back.
+        let aside = self
+            .dir
+            .join(format!("{LOCK}.stale-{}", std::process::id()));
+        match fs::rename(path, &aside) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(true),
+            Err(e) => return Err(e),
+        }
+        if fs::read_to_string(&aside).is_ok_and(|moved| moved != text) {
+            // Fails if yet another run holds the lock by now, which is fine.
+            let _ = fs::hard_link(&aside, path);
+        }
+        fs::remove_file(&aside)?;
+        Ok(true)
+    }
+}
+
+/// Seconds since the Unix epoch.
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+/// Removes the lock file when dropped.
+struct Lock(PathBuf);
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parts_never_clash_and_commits_append() {
+        let dir = std::env::temp_dir().join(format!("manifest-{}", std::process::id()));
+        let output = OutputDir::new(&dir).unwrap();
+        let (first, _) = output.create_part("a", "csv").unwrap();
+        let (second, _) = output.create_part("a", "csv").unwrap();
+        assert!(first.ends_with("part-a-00000.csv"));
+        assert!(second.ends_with("part-a-00001.csv"));
+
+        output.commit("a", &[(first, 2), (second, 1)]).unwrap();
+        output.commit("b", &[]).unwrap();
+        let manifest = output.manifest().unwrap();
+        assert_eq!(manifest.len(), 2);
+        assert_eq!(
+            manifest[1],
+            json!({"run": "a", "file": "part-a-00001.csv", "records": 1})
+        );
+
+        let held = output.lock().unwrap();
+        let error = output
+            .clone()
+            .timeout(Duration::ZERO)
+            .commit("c", &[])
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        let pid = std::process::id();
+        assert!(error
+            .to_string()
+            .ends_with(&format!("held by process {pid}")));
+        // A lock older than `stale` was left by a crashed run.
+        output
+            .clone()
+            .stale(Duration::ZERO)
+            .commit("c", &[])
+            .unwrap();
+        drop(held);
+
+        let stale = json!({"pid": 1, "locked_at": now() - 60.0});
+        fs::write(dir.join(LOCK), stale.to_string()).unwrap();
+        output.commit("d", &[]).unwrap();
+        assert!(!dir.join(LOCK).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,164 @@
+//! Column-name sanitizing for target systems with strict identifier rules.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::Record;
+
+/// A system whose identifier rules generated column names must follow.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    /// At most 63 bytes, lowercase `[a-z0-9_]`, reserved words escaped.
+    Postgres,
+    /// At most 300 characters, `[A-Za-z0-9_]`, reserved words escaped and
+    /// no `_TABLE_`/`_FILE_`/`_PARTITION` prefixes.
+    BigQuery,
+    /// `[A-Za-z_][A-Za-z0-9_]*`.
+    Avro,
+}
+
+#[rustfmt::skip]
+const POSTGRES_RESERVED: &[&str] = &[
+    "all", "analyse", "analyze", "and", "any", "array", "as", "asc", "asymmetric", "both", "case",
+    "cast", "check", "collate", "column", "constraint", "create", "current_catalog",
+    "current_date", "current_role", "current_time", "current_timestamp", "current_user", "default",
+    "deferrable", "desc", "distinct", "do", "else", "end", "except", "false", "fetch", "for",
+    "foreign", "from", "grant", "group", "having", "in", "initially", "intersect", "into",
+    "lateral", "leading", "limit", "localtime", "localtimestamp", "not", "null", "offset", "on",
+    "only", "or", "order", "placing", "primary", "references", "returning", "select",
+    "session_user", "some", "symmetric", "table", "then", "to", "trailing", "true", "union",
+    "unique", "user", "using", "variadic", "when", "where", "window", "with",
+];
+
+#[rustfmt::skip]
+const BIGQUERY_RESERVED: &[&str] = &[
+    "all", "and", "any", "array", "as", "asc", "assert_rows_modified", "at", "between", "by",
+    "case", "cast", "collate", "contains", "create", "cross", "cube", "current", "default",
+    "define", "desc", "distinct", "else", "end", "enum", "escape", "except", "exclude", "exists",
+    "extract", "false", "fetch", "following", "for", "from", "full", "group", "grouping", "groups",
+    "hash", "having", "if", "ignore", "in", "inner", "intersect", "interval", "into", "is", "join",
+    "lateral", "left", "like", "limit", "lookup", "merge", "natural", "new", "no", "not", "null",
+    "nulls", "of", "on", "or", "order", "outer", "over", "partition", "preceding", "proto",
+    "qualify", "range", "recursive", "respect", "right", "rollup", "rows", "select", "set", "some",
+    "struct", "tablesample", "then", "to", "treat", "true", "unbounded", "union", "unnest",
+    "using", "when", "where", "window", "with", "within",
+];
+
+impl Target {
+    fn max_len(&self) -> usize {
+        match self {
+            Self::Postgres => 63,
+            Self::BigQuery => 300,
+            Self::Avro => usize::MAX,
+        }
+    }
+
+    fn reserved(&self) -> &'static [&'static str] {
+        match self {
+            Self::Postgres => POSTGRES_RESERVED,
+            Self::BigQuery => BIGQUERY_RESERVED,
+            Self::Avro => &[],
+        }
+    }
+
+    /// Sanitizes a single column name. Different inputs may collide; use
+    /// `mapping` to sanitize a whole set of columns.
+    pub fn sanitize(&self, column: &str) -> String {
+        let mut name: String = column
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+
+        if *self == Self::Postgres {
+            name.make_ascii_lowercase();
+        }
+        if !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+            name.insert(0, '_');
+        }
+        if *self == Self::BigQuery {
+            let upper = name.to_ascii_uppercase();
+            if ["_TABLE_", "_FILE_", "_PARTITION"]
+                .iter()
+                .any(|p| upper.starts_with(p))
+            {
+                name.insert(0, 'c');
+            }
+        }
+        if self
+            .reserved()
+            .contains(&name.to_ascii_lowercase().as_str())
+        {
+            name.push('_');
+        }
+
+        name.truncate(self.max_len());
+        name
+    }
+
+    /// Sanitized names for `columns`, in the same order, with collisions
+    /// resolved by numeric suffixes (`name_2`, `name_3`, ...).
+    pub fn mapping(&self, columns: &[String]) -> Vec<(String, String)> {
+        let mut used = HashSet::new();
+        columns
+            .iter()
+            .map(|column| {
+                let base = self.sanitize(column);
+                let mut name = base.clone();
+                let mut n = 1;
+                while !used.insert(name.to_ascii_lowercase()) {
+                    n += 1;
+                    let suffix = format!("_{n}");
+                    let mut truncated = base.clone();
+                    truncated.truncate(self.max_len().saturating_sub(suffix.len()));
+                    name = truncated + &suffix;
+                }
+                (column.clone(), name)
+            })
+            .collect()
+    }
+}
+
+/// Renames a record's columns with a `Target::mapping`, so data keys match
+/// the sanitized headers. Columns missing from the mapping are kept as-is.
+pub fn rename(record: Record, mapping: &HashMap<String, String>) -> Record {
+    record
+        .into_iter()
+        .map(|(name, value)| match mapping.get(&name) {
+            Some(renamed) => (renamed.clone(), value),
+            None => (name, value),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sanitizes_for_targets() {
+        assert_eq!(Target::Postgres.sanitize("Phone-Number"), "phone_number");
+        assert_eq!(Target::Postgres.sanitize("order"), "order_");
+        assert_eq!(Target::Postgres.sanitize("2fa"), "_2fa");
+        assert_eq!(Target::Postgres.sanitize(&"x".repeat(80)).len(), 63);
+        assert_eq!(Target::BigQuery.sanitize("_TABLE_SUFFIX"), "c_TABLE_SUFFIX");
+        assert_eq!(Target::BigQuery.sanitize("Select"), "Select_");
+        assert_eq!(Target::Avro.sanitize("user.name"), "user_name");
+    }
+
+    #[test]
+    fn mapping_resolves_collisions() {
+        let columns = vec!["a.b".to_string(), "a_b".to_string(), "A-B".to_string()];
+        let names: Vec<String> = Target::Postgres
+            .mapping(&columns)
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect();
+        assert_eq!(names, vec!["a_b", "a_b_2", "a_b_3"]);
+
+        let mapping: HashMap<_, _> = Target::Postgres.mapping(&columns).into_iter().collect();
+        let record: Record = vec![("A-B".into(), None), ("other".into(), None)];
+        assert_eq!(
+            rename(record, &mapping),
+            vec![("a_b_3".to_string(), None), ("other".to_string(), None)]
+        );
+    }
+}
@@ -0,0 +1,132 @@
+//! Newline-delimited JSON input, read one document at a time so exports
+//! larger than memory can be flattened as a stream.
+
+use std::fmt;
+use std::io::{self, BufRead};
+
+use serde_json::Value;
+
+use crate::strict;
+
+/// Iterates over the documents of an NDJSON stream, skipping blank lines.
+/// A line that isn't valid JSON is an `InvalidData` error naming the line;
+/// iteration can continue past it. A strict reader's repeated keys are
+/// `InvalidData` errors too, wrapping a `DuplicateKeys`.
+pub struct NdjsonReader<R: BufRead> {
+    input: R,
+    line: usize,
+    buffer: String,
+    strict: bool,
+}
+
+impl<R: BufRead> NdjsonReader<R> {
+    pub fn new(input: R) -> Self {
+        NdjsonReader {
+            input,
+            line: 0,
+            buffer: String::new(),
+            strict: false,
+        }
+    }
+
+    /// Also rejects lines repeating a key within an object, which parsing
+    /// would silently resolve; see `strict::duplicate_keys`.
+    pub fn strict(mut self) -> Self {
+        self.strict = true;
+        self
+    }
+
+    /// 1-based number of the line the last document was read from.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+}
+
+impl<R: BufRead> Iterator for NdjsonReader<R> {
+    type Item = io::Result<Value>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            self.buffer.clear();
+            match self.input.read_line(&mut self.buffer) {
+                Ok(0) => return None,
+                Ok(_) => self.line += 1,
+                Err(e) => return Some(Err(e)),
+            }
+            let line = self.buffer.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = |message: String| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: {message}", self.line),
+                )
+            };
+            let document = serde_json::from_str(line).map_err(|e| invalid(e.to_string()));
+            let duplicates = match &document {
+                Ok(_) if self.strict => strict::duplicate_keys(line),
+                _ => vec![],
+            };
+            return Some(if duplicates.is_empty() {
+                document
+            } else {
+                let line = self.line;
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    DuplicateKeys { line, duplicates },
+                ))
+            });
+        }
+    }
+}
+
+/// A line of a strict `NdjsonReader` repeating keys within an object: valid
+/// JSON, unlike other `InvalidData` lines.
+#[derive(Debug)]
+pub struct DuplicateKeys {
+    pub line: usize,
+    /// JSON Pointers of the repeated keys.
+    pub duplicates: Vec<String>,
+}
+
+impl fmt::Display for DuplicateKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let duplicates = self.duplicates.join(", ");
+        write!(f, "line {}: duplicate keys {duplicates}", self.line)
+    }
+}
+
+impl std::error::Error for DuplicateKeys {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn reads_documents_line_by_line() {
+        let input = "{\"id\": 1}\n\n{\"id\": \n{\"id\": 3}\r\n";
+        let mut reader = NdjsonReader::new(input.as_bytes());
+
+        assert_eq!(reader.next().unwrap().unwrap(), json!({"id": 1}));
+        let error = reader.next().unwrap().unwrap_err();
+        assert!(error.to_string().starts_with("line 3: "), "{error}");
+        assert_eq!(reader.next().unwrap().unwrap(), json!({"id": 3}));
+        assert_eq!(reader.line(), 4);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn strict_reader_rejects_duplicate_keys() {
+        let input = "{\"id\": 1, \"id\": 2}\n{\"id\": 3}\n";
+        let mut reader = NdjsonReader::new(input.as_bytes()).strict();
+
+        let error = reader.next().unwrap().unwrap_err();
+        assert_eq!(error.to_string(), "line 1: duplicate keys /id");
+        assert!(error.get_ref().unwrap().is::<DuplicateKeys>());
+        assert_eq!(reader.next().unwrap().unwrap(), json!({"id": 3}));
+        let lenient = NdjsonReader::new(input.as_bytes()).next().unwrap();
+        assert_eq!(lenient.unwrap(), json!({"id": 2}));
+    }
+}
@@ -0,0 +1,290 @@
+//! Parquet output, behind the `parquet` feature, so typed analytics stacks
+//! don't lose column types going through CSV.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::str::FromStr;
+
+use ::arrow::datatypes::SchemaRef;
+use ::parquet::arrow::ArrowWriter;
+use ::parquet::basic::{Compression, GzipLevel, ZstdLevel};
+use ::parquet::errors::ParquetError;
+use ::parquet::file::properties::WriterProperties;
+
+use crate::arrow::{arrow_schema, record_batch};
+use crate::format::Format;
+use crate::warehouse::{ColumnType, TypeInference};
+use crate::Record;
+
+/// Compression applied to each column chunk. Levels are checked when the
+/// file is opened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    None,
+    /// The default, as in Spark.
+    #[default]
+    Snappy,
+    /// Level 0–10.
+    Gzip(u32),
+    /// Level 1–22.
+    Zstd(i32),
+}
+
+impl Codec {
+    fn compression(self) -> Result<Compression, ParquetError> {
+        Ok(match self {
+            Codec::None => Compression::UNCOMPRESSED,
+            Codec::Snappy => Compression::SNAPPY,
+            Codec::Gzip(level) => Compression::GZIP(GzipLevel::try_new(level)?),
+            Codec::Zstd(level) => Compression::ZSTD(ZstdLevel::try_new(level)?),
+        })
+    }
+}
+
+/// Parses `none`, `snappy`, `gzip`, `zstd`, or `gzip:LEVEL` / `zstd:LEVEL`;
+/// gzip defaults to level 6 and zstd to 3.
+impl FromStr for Codec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, level) = match s.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (s, None),
+        };
+        let invalid = || format!("invalid compression level in {s:?}");
+        match (name.to_ascii_lowercase().as_str(), level) {
+            ("none" | "uncompressed", None) => Ok(Codec::None),
+            ("snappy", None) => Ok(Codec::Snappy),
+            ("gzip", None) => Ok(Codec::Gzip(6)),
+            ("gzip", Some(level)) => level.parse().map(Codec::Gzip).map_err(|_| invalid()),
+            ("zstd", None) => Ok(Codec::Zstd(3)),
+            ("zstd", Some(level)) => level.parse().map(Codec::Zstd).map_err(|_| invalid()),
+            _ => Err(format!("unknown compression codec {s:?}")),
+        }
+    }
+}
+
+/// Writes records to a Parquet file in batches. Column types are inferred
+/// from the first batch (see `TypeInference`) unless given with
+/// `with_types`, and fixed from then on: a later value that doesn't fit its
+/// column's type, or a value in a column the first batch didn't have, is
+/// an error, except that anything can be written to a string column. Types
+/// map to Arrow as in `arrow::data_type`, and columns only ever null in the
+/// first batch are nullable strings so later batches can still fill them.
+pub struct ParquetWriter<W: Write + Send> {
+    out: Option<W>,
+    writer: Option<ArrowWriter<W>>,
+    schema: Option<SchemaRef>,
+    columns: Vec<String>,
+    formats: HashMap<String, Format>,
+    types: Option<Vec<(String, ColumnType)>>,
+    batch: Vec<Record>,
+    batch_size: usize,
+    codec: Codec,
+    row_group_size: Option<usize>,
+    page_size: Option<usize>,
+}
+
+impl<W: Write + Send> ParquetWriter<W> {
+    /// `columns` come first, in order, usually `SubSchema::columns()`.
+    pub fn new(out: W, columns: Vec<String>) -> Self {
+        ParquetWriter {
+            out: Some(out),
+            writer: None,
+            schema: None,
+            columns,
+            formats: HashMap::new(),
+            types: None,
+            batch: vec![],
+            batch_size: 8192,
+            codec: Codec::default(),
+            row_group_size: None,
+            page_size: None,
+        }
+    }
+
+    /// Declares how columns are typed, usually `SubSchema::formats()`; see
+    /// `ColumnType::declared`.
+    pub fn with_formats(mut self, formats: HashMap<String, Format>) -> Self {
+        self.formats = formats;
+        self
+    }
+
+    /// Fixes every column's type instead of inferring them.
+    pub fn with_types(mut self, types: Vec<(String, ColumnType)>) -> Self {
+        self.types = Some(types);
+        self
+    }
+
+    /// Records per Arrow batch, and for inference.
+    pub fn batch_size(mut self, size: usize) -> Self {
+        self.batch_size = size.max(1);
+        self
+    }
+
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Maximum rows per row group; Parquet's default is about a million.
+    pub fn row_group_size(mut self, rows: usize) -> Self {
+        self.row_group_size = Some(rows.max(1));
+        self
+    }
+
+    /// Target size of data pages in bytes; Parquet's default is 1 MiB.
+    pub fn page_size(mut self, bytes: usize) -> Self {
+        self.page_size = Some(bytes.max(1));
+        self
+    }
+
+    fn properties(&self) -> Result<WriterProperties, ParquetError> {
+        let mut properties = WriterProperties::builder().set_compression(self.codec.compression()?);
+        if let Some(rows) = self.row_group_size {
+            properties = properties.set_max_row_group_size(rows);
+        }
+        if let Some(bytes) = self.page_size {
+            properties = properties.set_data_page_size_limit(bytes);
+        }
+        Ok(properties.build())
+    }
+
+    pub fn write(&mut self, record: &Record) -> Result<(), ParquetError> {
+        self.batch.push(record.clone());
+        if self.batch.len() >= self.batch_size {
+            self.flush_batch()?;
+        }
+        Ok(())
+    }
+
+    fn flush_batch(&mut self) -> Result<(), ParquetError> {
+        if self.writer.is_none() {
+            let types = match self.types.take() {
+                Some(types) => types,
+                None => {
+                    let mut inference = TypeInference::new(self.columns.clone(), &self.formats);
+                    for record in self.batch.iter() {
+                        inference.observe(record);
+                    }
+                    inference.types()
+                }
+            };
+            let schema = arrow_schema(&types);
+            let properties = self.properties()?;
+            let out = self.out.take().expect("output is only taken once");
+            self.writer = Some(ArrowWriter::try_new(out, schema.clone(), Some(properties))?);
+            self.schema = Some(schema);
+        }
+        if self.batch.is_empty() {
+            return Ok(());
+        }
+
+        let schema = self.schema.clone().expect("schema is set with the writer");
+        let added = self.batch.iter().flatten().find(|(name, value)| {
+            value.as_ref().is_some_and(|value| !value.is_null())
+                && schema.field_with_name(name).is_err()
+        });
+        if let Some((name, _)) = added {
+            return Err(ParquetError::General(format!(
+                "column {name:?} isn't in the schema fixed by the first batch"
+            )));
+        }
+        let batch = std::mem::take(&mut self.batch);
+        let batch = record_batch(schema, &batch, &self.formats)?;
+        match self.writer.as_mut() {
+            Some(writer) => writer.write(&batch),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the remaining records and the file footer.
+    pub fn finish(mut self) -> Result<W, ParquetError> {
+        self.flush_batch()?;
+        match self.writer.take() {
+            Some(writer) => writer.into_inner(),
+            None => Err(ParquetError::General("writer was never opened".into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::arrow::array::AsArray;
+    use ::arrow::compute::concat_batches;
+    use ::arrow::datatypes::Int64Type;
+    use ::arrow::record_batch::RecordBatch;
+    use ::parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use serde_json::json;
+    use std::fs::{self, File};
+
+    #[test]
+    fn writes_records_in_batches() {
+        let records: Vec<Record> = vec![
+            vec![
+                ("id".into(), Some(json!(1))),
+                ("ok".into(), Some(json!(true))),
+            ],
+            vec![("id".into(), Some(json!(2))), ("name".into(), None)],
+            vec![
+                ("id".into(), Some(json!(3))),
+                ("ok".into(), Some(json!(false))),
+            ],
+        ];
+        let path = std::env::temp_dir().join(format!("parquet-{}", std::process::id()));
+        let file = File::create(&path).unwrap();
+        let mut writer = ParquetWriter::new(file, vec!["id".into(), "name".into()]).batch_size(1);
+        for record in records.iter() {
+            writer.write(record).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let batches: Vec<RecordBatch> = reader.map(Result::unwrap).collect();
+        fs::remove_file(&path).unwrap();
+        let batch = concat_batches(&batches[0].schema(), &batches).unwrap();
+        let ids = batch.column(0).as_primitive::<Int64Type>();
+        assert_eq!(ids.values(), &[1, 2, 3]);
+        assert_eq!(batch.column(1).null_count(), 3);
+        let ok = batch.column(2).as_boolean();
+        assert_eq!(
+            ok.iter().collect::<Vec<_>>(),
+            [Some(true), None, Some(false)]
+        );
+    }
+
+    #[test]
+    fn rejects_values_outside_the_first_batchs_schema() {
+        let first: Record = vec![("id".into(), Some(json!(1)))];
+        let mut writer = ParquetWriter::new(vec![], vec![]).batch_size(1);
+        writer.write(&first).unwrap();
+        let error = writer.write(&vec![("id".into(), Some(json!("two")))]);
+        assert!(error
+            .unwrap_err()
+            .to_string()
+            .contains("\"two\" is not Int64"));
+
+        let mut writer = ParquetWriter::new(vec![], vec![]).batch_size(1);
+        writer.write(&first).unwrap();
+        let error = writer.write(&vec![("added".into(), Some(json!(1)))]);
+        assert!(error.unwrap_err().to_string().contains("\"added\""));
+    }
+
+    #[test]
+    fn parses_and_checks_codecs() {
+        assert_eq!("snappy".parse(), Ok(Codec::Snappy));
+        assert_eq!("zstd".parse(), Ok(Codec::Zstd(3)));
+        assert_eq!("gzip:9".parse(), Ok(Codec::Gzip(9)));
+        assert!("snappy:1".parse::<Codec>().is_err());
+        assert!("lz4".parse::<Codec>().is_err());
+
+        let record: Record = vec![("id".into(), Some(json!(1)))];
+        let mut writer = ParquetWriter::new(vec![], vec![]).codec(Codec::Zstd(23));
+        writer.write(&record).unwrap();
+        assert!(writer.finish().is_err());
+    }
+}
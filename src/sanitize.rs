@@ -0,0 +1,91 @@
+//! Output-time cleanup of string values from untrusted sources.
+
+use std::borrow::Cow;
+
+use serde_json::Value;
+#[cfg(feature = "unicode")]
+use unicode_normalization::UnicodeNormalization;
+
+/// Which cleanups sinks apply to string values. Non-string values are never
+/// touched.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sanitize {
+    /// Prefix values starting with `=`, `+`, `-`, `@`, tab or carriage
+    /// return with `'`, so spreadsheets show them as text instead of
+    /// evaluating them as formulas (OWASP "CSV injection").
+    pub formulas: bool,
+    /// Remove control characters other than tab, line feed and carriage
+    /// return.
+    pub control_chars: bool,
+    /// Compose characters to Unicode Normalization Form C, so `e` followed
+    /// by a combining acute accent becomes a single `é`. Needs the
+    /// `unicode` feature; ignored without it.
+    pub nfc: bool,
+}
+
+impl Sanitize {
+    pub fn string<'s>(&self, s: &'s str) -> Cow<'s, str> {
+        let mut s = Cow::Borrowed(s);
+
+        #[cfg(feature = "unicode")]
+        if self.nfc && !unicode_normalization::is_nfc(&s) {
+            s = Cow::Owned(s.nfc().collect());
+        }
+
+        if self.control_chars && s.chars().any(is_stripped) {
+            s = Cow::Owned(s.chars().filter(|c| !is_stripped(*c)).collect());
+        }
+
+        if self.formulas && s.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+            s = Cow::Owned(format!("'{s}"));
+        }
+
+        s
+    }
+
+    pub fn value(&self, value: &Value) -> Value {
+        match value {
+            Value::String(s) => Value::String(self.string(s).into_owned()),
+            other => other.clone(),
+        }
+    }
+}
+
+fn is_stripped(c: char) -> bool {
+    c.is_control() && !matches!(c, '\t' | '\n' | '\r')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn neutralizes_formulas_and_controls() {
+        let all = Sanitize {
+            formulas: true,
+            control_chars: true,
+            nfc: false,
+        };
+
+        assert_eq!(all.string("=SUM(A1:A9)"), "'=SUM(A1:A9)");
+        assert_eq!(all.string("@cmd"), "'@cmd");
+        assert_eq!(all.string("\u{0}=1+1"), "'=1+1");
+        assert_eq!(all.string("bell\u{7}\nline"), "bell\nline");
+        assert_eq!(all.string("plain"), "plain");
+        assert_eq!(all.value(&json!(-5)), json!(-5));
+
+        assert_eq!(Sanitize::default().string("=1"), "=1");
+    }
+
+    #[test]
+    #[cfg(feature = "unicode")]
+    fn normalizes_to_nfc() {
+        let nfc = Sanitize {
+            nfc: true,
+            ..Sanitize::default()
+        };
+
+        assert_eq!(nfc.string("cafe\u{301}"), "caf\u{e9}");
+    }
+}
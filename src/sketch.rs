@@ -0,0 +1,320 @@
+//! Fixed-size approximate column statistics, cheap enough to compute while
+//! records stream past so data-quality monitoring needs no second pass.
+
+use std::collections::{BTreeMap, HashSet};
+
+use serde_json::{json, Map, Value};
+
+use crate::{fnv_str, Record, FNV_OFFSET};
+
+/// HyperLogLog distinct counter with 2^12 registers (about 1.6% standard
+/// error, 4 KiB of state).
+#[derive(Debug, Clone)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+const HLL_BITS: u32 = 12;
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        HyperLogLog {
+            registers: vec![0; 1 << HLL_BITS],
+        }
+    }
+}
+
+impl HyperLogLog {
+    pub fn insert(&mut self, item: &str) {
+        let mut hash = FNV_OFFSET;
+        fnv_str(&mut hash, item);
+        // FNV's high bits are poorly mixed; finish with splitmix64's mixer.
+        hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+        hash ^= hash >> 31;
+
+        let index = (hash >> (64 - HLL_BITS)) as usize;
+        let rank = ((hash << HLL_BITS) | (1 << (HLL_BITS - 1))).leading_zeros() as u8 + 1;
+        self.registers[index] = self.registers[index].max(rank);
+    }
+
+    pub fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let sum: f64 = self.registers.iter().map(|r| 2f64.powi(-(*r as i32))).sum();
+        let raw = 0.7213 / (1.0 + 1.079 / m) * m * m / sum;
+
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        if raw <= 2.5 * m && zeros > 0 {
+            // Linear counting is more accurate for small cardinalities.
+            m * (m / zeros as f64).ln()
+        } else {
+            raw
+        }
+    }
+}
+
+/// Merging t-digest for quantiles of a numeric column.
+#[derive(Debug, Clone)]
+pub struct TDigest {
+    compression: f64,
+    /// `(mean, weight)`, sorted by mean.
+    centroids: Vec<(f64, f64)>,
+    buffer: Vec<f64>,
+    count: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Default for TDigest {
+    fn default() -> Self {
+        TDigest::new(100.0)
+    }
+}
+
+impl TDigest {
+    /// Higher `compression` keeps more centroids and gives more accurate
+    /// quantiles; 100 is a common choice.
+    pub fn new(compression: f64) -> Self {
+        TDigest {
+            compression,
+            centroids: vec![],
+            buffer: vec![],
+            count: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    pub fn add(&mut self, x: f64) {
+        if x.is_nan() {
+            return;
+        }
+        self.buffer.push(x);
+        self.count += 1.0;
+        self.min = self.min.min(x);
+        self.max = self.max.max(x);
+        if self.buffer.len() as f64 >= 5.0 * self.compression {
+            self.compress();
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count as usize
+    }
+
+    fn compress(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut all = std::mem::take(&mut self.centroids);
+        all.extend(self.buffer.drain(..).map(|x| (x, 1.0)));
+        all.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let mut merged: Vec<(f64, f64)> = Vec::with_capacity(all.len());
+        let mut before = 0.0;
+        for (mean, weight) in all {
+            if let Some(last) = merged.last_mut() {
+                let q = (before + (last.1 + weight) / 2.0) / self.count;
+                if last.1 + weight <= 4.0 * self.count * q * (1.0 - q) / self.compression {
+                    last.0 += (mean - last.0) * weight / (last.1 + weight);
+                    last.1 += weight;
+                    continue;
+                }
+                before += last.1;
+            }
+            merged.push((mean, weight));
+        }
+        self.centroids = merged;
+    }
+
+    /// Estimated value at quantile `q` (0.0 to 1.0), or `None` if nothing
+    /// was added.
+    pub fn quantile(&mut self, q: f64) -> Option<f64> {
+        self.compress();
+        if self.centroids.is_empty() {
+            return None;
+        }
+
+        let target = q.clamp(0.0, 1.0) * self.count;
+        // Interpolate between centroid centers, anchored at min and max.
+        let mut prev = (0.0, self.min);
+        let mut cumulative = 0.0;
+        for (mean, weight) in &self.centroids {
+            let center = cumulative + weight / 2.0;
+            if target < center {
+                let span = center - prev.0;
+                let t = if span > 0.0 {
+                    (target - prev.0) / span
+                } else {
+                    0.0
+                };
+                return Some(prev.1 + t * (mean - prev.1));
+            }
+            prev = (center, *mean);
+            cumulative += weight;
+        }
+        let span = self.count - prev.0;
+        let t = if span > 0.0 {
+            (target - prev.0) / span
+        } else {
+            1.0
+        };
+        Some(prev.1 + t * (self.max - prev.1))
+    }
+}
+
+/// How `ColumnSketches` treats example values of PII columns.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum PiiExamples {
+    /// Leave PII columns without examples.
+    #[default]
+    Exclude,
+    /// Replace every character of an example with `*`, keeping its length.
+    /// Masked examples aren't deduplicated, as different values can mask
+    /// alike, so they count the first values seen.
+    Mask,
+}
+
+/// A distinct-count sketch for every column and a quantile sketch for
+/// numeric values, built from observed records, plus optionally the first
+/// few distinct values of each column as examples. Missing and null values
+/// are not counted.
+#[derive(Debug, Default)]
+pub struct ColumnSketches {
+    columns: BTreeMap<String, (HyperLogLog, TDigest)>,
+    examples: usize,
+    pii: HashSet<String>,
+    pii_examples: PiiExamples,
+    samples: BTreeMap<String, Vec<Value>>,
+}
+
+impl ColumnSketches {
+    /// Keeps up to `count` distinct example values per column for the
+    /// report. Examples of `pii` columns, usually the names from
+    /// `SubSchema::pii_columns()`, are excluded or masked.
+    pub fn examples(mut self, count: usize, pii: Vec<String>, mode: PiiExamples) -> Self {
+        self.examples = count;
+        self.pii = pii.into_iter().collect();
+        self.pii_examples = mode;
+        self
+    }
+
+    pub fn observe(&mut self, record: &Record) {
+        for (name, value) in record {
+            let value = match value {
+                None | Some(Value::Null) => continue,
+                Some(value) => value,
+            };
+            self.sample(name, value);
+            let (distinct, digest) = self.columns.entry(name.clone()).or_default();
+            match value {
+                Value::String(s) => distinct.insert(s),
+                other => distinct.insert(&other.to_string()),
+            }
+            if let Some(x) = value.as_f64() {
+                digest.add(x);
+            }
+        }
+    }
+
+    fn sample(&mut self, name: &str, value: &Value) {
+        let pii = self.pii.contains(name);
+        if self.examples == 0 || (pii && self.pii_examples == PiiExamples::Exclude) {
+            return;
+        }
+        let value = match (pii, value) {
+            (false, value) => value.clone(),
+            (true, Value::String(s)) => Value::String("*".repeat(s.chars().count())),
+            (true, other) => Value::String("*".repeat(other.to_string().chars().count())),
+        };
+        let samples = self.samples.entry(name.to_string()).or_default();
+        if samples.len() < self.examples && (pii || !samples.contains(&value)) {
+            samples.push(value);
+        }
+    }
+
+    /// Per-column summary for a run report: `distinct`, plus `min`, `p50`,
+    /// `p90`, `p99` and `max` for columns with numeric values, and
+    /// `examples` when enabled.
+    pub fn report(&mut self) -> Value {
+        let mut report = Map::new();
+        for (name, (distinct, digest)) in self.columns.iter_mut() {
+            let mut column = Map::new();
+            column.insert("distinct".into(), json!(distinct.estimate().round()));
+            if digest.count() > 0 {
+                for (key, q) in [
+                    ("min", 0.0),
+                    ("p50", 0.5),
+                    ("p90", 0.9),
+                    ("p99", 0.99),
+                    ("max", 1.0),
+                ] {
+                    column.insert(key.into(), json!(digest.quantile(q)));
+                }
+            }
+            if let Some(samples) = self.samples.get(name) {
+                column.insert("examples".into(), Value::Array(samples.clone()));
+            }
+            report.insert(name.clone(), Value::Object(column));
+        }
+        Value::Object(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hyperloglog_estimates_distinct_values() {
+        let mut hll = HyperLogLog::default();
+        for i in 0..50_000 {
+            hll.insert(&(i % 20_000).to_string());
+        }
+        let estimate = hll.estimate();
+        assert!((19_000.0..21_000.0).contains(&estimate), "{estimate}");
+
+        let mut small = HyperLogLog::default();
+        for s in ["a", "b", "c", "a"] {
+            small.insert(s);
+        }
+        assert_eq!(small.estimate().round(), 3.0);
+    }
+
+    #[test]
+    fn tdigest_estimates_quantiles() {
+        let mut digest = TDigest::default();
+        assert_eq!(digest.quantile(0.5), None);
+        for i in (1..=10_000).rev() {
+            digest.add(i as f64);
+        }
+
+        let median = digest.quantile(0.5).unwrap();
+        assert!((4_900.0..5_100.0).contains(&median), "{median}");
+        let p99 = digest.quantile(0.99).unwrap();
+        assert!((9_850.0..9_950.0).contains(&p99), "{p99}");
+        assert_eq!(digest.quantile(0.0), Some(1.0));
+        assert_eq!(digest.quantile(1.0), Some(10_000.0));
+    }
+
+    #[test]
+    fn report_includes_examples() {
+        let mut sketches =
+            ColumnSketches::default().examples(2, vec!["email".into()], PiiExamples::Mask);
+        for (id, email) in [(1, "x@y.z"), (1, "q@r.s"), (2, "ab@c.d"), (3, "t@u.v")] {
+            sketches.observe(&vec![
+                ("id".to_string(), Some(json!(id))),
+                ("email".to_string(), Some(json!(email))),
+            ]);
+        }
+
+        let report = sketches.report();
+        assert_eq!(report["id"]["examples"], json!([1, 2]));
+        assert_eq!(report["email"]["examples"], json!(["*****", "*****"]));
+
+        let mut excluded =
+            ColumnSketches::default().examples(2, vec!["email".into()], PiiExamples::Exclude);
+        excluded.observe(&vec![("email".to_string(), Some(json!("a@b.c")))]);
+        assert_eq!(excluded.report()["email"].get("examples"), None);
+    }
+}
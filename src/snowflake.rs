@@ -0,0 +1,208 @@
+//! Output for Snowflake stages: CSV chunk files to `PUT` into a stage and
+//! the `COPY INTO` statement that loads them. Chunks are gzip-compressed
+//! when the `gzip` feature is enabled.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+
+use crate::csv::{CsvWriter, Dialect};
+use crate::format::Format;
+use crate::warehouse::Warehouse;
+use crate::Record;
+
+#[cfg(feature = "gzip")]
+type Chunk = flate2::write::GzEncoder<BufWriter<File>>;
+#[cfg(not(feature = "gzip"))]
+type Chunk = BufWriter<File>;
+
+#[cfg(feature = "gzip")]
+const EXTENSION: &str = "csv.gz";
+#[cfg(not(feature = "gzip"))]
+const EXTENSION: &str = "csv";
+
+fn open_chunk(path: &Path) -> io::Result<Chunk> {
+    let file = BufWriter::new(File::create(path)?);
+    #[cfg(feature = "gzip")]
+    let file = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+    Ok(file)
+}
+
+fn finish_chunk(chunk: Chunk) -> io::Result<()> {
+    #[cfg(feature = "gzip")]
+    let chunk = chunk.finish()?;
+    chunk.into_inner().map_err(|e| e.into_error())?;
+    Ok(())
+}
+
+/// Writes records into numbered CSV files of at most `rows_per_file` rows,
+/// `{prefix}_0000.csv.gz` and on, each starting with the header.
+///
+/// Fields use RFC 4180 quoting, which `copy_into` declares along with
+/// `ESCAPE_UNENCLOSED_FIELD = NONE` so backslashes in unquoted fields stay
+/// literal. Absent values and empty strings are both written as empty
+/// fields and load as NULL.
+pub struct StageWriter {
+    dir: PathBuf,
+    prefix: String,
+    rows_per_file: usize,
+    columns: Vec<String>,
+    formats: HashMap<String, Format>,
+    current: Option<CsvWriter<Chunk>>,
+    rows: usize,
+    files: Vec<PathBuf>,
+}
+
+impl StageWriter {
+    pub fn new(dir: impl Into<PathBuf>, prefix: &str, columns: Vec<String>) -> Self {
+        StageWriter {
+            dir: dir.into(),
+            prefix: prefix.to_string(),
+            rows_per_file: 100_000,
+            columns,
+            formats: HashMap::new(),
+            current: None,
+            rows: 0,
+            files: vec![],
+        }
+    }
+
+    pub fn rows_per_file(mut self, rows: usize) -> Self {
+        self.rows_per_file = rows.max(1);
+        self
+    }
+
+    /// See `CsvWriter::with_formats`.
+    pub fn with_formats(mut self, formats: HashMap<String, Format>) -> Self {
+        self.formats = formats;
+        self
+    }
+
+    pub fn write(&mut self, record: &Record) -> io::Result<()> {
+        if self.rows == self.rows_per_file {
+            self.finish_current()?;
+        }
+        let writer = match &mut self.current {
+            Some(writer) => writer,
+            None => {
+                let path = self.dir.join(format!(
+                    "{}_{:04}.{EXTENSION}",
+                    self.prefix,
+                    self.files.len()
+                ));
+                let writer = CsvWriter::new(open_chunk(&path)?, self.columns.clone(), dialect())?
+                    .with_formats(self.formats.clone());
+                self.files.push(path);
+                self.current.insert(writer)
+            }
+        };
+        writer.write(record)?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    fn finish_current(&mut self) -> io::Result<()> {
+        self.rows = 0;
+        match self.current.take() {
+            Some(writer) => finish_chunk(writer.into_inner()),
+            None => Ok(()),
+        }
+    }
+
+    /// Flushes the last chunk and returns the paths of all chunks written.
+    pub fn finish(mut self) -> io::Result<Vec<PathBuf>> {
+        self.finish_current()?;
+        Ok(self.files)
+    }
+
+    /// The `COPY INTO` statement loading this writer's chunks once they are
+    /// uploaded to `stage`, e.g. `my_stage` or `~`; see `copy_into`.
+    pub fn copy_into(&self, table: &str, stage: &str) -> String {
+        copy_into(table, &self.columns, stage, &self.prefix)
+    }
+}
+
+/// The CSV dialect `StageWriter` writes.
+pub fn dialect() -> Dialect {
+    Dialect::default()
+}
+
+/// A `COPY INTO` statement loading the chunks named `{prefix}_NNNN` from
+/// `@stage/` into `columns` of `table`.
+pub fn copy_into(table: &str, columns: &[String], stage: &str, prefix: &str) -> String {
+    let quote = |name: &str| Warehouse::Snowflake.quote(name);
+    let columns: Vec<String> = columns.iter().map(|column| quote(column)).collect();
+    let compression = if cfg!(feature = "gzip") {
+        "GZIP"
+    } else {
+        "NONE"
+    };
+    format!(
+        "COPY INTO {} ({})\n\
+         FROM @{stage}/\n\
+         FILE_FORMAT = (TYPE = CSV COMPRESSION = {compression} SKIP_HEADER = 1 \
+         FIELD_OPTIONALLY_ENCLOSED_BY = '\"' ESCAPE_UNENCLOSED_FIELD = NONE \
+         EMPTY_FIELD_AS_NULL = TRUE)\n\
+         PATTERN = '{}_[0-9]+[.]{}';\n",
+        quote(table),
+        columns.join(", "),
+        regex_literal(prefix),
+        EXTENSION.replace('.', "[.]"),
+    )
+}
+
+/// Escapes `text` to match itself in a `PATTERN`, inside a SQL string.
+fn regex_literal(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '\'' => escaped.push_str("''"),
+            c if "\\.+*?()|[]{}^$".contains(c) => {
+                escaped.push('[');
+                escaped.push(c);
+                escaped.push(']');
+            }
+            c => escaped.push(c),
+        }
+    }
+    format!(".*{escaped}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn writes_chunks_and_copy_statement() {
+        let dir = std::env::temp_dir().join(format!("snowflake-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let columns = vec!["id".to_string(), "path".to_string()];
+        let mut writer = StageWriter::new(&dir, "events", columns).rows_per_file(2);
+        for id in 0..3 {
+            let record: Record = vec![
+                ("id".into(), Some(json!(id))),
+                ("path".into(), Some(json!("C:\\tmp, \"x\""))),
+            ];
+            writer.write(&record).unwrap();
+        }
+
+        let statement = writer.copy_into("raw events", "landing");
+        let files = writer.finish().unwrap();
+        assert_eq!(files.len(), 2);
+        assert!(files[1].ends_with(format!("events_0001.{EXTENSION}")));
+        #[cfg(not(feature = "gzip"))]
+        assert_eq!(
+            std::fs::read_to_string(&files[1]).unwrap(),
+            "id,path\n2,\"C:\\tmp, \"\"x\"\"\"\n"
+        );
+        assert!(
+            statement.starts_with("COPY INTO \"raw events\" (\"id\", \"path\")\nFROM @landing/\n")
+        );
+        assert!(statement.contains("ESCAPE_UNENCLOSED_FIELD = NONE"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(regex_literal("a.b'c"), ".*a[.]b''c");
+    }
+}
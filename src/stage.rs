@@ -0,0 +1,1324 @@
+//! Stages applied around `SubSchema::extract`: document checks before it and
+//! record-level processing of its output.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{Map, Value};
+
+use crate::filter::{Filter, FilterError};
+use crate::{fnv_str, lookup, time, Limits, Record, FNV_OFFSET};
+
+/// Selects documents whose timestamp field falls in `since..until`, so
+/// partial backfills can skip out-of-range documents before extracting
+/// them.
+#[derive(Debug, Clone)]
+pub struct TimeRange {
+    /// Dot-separated path to the timestamp in the document.
+    field: String,
+    /// Inclusive lower bound, Unix seconds.
+    since: Option<i64>,
+    /// Exclusive upper bound, Unix seconds.
+    until: Option<i64>,
+}
+
+impl TimeRange {
+    pub fn new(field: &str, since: Option<i64>, until: Option<i64>) -> Self {
+        TimeRange {
+            field: field.to_string(),
+            since,
+            until,
+        }
+    }
+
+    /// Whether `document` is in range. Documents without a readable
+    /// timestamp (see `time::timestamp_of`) never are.
+    pub fn contains(&self, document: &Value) -> bool {
+        match lookup(document, &self.field).and_then(time::timestamp_of) {
+            Some(ts) => {
+                self.since.is_none_or(|since| ts >= since)
+                    && self.until.is_none_or(|until| ts < until)
+            }
+            None => false,
+        }
+    }
+}
+
+/// Drops records whose timestamp column is older than a retention window,
+/// so expired data never reaches new outputs. Records without a readable
+/// timestamp are kept.
+#[derive(Debug, Clone)]
+pub struct Retention {
+    column: String,
+    /// Records stamped before this, in Unix seconds, are expired.
+    cutoff: i64,
+    window: Duration,
+}
+
+impl Retention {
+    /// Retains `window` back from the current time.
+    pub fn new(column: &str, window: Duration) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        Retention {
+            column: column.to_string(),
+            cutoff: 0,
+            window,
+        }
+        .now(now)
+    }
+
+    /// Measures the window back from `now` (Unix seconds) instead, e.g. a
+    /// run's logical date for reproducible backfills.
+    pub fn now(mut self, now: i64) -> Self {
+        self.cutoff = now.saturating_sub(self.window.as_secs() as i64);
+        self
+    }
+
+    pub fn expired(&self, record: &Record) -> bool {
+        record
+            .iter()
+            .find(|(name, _)| name == &self.column)
+            .and_then(|(_, value)| value.as_ref())
+            .and_then(time::timestamp_of)
+            .is_some_and(|ts| ts < self.cutoff)
+    }
+
+    pub fn apply(&self, records: Vec<Record>) -> Vec<Record> {
+        records
+            .into_iter()
+            .filter(|record| !self.expired(record))
+            .collect()
+    }
+}
+
+/// Keeps a random fraction of records. The same seed always keeps the same
+/// records from the same input, so sampled datasets are reproducible.
+#[derive(Debug, Clone)]
+pub struct Sampler {
+    rate: f64,
+    state: u64,
+}
+
+impl Sampler {
+    /// `rate` is the fraction of records to keep, clamped to `0.0..=1.0`.
+    pub fn new(rate: f64, seed: u64) -> Self {
+        Sampler {
+            rate: rate.clamp(0.0, 1.0),
+            state: seed,
+        }
+    }
+
+    /// Decides whether to keep the next record.
+    pub fn keep(&mut self) -> bool {
+        ((splitmix64(&mut self.state) >> 11) as f64 / (1u64 << 53) as f64) < self.rate
+    }
+
+    pub fn sample(&mut self, records: Vec<Record>) -> Vec<Record> {
+        records.into_iter().filter(|_| self.keep()).collect()
+    }
+}
+
+/// splitmix64; only needs to be fast and stable, not cryptographic.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// What `Suppression` does with a record whose id is on the list.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SuppressMode {
+    Drop,
+    /// Keep the row but null out every column, including the id.
+    Redact,
+}
+
+/// Removes erased subjects (e.g. GDPR deletion requests) from extracted
+/// records by matching one column against a suppression list of ids.
+#[derive(Debug, Clone)]
+pub struct Suppression {
+    column: String,
+    ids: HashSet<String>,
+    mode: SuppressMode,
+}
+
+impl Suppression {
+    pub fn new<I>(column: &str, ids: I, mode: SuppressMode) -> Self
+    where
+        I: IntoIterator<Item = String>,
+    {
+        Suppression {
+            column: column.to_string(),
+            ids: ids.into_iter().collect(),
+            mode,
+        }
+    }
+
+    /// Reads one id per line, skipping blank lines and `#` comments.
+    pub fn from_file<P: AsRef<Path>>(
+        column: &str,
+        path: P,
+        mode: SuppressMode,
+    ) -> io::Result<Self> {
+        let ids = fs::read_to_string(path)?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        Ok(Suppression::new(column, ids, mode))
+    }
+
+    fn suppressed(&self, record: &Record) -> bool {
+        record.iter().any(|(name, value)| {
+            name == &self.column
+                && match value {
+                    Some(Value::String(id)) => self.ids.contains(id),
+                    Some(Value::Number(id)) => self.ids.contains(&id.to_string()),
+                    _ => false,
+                }
+        })
+    }
+
+    pub fn apply(&self, records: Vec<Record>) -> Vec<Record> {
+        match self.mode {
+            SuppressMode::Drop => records
+                .into_iter()
+                .filter(|record| !self.suppressed(record))
+                .collect(),
+            SuppressMode::Redact => records
+                .into_iter()
+                .map(|mut record| {
+                    if self.suppressed(&record) {
+                        for (_, value) in record.iter_mut() {
+                            *value = None;
+                        }
+                    }
+                    record
+                })
+                .collect(),
+        }
+    }
+}
+
+/// What `Guard` does with a value exceeding its column's `Limits`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    /// Cut strings to `max_length` characters, and null out values beyond
+    /// `max_cardinality`.
+    Truncate,
+    /// Set the whole record aside for a dead-letter sink.
+    DeadLetter,
+}
+
+/// Enforces the `Limits` keys declare (see `SubSchema::limits`), so values
+/// that would fail a warehouse load are handled before they reach a sink.
+/// Distinct values are counted across calls, so one `Guard` should see the
+/// whole run; `warnings` summarizes what it changed.
+#[derive(Debug, Clone)]
+pub struct Guard {
+    limits: HashMap<String, Limits>,
+    overflow: Overflow,
+    distinct: HashMap<String, HashSet<String>>,
+    /// Values over `max_length` and beyond `max_cardinality` per column.
+    exceeded: BTreeMap<String, (usize, usize)>,
+}
+
+impl Guard {
+    pub fn new(limits: HashMap<String, Limits>, overflow: Overflow) -> Self {
+        Guard {
+            limits,
+            overflow,
+            distinct: HashMap::new(),
+            exceeded: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the records to keep and, with `Overflow::DeadLetter`, the
+    /// records set aside. Distinct values are counted as they are written:
+    /// after truncation, and not at all for dead-lettered records.
+    pub fn apply(&mut self, records: Vec<Record>) -> (Vec<Record>, Vec<Record>) {
+        let truncate = self.overflow == Overflow::Truncate;
+        let mut kept = vec![];
+        let mut dead = vec![];
+        for mut record in records {
+            let mut fits = true;
+            let mut new = vec![];
+            for (column, value) in record.iter_mut() {
+                let (limits, v) = match (self.limits.get(column.as_str()), value.as_mut()) {
+                    (Some(limits), Some(v)) => (*limits, v),
+                    _ => continue,
+                };
+                let exceeded = self.exceeded.entry(column.clone()).or_default();
+                if let (Value::String(s), Some(max)) = (&mut *v, limits.max_length) {
+                    if s.chars().count() > max {
+                        exceeded.0 += 1;
+                        fits = false;
+                        if truncate {
+                            *s = s.chars().take(max).collect();
+                        }
+                    }
+                }
+                let max = match limits.max_cardinality {
+                    Some(max) if !v.is_null() => max,
+                    _ => continue,
+                };
+                let key = v.to_string();
+                let seen = self.distinct.entry(column.clone()).or_default();
+                if seen.contains(&key) {
+                    continue;
+                }
+                if seen.len() < max {
+                    new.push((column.clone(), key));
+                } else {
+                    exceeded.1 += 1;
+                    fits = false;
+                    if truncate {
+                        *value = Some(Value::Null);
+                    }
+                }
+            }
+            match self.overflow {
+                Overflow::DeadLetter if !fits => dead.push(record),
+                _ => {
+                    for (column, key) in new {
+                        self.distinct.entry(column).or_default().insert(key);
+                    }
+                    kept.push(record);
+                }
+            }
+        }
+        (kept, dead)
+    }
+
+    /// One line per column whose limits were exceeded so far.
+    pub fn warnings(&self) -> Vec<String> {
+        let action = match self.overflow {
+            Overflow::Truncate => "truncated",
+            Overflow::DeadLetter => "dead-lettered",
+        };
+        let mut warnings = vec![];
+        for (column, (long, excess)) in self.exceeded.iter() {
+            let limits = self.limits[column.as_str()];
+            if *long > 0 {
+                let max = limits.max_length.unwrap_or(0);
+                warnings.push(format!(
+                    "{column}: {long} values longer than {max} characters {action}"
+                ));
+            }
+            if *excess > 0 {
+                let max = limits.max_cardinality.unwrap_or(0);
+                warnings.push(format!(
+                    "{column}: {excess} values beyond {max} distinct values {action}"
+                ));
+            }
+        }
+        warnings
+    }
+}
+
+/// Splits records between output tables by the value of a discriminator
+/// column, so one pass over the input can feed several sinks.
+#[derive(Debug, Clone)]
+pub struct Router {
+    column: String,
+    routes: HashMap<String, String>,
+    default: Option<String>,
+}
+
+impl Router {
+    pub fn new(column: &str) -> Self {
+        Router {
+            column: column.to_string(),
+            routes: HashMap::new(),
+            default: None,
+        }
+    }
+
+    /// Sends records whose discriminator is `value` to `table`. Numbers and
+    /// booleans match their JSON text, e.g. `"2"` or `"true"`.
+    pub fn route(mut self, value: &str, table: &str) -> Self {
+        self.routes.insert(value.to_string(), table.to_string());
+        self
+    }
+
+    /// Table for records no route matches; without one they are dropped.
+    pub fn default_table(mut self, table: &str) -> Self {
+        self.default = Some(table.to_string());
+        self
+    }
+
+    /// Every table records can be sent to.
+    pub fn tables(&self) -> BTreeSet<&str> {
+        self.routes
+            .values()
+            .chain(self.default.as_ref())
+            .map(String::as_str)
+            .collect()
+    }
+
+    pub fn table(&self, record: &Record) -> Option<&str> {
+        let value = record
+            .iter()
+            .find(|(name, _)| name == &self.column)
+            .and_then(|(_, value)| match value {
+                Some(Value::String(s)) => Some(s.clone()),
+                Some(Value::Number(n)) => Some(n.to_string()),
+                Some(Value::Bool(b)) => Some(b.to_string()),
+                _ => None,
+            });
+        value
+            .and_then(|value| self.routes.get(&value))
+            .or(self.default.as_ref())
+            .map(String::as_str)
+    }
+
+    pub fn apply(&self, records: Vec<Record>) -> BTreeMap<String, Vec<Record>> {
+        let mut tables: BTreeMap<String, Vec<Record>> = BTreeMap::new();
+        for record in records {
+            if let Some(table) = self.table(&record) {
+                tables.entry(table.to_string()).or_default().push(record);
+            }
+        }
+        tables
+    }
+}
+
+/// Appends boolean columns computed from the extracted columns with
+/// `filter` expressions, e.g. `is_minor` from `age < 18` or `has_phone`
+/// from `phone_number != null`. Absent columns compare as `null`.
+#[derive(Debug, Clone, Default)]
+pub struct DerivedFlags {
+    flags: Vec<(String, Filter)>,
+}
+
+impl DerivedFlags {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn flag(mut self, column: &str, expression: &str) -> Result<Self, FilterError> {
+        self.flags.push((column.to_string(), expression.parse()?));
+        Ok(self)
+    }
+
+    /// The flag columns, in the order they are appended.
+    pub fn columns(&self) -> Vec<String> {
+        self.flags
+            .iter()
+            .map(|(column, _)| column.clone())
+            .collect()
+    }
+
+    pub fn apply(&self, records: Vec<Record>) -> Vec<Record> {
+        records
+            .into_iter()
+            .map(|mut record| {
+                let object: Map<String, Value> = record
+                    .iter()
+                    .filter_map(|(name, value)| Some((name.clone(), value.clone()?)))
+                    .collect();
+                let object = Value::Object(object);
+                for (column, filter) in self.flags.iter() {
+                    record.push((column.clone(), Some(Value::Bool(filter.matches(&object)))));
+                }
+                record
+            })
+            .collect()
+    }
+}
+
+impl FromIterator<(String, Filter)> for DerivedFlags {
+    fn from_iter<I: IntoIterator<Item = (String, Filter)>>(iter: I) -> Self {
+        DerivedFlags {
+            flags: iter.into_iter().collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum WindowOp {
+    Lag { column: String, output: String },
+    Lead { column: String, output: String },
+    RunningCount { output: String },
+    TimeSincePrevious { column: String, output: String },
+}
+
+/// Windowed computations over an ordered stream of records, partitioned by
+/// a key column: the previous or next value of a column, a running count
+/// and the seconds since the previous record. Partitions carry over between
+/// calls, so documents can be fed one at a time in order.
+///
+/// With a `lead`, each partition's latest record is held back until the
+/// next one arrives, so `apply` emits records late and out of input order;
+/// `finish` emits the held records with a null lead.
+#[derive(Debug, Clone)]
+pub struct Window {
+    partition: String,
+    ops: Vec<WindowOp>,
+    counts: HashMap<String, usize>,
+    previous: HashMap<String, Record>,
+    pending: BTreeMap<String, Record>,
+}
+
+fn column<'r>(record: &'r Record, name: &str) -> Option<&'r Value> {
+    record
+        .iter()
+        .find(|(column, _)| column == name)
+        .and_then(|(_, value)| value.as_ref())
+}
+
+impl Window {
+    pub fn new(partition: &str) -> Self {
+        Window {
+            partition: partition.to_string(),
+            ops: vec![],
+            counts: HashMap::new(),
+            previous: HashMap::new(),
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Appends `output` with the partition's previous value of `column`.
+    pub fn lag(mut self, column: &str, output: &str) -> Self {
+        self.ops.push(WindowOp::Lag {
+            column: column.to_string(),
+            output: output.to_string(),
+        });
+        self
+    }
+
+    /// Appends `output` with the partition's next value of `column`.
+    pub fn lead(mut self, column: &str, output: &str) -> Self {
+        self.ops.push(WindowOp::Lead {
+            column: column.to_string(),
+            output: output.to_string(),
+        });
+        self
+    }
+
+    /// Appends `output` with the record's 1-based position in its partition.
+    pub fn running_count(mut self, output: &str) -> Self {
+        self.ops.push(WindowOp::RunningCount {
+            output: output.to_string(),
+        });
+        self
+    }
+
+    /// Appends `output` with the seconds between the partition's previous
+    /// timestamp in `column` and this one (see `time::timestamp_of`).
+    pub fn time_since_previous(mut self, column: &str, output: &str) -> Self {
+        self.ops.push(WindowOp::TimeSincePrevious {
+            column: column.to_string(),
+            output: output.to_string(),
+        });
+        self
+    }
+
+    pub fn apply(&mut self, records: Vec<Record>) -> Vec<Record> {
+        let mut emitted = vec![];
+        for record in records {
+            let key = column(&record, &self.partition)
+                .cloned()
+                .unwrap_or(Value::Null)
+                .to_string();
+            let count = self.counts.entry(key.clone()).or_default();
+            *count += 1;
+            let count = *count;
+            let previous = self.previous.insert(key.clone(), record.clone());
+
+            let mut output = record.clone();
+            for op in self.ops.iter() {
+                output.push(match op {
+                    WindowOp::Lag {
+                        column: lagged,
+                        output,
+                    } => (
+                        output.clone(),
+                        previous.as_ref().and_then(|p| column(p, lagged)).cloned(),
+                    ),
+                    WindowOp::Lead { output, .. } => (output.clone(), None),
+                    WindowOp::RunningCount { output } => (output.clone(), Some(count.into())),
+                    WindowOp::TimeSincePrevious { column: ts, output } => {
+                        let since = |r: &Record| column(r, ts).and_then(time::timestamp_of);
+                        let elapsed = match (previous.as_ref().and_then(since), since(&record)) {
+                            (Some(before), Some(now)) => Some((now - before).into()),
+                            _ => None,
+                        };
+                        (output.clone(), elapsed)
+                    }
+                });
+            }
+
+            if !self
+                .ops
+                .iter()
+                .any(|op| matches!(op, WindowOp::Lead { .. }))
+            {
+                emitted.push(output);
+                continue;
+            }
+            if let Some(mut held) = self.pending.insert(key, output) {
+                for op in self.ops.iter() {
+                    if let WindowOp::Lead {
+                        column: led,
+                        output,
+                    } = op
+                    {
+                        let next = column(&record, led).cloned();
+                        if let Some(slot) = held.iter_mut().rev().find(|(name, _)| name == output) {
+                            slot.1 = next;
+                        }
+                    }
+                }
+                emitted.push(held);
+            }
+        }
+        emitted
+    }
+
+    /// Emits records held back for a `lead`, ordered by partition.
+    pub fn finish(&mut self) -> Vec<Record> {
+        std::mem::take(&mut self.pending).into_values().collect()
+    }
+}
+
+/// Keeps the first record seen for each natural key, usually
+/// `SubSchema::natural_key_columns()`. With no key columns, whole records are
+/// compared. State carries over between calls, so it deduplicates across
+/// documents.
+#[derive(Debug, Clone, Default)]
+pub struct Dedupe {
+    columns: Vec<String>,
+    seen: HashSet<String>,
+}
+
+impl Dedupe {
+    pub fn new(columns: Vec<String>) -> Self {
+        Dedupe {
+            columns,
+            seen: HashSet::new(),
+        }
+    }
+
+    fn key(&self, record: &Record) -> String {
+        let values: Vec<Value> = if self.columns.is_empty() {
+            record
+                .iter()
+                .map(|(_, value)| value.clone().unwrap_or(Value::Null))
+                .collect()
+        } else {
+            self.columns
+                .iter()
+                .map(|column| {
+                    record
+                        .iter()
+                        .find(|(name, _)| name == column)
+                        .and_then(|(_, value)| value.clone())
+                        .unwrap_or(Value::Null)
+                })
+                .collect()
+        };
+        Value::Array(values).to_string()
+    }
+
+    pub fn apply(&mut self, records: Vec<Record>) -> Vec<Record> {
+        records
+            .into_iter()
+            .filter(|record| {
+                let key = self.key(record);
+                self.seen.insert(key)
+            })
+            .collect()
+    }
+}
+
+/// Appends a hash of each record's values as 16 hex digits, so merge jobs
+/// can detect changed rows by comparing one column. The hash covers
+/// `columns` in the given order, or with none, every column the record has
+/// in record order; it covers names as well as values, and a missing
+/// column hashes like `null`. It is stable across runs and Rust releases.
+#[derive(Debug, Clone)]
+pub struct RowHash {
+    column: String,
+    columns: Vec<String>,
+}
+
+impl RowHash {
+    pub fn new(column: &str, columns: Vec<String>) -> Self {
+        RowHash {
+            column: column.to_string(),
+            columns,
+        }
+    }
+
+    fn hash(&self, record: &Record) -> String {
+        let value = |name: &str| {
+            record
+                .iter()
+                .find(|(column, _)| column == name)
+                .and_then(|(_, value)| value.as_ref())
+                .unwrap_or(&Value::Null)
+                .to_string()
+        };
+        let mut hash = FNV_OFFSET;
+        if self.columns.is_empty() {
+            for (name, _) in record.iter().filter(|(name, _)| *name != self.column) {
+                fnv_str(&mut hash, name);
+                fnv_str(&mut hash, &value(name));
+            }
+        } else {
+            for name in self.columns.iter() {
+                fnv_str(&mut hash, name);
+                fnv_str(&mut hash, &value(name));
+            }
+        }
+        format!("{hash:016x}")
+    }
+
+    pub fn apply(&self, records: Vec<Record>) -> Vec<Record> {
+        records
+            .into_iter()
+            .map(|mut record| {
+                let hash = self.hash(&record);
+                record.push((self.column.clone(), Some(Value::String(hash))));
+                record
+            })
+            .collect()
+    }
+}
+
+/// How `RowId` numbers records.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RowIdKind {
+    /// Time-ordered UUIDs (RFC 9562 version 7). The random bits are
+    /// drawn once per run and a counter is added to them, so ids never
+    /// repeat within a run.
+    UuidV7,
+    /// `document-record` positions in the source, e.g. `41-0`, which are
+    /// the same every time the same input is processed.
+    Offset,
+}
+
+/// Appends a surrogate key column unique within the run, for stages and
+/// sinks that need one when the documents have no natural key. Callers
+/// pass each record's position in the source: the index of its document
+/// in the input, and its index among the records `extract` returned for
+/// that document, so `Offset` ids survive records dropped along the way.
+#[derive(Debug, Clone)]
+pub struct RowId {
+    column: String,
+    kind: RowIdKind,
+    count: u64,
+    random: u64,
+    millis: u64,
+}
+
+impl RowId {
+    pub fn new(column: &str, kind: RowIdKind) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut seed = now.as_nanos() as u64 ^ (std::process::id() as u64).rotate_left(32);
+        RowId {
+            column: column.to_string(),
+            kind,
+            count: 0,
+            random: splitmix64(&mut seed),
+            millis: 0,
+        }
+    }
+
+    fn uuid_v7(&mut self) -> String {
+        // Never step back if the clock does, so ids stay time-ordered.
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.millis = self.millis.max(now);
+
+        let rand_a = (self.random >> 52) & 0xfff;
+        let rand_b = self.random.wrapping_add(self.count) & 0x3fff_ffff_ffff_ffff;
+        self.count += 1;
+        let high = (self.millis & 0xffff_ffff_ffff) << 16 | 0x7000 | rand_a;
+        let low = 0x8000_0000_0000_0000 | rand_b;
+        format!(
+            "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+            high >> 32,
+            (high >> 16) & 0xffff,
+            high & 0xffff,
+            low >> 48,
+            low & 0xffff_ffff_ffff
+        )
+    }
+
+    /// Adds the id column to the `index`th record of the `document`th
+    /// document.
+    pub fn append(&mut self, document: u64, index: usize, record: &mut Record) {
+        let id = match self.kind {
+            RowIdKind::UuidV7 => self.uuid_v7(),
+            RowIdKind::Offset => format!("{document}-{index}"),
+        };
+        record.push((self.column.clone(), Some(Value::String(id))));
+    }
+
+    /// Adds the id column to every record `extract` returned for the
+    /// `document`th document.
+    pub fn apply(&mut self, document: u64, mut records: Vec<Record>) -> Vec<Record> {
+        for (index, record) in records.iter_mut().enumerate() {
+            self.append(document, index, record);
+        }
+        records
+    }
+}
+
+/// Reshapes records into sparse entity-attribute-value triples, one
+/// `(row_id, column, value)` record per non-null value, for key/value sinks
+/// that prefer them over wide, mostly empty rows. The row id is read from a
+/// column of the record, usually one added by `RowId`.
+#[derive(Debug, Clone)]
+pub struct Eav {
+    row_id: String,
+}
+
+impl Eav {
+    pub fn new(row_id: &str) -> Self {
+        Eav {
+            row_id: row_id.to_string(),
+        }
+    }
+
+    /// The columns of the triples: the row id column, `column` and `value`.
+    pub fn columns(&self) -> Vec<String> {
+        vec![
+            self.row_id.clone(),
+            "column".to_string(),
+            "value".to_string(),
+        ]
+    }
+
+    pub fn apply(&self, records: Vec<Record>) -> Vec<Record> {
+        let mut triples = vec![];
+        for record in records {
+            let row_id = record
+                .iter()
+                .find(|(name, _)| *name == self.row_id)
+                .and_then(|(_, value)| value.clone());
+            for (name, value) in record {
+                let value = match value {
+                    Some(value) if !value.is_null() && name != self.row_id => value,
+                    _ => continue,
+                };
+                triples.push(vec![
+                    (self.row_id.clone(), row_id.clone()),
+                    ("column".to_string(), Some(Value::String(name))),
+                    ("value".to_string(), Some(value)),
+                ]);
+            }
+        }
+        triples
+    }
+}
+
+/// Exchange rates over time against one base currency, for `Conversion`.
+#[derive(Debug, Clone)]
+pub struct Rates {
+    base: String,
+    /// Units of each currency per unit of `base`, by day (Unix seconds),
+    /// sorted.
+    rates: HashMap<String, Vec<(i64, f64)>>,
+}
+
+impl Rates {
+    pub fn new(base: &str) -> Self {
+        Rates {
+            base: base.to_string(),
+            rates: HashMap::new(),
+        }
+    }
+
+    /// Adds the rate of `currency` from `date` (Unix seconds) on, in units
+    /// per unit of the base currency.
+    pub fn insert(&mut self, currency: &str, date: i64, rate: f64) {
+        let rates = self.rates.entry(currency.to_string()).or_default();
+        let at = rates.partition_point(|(day, _)| *day < date);
+        match rates.get_mut(at) {
+            Some(entry) if entry.0 == date => entry.1 = rate,
+            _ => rates.insert(at, (date, rate)),
+        }
+    }
+
+    /// Reads `date,currency,rate` lines, e.g. `2024-05-02,USD,1.0702` for
+    /// ECB reference rates against EUR. Blank lines, `#` comments and a
+    /// `date,currency,rate` header are skipped; a malformed line is an
+    /// `InvalidData` error naming it.
+    pub fn from_file<P: AsRef<Path>>(base: &str, path: P) -> io::Result<Self> {
+        let mut rates = Rates::new(base);
+        for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with("date,") {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let parsed = match fields[..] {
+                [date, currency, rate] => time::parse_timestamp(date)
+                    .zip(rate.parse::<f64>().ok().filter(|rate| *rate > 0.0))
+                    .map(|(date, rate)| (currency, date, rate)),
+                _ => None,
+            };
+            match parsed {
+                Some((currency, date, rate)) => rates.insert(currency, date, rate),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line {}: expected `date,currency,rate`", i + 1),
+                    ))
+                }
+            }
+        }
+        Ok(rates)
+    }
+
+    /// Units of `to` per unit of `from` at `date` (Unix seconds), from each
+    /// currency's latest rate on or before it.
+    pub fn rate(&self, from: &str, to: &str, date: i64) -> Option<f64> {
+        let per_base = |currency: &str| -> Option<f64> {
+            if currency == self.base {
+                return Some(1.0);
+            }
+            let rates = self.rates.get(currency)?;
+            let at = rates.partition_point(|(day, _)| *day <= date);
+            Some(rates.get(at.checked_sub(1)?)?.1)
+        };
+        Some(per_base(to)? / per_base(from)?)
+    }
+}
+
+/// Converts amounts into one currency at the rate on each record's date,
+/// adding the converted amount and the rate used as columns, `{amount}_{to}`
+/// (lowercase) and `{amount}_rate` unless renamed with `columns`.
+///
+/// Both are missing when the amount is; they are null when the amount
+/// isn't a number (or numeric string) or there is no rate for its currency
+/// and date.
+#[derive(Debug, Clone)]
+pub struct Conversion {
+    rates: Rates,
+    to: String,
+    amount: String,
+    currency: String,
+    date: String,
+    converted: String,
+    rate: String,
+}
+
+impl Conversion {
+    /// Converts the `amount` column into `to`, reading its currency code
+    /// and date (see `time::timestamp_of`) from the `currency` and `date`
+    /// columns.
+    pub fn new(rates: Rates, to: &str, amount: &str, currency: &str, date: &str) -> Self {
+        Conversion {
+            rates,
+            to: to.to_string(),
+            amount: amount.to_string(),
+            currency: currency.to_string(),
+            date: date.to_string(),
+            converted: format!("{amount}_{}", to.to_lowercase()),
+            rate: format!("{amount}_rate"),
+        }
+    }
+
+    /// Names the converted amount and rate columns.
+    pub fn columns(mut self, converted: &str, rate: &str) -> Self {
+        self.converted = converted.to_string();
+        self.rate = rate.to_string();
+        self
+    }
+
+    fn convert(&self, record: &Record) -> Option<(Value, Value)> {
+        let get = |column: &str| {
+            record
+                .iter()
+                .find(|(name, _)| name == column)
+                .and_then(|(_, value)| value.as_ref())
+        };
+        let amount = get(&self.amount)?;
+        let converted = (|| {
+            let amount = match amount {
+                Value::String(s) => s.trim().parse::<f64>().ok()?,
+                other => other.as_f64()?,
+            };
+            let date = time::timestamp_of(get(&self.date)?)?;
+            let rate = self
+                .rates
+                .rate(get(&self.currency)?.as_str()?, &self.to, date)?;
+            Some((Value::from(amount * rate), Value::from(rate)))
+        })();
+        Some(converted.unwrap_or((Value::Null, Value::Null)))
+    }
+
+    pub fn apply(&self, records: Vec<Record>) -> Vec<Record> {
+        records
+            .into_iter()
+            .map(|mut record| {
+                let (converted, rate) = self.convert(&record).unzip();
+                record.retain(|(name, _)| *name != self.converted && *name != self.rate);
+                record.push((self.converted.clone(), converted));
+                record.push((self.rate.clone(), rate));
+                record
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn records(n: usize) -> Vec<Record> {
+        (0..n)
+            .map(|i| vec![("n".to_string(), Some(i.into()))])
+            .collect()
+    }
+
+    #[test]
+    fn sample_is_reproducible() {
+        let first = Sampler::new(0.1, 42).sample(records(10_000));
+        let second = Sampler::new(0.1, 42).sample(records(10_000));
+        let other = Sampler::new(0.1, 7).sample(records(10_000));
+
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+        assert!((900..1100).contains(&first.len()), "{}", first.len());
+    }
+
+    #[test]
+    fn sample_edges() {
+        assert!(Sampler::new(0.0, 1).sample(records(100)).is_empty());
+        assert_eq!(Sampler::new(1.0, 1).sample(records(100)).len(), 100);
+    }
+
+    #[test]
+    fn suppression_drops_and_redacts() {
+        let ids = vec!["2".to_string(), "u-3".to_string()];
+        let records: Vec<Record> = vec![
+            vec![
+                ("id".into(), Some(1.into())),
+                ("name".into(), Some("a".into())),
+            ],
+            vec![
+                ("id".into(), Some(2.into())),
+                ("name".into(), Some("b".into())),
+            ],
+            vec![
+                ("id".into(), Some("u-3".into())),
+                ("name".into(), Some("c".into())),
+            ],
+        ];
+
+        let dropped =
+            Suppression::new("id", ids.clone(), SuppressMode::Drop).apply(records.clone());
+        assert_eq!(dropped, records[..1].to_vec());
+
+        let redacted = Suppression::new("id", ids, SuppressMode::Redact).apply(records.clone());
+        assert_eq!(redacted[0], records[0]);
+        assert_eq!(
+            redacted[1],
+            vec![("id".into(), None), ("name".into(), None)]
+        );
+        assert_eq!(redacted.len(), 3);
+    }
+
+    #[test]
+    fn time_range_bounds() {
+        let range = TimeRange::new(
+            "meta.ts",
+            time::parse_timestamp("2024-05-01"),
+            time::parse_timestamp("2024-06-01"),
+        );
+        let doc = |ts: Value| serde_json::json!({"meta": {"ts": ts}});
+
+        assert!(range.contains(&doc("2024-05-01T00:00:00Z".into())));
+        assert!(range.contains(&doc(1715000000.into())));
+        assert!(!range.contains(&doc("2024-06-01".into())));
+        assert!(!range.contains(&doc("not a date".into())));
+        assert!(!range.contains(&serde_json::json!({})));
+        assert!(TimeRange::new("meta.ts", None, None).contains(&doc(0.into())));
+    }
+
+    #[test]
+    fn retention_drops_expired_records() {
+        let now = time::parse_timestamp("2024-05-31").unwrap();
+        let retention = Retention::new("at", Duration::from_secs(30 * 86400)).now(now);
+        let records: Vec<Record> = ["2024-05-01", "2024-04-30T23:59:59Z", "?"]
+            .iter()
+            .map(|at| vec![("at".to_string(), Some((*at).into()))])
+            .collect();
+
+        let kept = retention.apply(records);
+        assert_eq!(kept.len(), 2);
+        assert_eq!(kept[0][0].1, Some("2024-05-01".into()));
+    }
+
+    #[test]
+    fn derived_flags() {
+        let flags = DerivedFlags::new()
+            .flag("is_minor", "age < 18")
+            .unwrap()
+            .flag("has_phone", "phone_number != null")
+            .unwrap();
+        let records: Vec<Record> = vec![vec![
+            ("age".to_string(), Some(12.into())),
+            ("phone_number".to_string(), None),
+        ]];
+
+        let flagged = flags.apply(records);
+        assert_eq!(flagged[0][2], ("is_minor".to_string(), Some(true.into())));
+        assert_eq!(flagged[0][3], ("has_phone".to_string(), Some(false.into())));
+
+        assert!(DerivedFlags::new().flag("bad", "a ==").is_err());
+    }
+
+    #[test]
+    fn window_computes_per_partition() {
+        let event = |user: &str, at: &str| -> Record {
+            vec![
+                ("user".to_string(), Some(user.into())),
+                ("at".to_string(), Some(at.into())),
+            ]
+        };
+        let mut window = Window::new("user")
+            .lag("at", "previous_at")
+            .running_count("n")
+            .time_since_previous("at", "gap");
+
+        let records = window.apply(vec![
+            event("a", "2024-05-01T00:00:00Z"),
+            event("b", "2024-05-01T00:00:10Z"),
+            event("a", "2024-05-01T00:01:00Z"),
+        ]);
+        assert_eq!(records[2][2].1, Some("2024-05-01T00:00:00Z".into()));
+        assert_eq!(records[2][3].1, Some(2.into()));
+        assert_eq!(records[2][4].1, Some(60.into()));
+        assert_eq!(records[1][4].1, None);
+
+        let mut window = Window::new("user").lead("at", "next_at");
+        let emitted = window.apply(vec![event("a", "1"), event("a", "2"), event("b", "3")]);
+        assert_eq!(
+            emitted,
+            vec![vec![
+                ("user".to_string(), Some("a".into())),
+                ("at".to_string(), Some("1".into())),
+                ("next_at".to_string(), Some("2".into())),
+            ]]
+        );
+        let rest = window.finish();
+        assert_eq!(rest.len(), 2);
+        assert_eq!(rest[0][2].1, None);
+    }
+
+    #[test]
+    fn router_splits_by_discriminator() {
+        let records: Vec<Record> = ["click", "view", "buy", "click"]
+            .iter()
+            .map(|kind| vec![("kind".to_string(), Some((*kind).into()))])
+            .collect();
+        let router = Router::new("kind")
+            .route("click", "clicks")
+            .route("view", "views");
+
+        let tables = router.apply(records.clone());
+        assert_eq!(tables["clicks"].len(), 2);
+        assert_eq!(tables["views"].len(), 1);
+        assert_eq!(tables.len(), 2);
+
+        assert_eq!(router.tables(), BTreeSet::from(["clicks", "views"]));
+        let router = router.default_table("other");
+        assert_eq!(router.tables().len(), 3);
+        let tables = router.apply(records);
+        assert_eq!(
+            tables["other"],
+            vec![vec![("kind".to_string(), Some("buy".into()))]]
+        );
+    }
+
+    #[test]
+    fn row_hash_covers_selected_columns() {
+        let record = |name: &str, seen: i64| -> Record {
+            vec![
+                ("id".into(), Some(1.into())),
+                ("name".into(), Some(name.into())),
+                ("seen".into(), Some(seen.into())),
+            ]
+        };
+        let hash = |stage: &RowHash, record: Record| {
+            let records = stage.apply(vec![record]);
+            records[0].last().cloned().unwrap().1.unwrap()
+        };
+
+        let all = RowHash::new("row_hash", vec![]);
+        assert_eq!(hash(&all, record("a", 1)), hash(&all, record("a", 1)));
+        assert_ne!(hash(&all, record("a", 1)), hash(&all, record("a", 2)));
+
+        let selected = RowHash::new("row_hash", vec!["id".into(), "name".into()]);
+        assert_eq!(
+            hash(&selected, record("a", 1)),
+            hash(&selected, record("a", 2))
+        );
+        assert_ne!(
+            hash(&selected, record("a", 1)),
+            hash(&selected, record("b", 1))
+        );
+        assert_eq!(hash(&selected, record("a", 1)).as_str().unwrap().len(), 16);
+    }
+
+    #[test]
+    fn row_ids_are_unique() {
+        let id = |record: &Record| record.last().cloned().unwrap().1.unwrap();
+
+        let mut uuids = RowId::new("row_id", RowIdKind::UuidV7);
+        let ids: HashSet<String> = uuids
+            .apply(0, records(1000))
+            .iter()
+            .map(|r| id(r).to_string())
+            .collect();
+        assert_eq!(ids.len(), 1000);
+        let first = uuids.apply(1, records(1));
+        let first = id(&first[0]);
+        let first = first.as_str().unwrap();
+        assert_eq!(first.len(), 36);
+        assert_eq!(&first[14..15], "7");
+        assert!("89ab".contains(&first[19..20]));
+
+        let mut offsets = RowId::new("row_id", RowIdKind::Offset);
+        let second = offsets.apply(41, records(2));
+        assert_eq!(id(&second[1]), json!("41-1"));
+        let mut record = records(1).remove(0);
+        offsets.append(7, 3, &mut record);
+        assert_eq!(id(&record), json!("7-3"));
+    }
+
+    #[test]
+    fn guard_truncates_or_dead_letters() {
+        let limits = HashMap::from([(
+            "code".to_string(),
+            Limits {
+                max_length: Some(3),
+                max_cardinality: Some(2),
+            },
+        )]);
+        let records = || -> Vec<Record> {
+            ["ab", "abcd", "ab", "abce", "xy"]
+                .into_iter()
+                .map(|code| vec![("code".to_string(), Some(json!(code)))])
+                .collect()
+        };
+
+        let mut guard = Guard::new(limits.clone(), Overflow::Truncate);
+        let (kept, dead) = guard.apply(records());
+        let codes: Vec<Option<Value>> = kept.into_iter().map(|r| r[0].1.clone()).collect();
+        assert_eq!(
+            codes,
+            vec![
+                Some(json!("ab")),
+                Some(json!("abc")),
+                Some(json!("ab")),
+                Some(json!("abc")),
+                Some(Value::Null)
+            ]
+        );
+        assert!(dead.is_empty());
+        assert_eq!(
+            guard.warnings(),
+            vec![
+                "code: 2 values longer than 3 characters truncated",
+                "code: 1 values beyond 2 distinct values truncated"
+            ]
+        );
+
+        // Dead-lettered values don't take up distinct slots, so "xy" fits.
+        let mut guard = Guard::new(limits, Overflow::DeadLetter);
+        let (kept, dead) = guard.apply(records());
+        assert_eq!((kept.len(), dead.len()), (3, 2));
+    }
+
+    #[test]
+    fn eav_emits_non_null_triples() {
+        let records: Vec<Record> = vec![vec![
+            ("row_id".into(), Some(json!("0-0"))),
+            ("a".into(), Some(json!(1))),
+            ("b".into(), None),
+            ("c".into(), Some(Value::Null)),
+            ("d".into(), Some(json!("x"))),
+        ]];
+        let eav = Eav::new("row_id");
+
+        assert_eq!(eav.columns(), vec!["row_id", "column", "value"]);
+        assert_eq!(
+            eav.apply(records),
+            vec![
+                vec![
+                    ("row_id".to_string(), Some(json!("0-0"))),
+                    ("column".to_string(), Some(json!("a"))),
+                    ("value".to_string(), Some(json!(1))),
+                ],
+                vec![
+                    ("row_id".to_string(), Some(json!("0-0"))),
+                    ("column".to_string(), Some(json!("d"))),
+                    ("value".to_string(), Some(json!("x"))),
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn conversion_uses_rate_on_date() {
+        let path = std::env::temp_dir().join(format!("rates-test-{}.csv", std::process::id()));
+        fs::write(
+            &path,
+            "date,currency,rate\n2024-05-01,USD,1.1\n2024-05-03,USD,1.2\n2024-05-01,GBP,0.8\n",
+        )
+        .unwrap();
+        let rates = Rates::from_file("EUR", &path).unwrap();
+        fs::write(&path, "2024-05-01,USD\n").unwrap();
+        assert!(Rates::from_file("EUR", &path).is_err());
+        fs::remove_file(&path).unwrap();
+
+        let record = |amount: Option<Value>, currency: &str, date: &str| -> Record {
+            vec![
+                ("amount".into(), amount),
+                ("currency".into(), Some(json!(currency))),
+                ("date".into(), Some(json!(date))),
+            ]
+        };
+        let conversion = Conversion::new(rates, "EUR", "amount", "currency", "date");
+        let converted = conversion.apply(vec![
+            record(Some(json!(11)), "USD", "2024-05-02"),
+            record(Some(json!("12")), "USD", "2024-05-03T10:00:00Z"),
+            record(Some(json!(10)), "EUR", "2024-05-02"),
+            record(Some(json!(1)), "USD", "2024-04-30"),
+            record(None, "USD", "2024-05-02"),
+        ]);
+        let columns: Vec<_> = converted.iter().map(|record| &record[3..]).collect();
+
+        assert_eq!(
+            columns[0],
+            [
+                ("amount_eur".to_string(), Some(json!(10.0))),
+                ("amount_rate".to_string(), Some(json!(1.0 / 1.1))),
+            ]
+        );
+        assert_eq!(columns[1][0].1, Some(json!(10.0)));
+        assert_eq!(columns[2][1].1, Some(json!(1.0)));
+        assert_eq!(columns[3][0].1, Some(Value::Null));
+        assert_eq!(columns[4][0].1, None);
+    }
+}
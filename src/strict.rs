@@ -0,0 +1,149 @@
+//! Strict-mode checks on raw JSON text, for problems `serde_json` resolves
+//! silently while parsing.
+
+use std::collections::HashSet;
+
+use serde_json::Value;
+
+use crate::escape_pointer;
+
+/// JSON Pointers of keys repeated within the same object, in the order they
+/// repeat. `serde_json` keeps the last value of a repeated key, which can
+/// hide upstream bugs.
+///
+/// Keys are compared after unescaping, so `"a"` and `"\u0061"` are the same
+/// key. Text that isn't valid JSON is only scanned up to the first error;
+/// parsing it reports what the error is.
+pub fn duplicate_keys(json: &str) -> Vec<String> {
+    let mut scanner = Scanner {
+        json,
+        pos: 0,
+        duplicates: vec![],
+    };
+    scanner.value("");
+    scanner.duplicates
+}
+
+struct Scanner<'j> {
+    json: &'j str,
+    pos: usize,
+    duplicates: Vec<String>,
+}
+
+impl<'j> Scanner<'j> {
+    /// The next byte that isn't whitespace, without consuming it.
+    fn peek(&mut self) -> Option<u8> {
+        let bytes = self.json.as_bytes();
+        while bytes.get(self.pos)?.is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        bytes.get(self.pos).copied()
+    }
+
+    fn value(&mut self, path: &str) -> Option<()> {
+        match self.peek()? {
+            b'{' => {
+                self.pos += 1;
+                let mut keys = HashSet::new();
+                if self.peek()? == b'}' {
+                    self.pos += 1;
+                    return Some(());
+                }
+                loop {
+                    if self.peek()? != b'"' {
+                        return None;
+                    }
+                    let key = self.string()?;
+                    let path = format!("{path}/{}", escape_pointer(&key));
+                    if !keys.insert(key) {
+                        self.duplicates.push(path.clone());
+                    }
+                    if self.peek()? != b':' {
+                        return None;
+                    }
+                    self.pos += 1;
+                    self.value(&path)?;
+                    match self.peek()? {
+                        b',' => self.pos += 1,
+                        b'}' => {
+                            self.pos += 1;
+                            return Some(());
+                        }
+                        _ => return None,
+                    }
+                }
+            }
+            b'[' => {
+                self.pos += 1;
+                if self.peek()? == b']' {
+                    self.pos += 1;
+                    return Some(());
+                }
+                for index in 0.. {
+                    self.value(&format!("{path}/{index}"))?;
+                    match self.peek()? {
+                        b',' => self.pos += 1,
+                        b']' => {
+                            self.pos += 1;
+                            break;
+                        }
+                        _ => return None,
+                    }
+                }
+                Some(())
+            }
+            b'"' => self.string().map(|_| ()),
+            _ => {
+                let start = self.pos;
+                let bytes = self.json.as_bytes();
+                while bytes
+                    .get(self.pos)
+                    .is_some_and(|b| !b",]} \t\n\r".contains(b))
+                {
+                    self.pos += 1;
+                }
+                (self.pos > start).then_some(())
+            }
+        }
+    }
+
+    /// Consumes a string literal and returns its unescaped content.
+    fn string(&mut self) -> Option<String> {
+        let bytes = self.json.as_bytes();
+        let start = self.pos;
+        self.pos += 1;
+        loop {
+            match bytes.get(self.pos)? {
+                b'"' => break,
+                b'\\' => self.pos += 2,
+                _ => self.pos += 1,
+            }
+        }
+        self.pos += 1;
+
+        let literal = &self.json[start..self.pos];
+        if !literal.contains('\\') {
+            return Some(literal[1..literal.len() - 1].to_string());
+        }
+        match serde_json::from_str(literal).ok()? {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn finds_duplicate_keys() {
+        let json = r#"{"a": 1, "b": {"c": 1, "c": 2}, "a": 3, "l": [{"x": 1, "\u0078": 2}]}"#;
+        assert_eq!(duplicate_keys(json), vec!["/b/c", "/a", "/l/0/x"]);
+        assert!(duplicate_keys(r#"{"a": {"a": [1, "a", {}]}, "b": null}"#).is_empty());
+        assert_eq!(
+            duplicate_keys(r#"{"a/b": 1, "a/b": 2, "x": "#),
+            vec!["/a~1b"]
+        );
+    }
+}
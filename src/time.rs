@@ -0,0 +1,176 @@
+//! Just enough calendar arithmetic to read and write UTC timestamps.
+
+use serde_json::Value;
+
+/// Parses an RFC 3339 / ISO 8601 timestamp into Unix seconds (UTC).
+///
+/// Accepts a bare date (`2024-05-01`), a `T` or space separator, optional
+/// fractional seconds (truncated) and a `Z` or `±HH:MM` offset. A timestamp
+/// without an offset is taken as UTC. Dates and times that don't exist,
+/// such as `2023-02-29` or `24:00:00`, are rejected, leap seconds included.
+pub fn parse_timestamp(s: &str) -> Option<i64> {
+    let s = s.trim();
+    let number = |range: std::ops::Range<usize>| -> Option<i64> {
+        let part = s.get(range)?;
+        if part.bytes().all(|b| b.is_ascii_digit()) {
+            part.parse().ok()
+        } else {
+            None
+        }
+    };
+
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    if s.get(4..5)? != "-" || s.get(7..8)? != "-" || !(1..=12).contains(&month) {
+        return None;
+    }
+    if !(1..=days_in_month(year, month)).contains(&day) {
+        return None;
+    }
+    let mut secs = days_from_civil(year, month, day) * 86400;
+
+    let rest = &s[10..];
+    if rest.is_empty() {
+        return Some(secs);
+    }
+    if !rest.starts_with(['T', 't', ' ']) || rest.get(3..4)? != ":" || rest.get(6..7)? != ":" {
+        return None;
+    }
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    if hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    secs += hour * 3600 + minute * 60 + second;
+
+    let mut offset = &s[19..];
+    if let Some(fraction) = offset.strip_prefix('.') {
+        let digits = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        offset = &fraction[digits..];
+    }
+    match offset {
+        "" | "Z" | "z" => Some(secs),
+        _ => {
+            let sign = match offset.get(0..1)? {
+                "+" => 1,
+                "-" => -1,
+                _ => return None,
+            };
+            let hours: i64 = offset.get(1..3)?.parse().ok()?;
+            let minutes: i64 = offset.get(4..6)?.parse().ok()?;
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            Some(secs - sign * (hours * 3600 + minutes * 60))
+        }
+    }
+}
+
+/// Reads a timestamp from a JSON value: a string is parsed with
+/// `parse_timestamp`, a number is taken as Unix seconds.
+pub fn timestamp_of(value: &Value) -> Option<i64> {
+    match value {
+        Value::String(s) => parse_timestamp(s),
+        Value::Number(n) => n.as_i64().or_else(|| n.as_f64().map(|f| f as i64)),
+        _ => None,
+    }
+}
+
+/// Formats Unix seconds (UTC) with a strftime-style pattern supporting
+/// `%Y %m %d %H %M %S %s %%`; other characters are copied verbatim.
+pub fn format_timestamp(secs: i64, pattern: &str) -> String {
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let time = secs.rem_euclid(86400);
+    let (hour, minute, second) = (time / 3600, time % 3600 / 60, time % 60);
+
+    let mut out = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => out.push_str(&format!("{year:04}")),
+            Some('m') => out.push_str(&format!("{month:02}")),
+            Some('d') => out.push_str(&format!("{day:02}")),
+            Some('H') => out.push_str(&format!("{hour:02}")),
+            Some('M') => out.push_str(&format!("{minute:02}")),
+            Some('S') => out.push_str(&format!("{second:02}")),
+            Some('s') => out.push_str(&secs.to_string()),
+            Some('%') => out.push('%'),
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            }
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Howard Hinnant's civil calendar algorithms.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("1970-01-01"), Some(0));
+        assert_eq!(parse_timestamp("2024-05-01T12:30:00Z"), Some(1714566600));
+        assert_eq!(
+            parse_timestamp("2024-05-01 14:30:00.250+02:00"),
+            Some(1714566600)
+        );
+        assert_eq!(parse_timestamp("1969-12-31T23:59:59Z"), Some(-1));
+        assert_eq!(parse_timestamp("2024-13-01"), None);
+        assert_eq!(parse_timestamp("2024-02-29"), Some(1709164800));
+        assert_eq!(parse_timestamp("2023-02-29"), None);
+        assert_eq!(parse_timestamp("1900-02-29"), None);
+        assert_eq!(parse_timestamp("2024-04-31"), None);
+        assert_eq!(parse_timestamp("2024-05-00"), None);
+        assert_eq!(parse_timestamp("2024-05-01T24:00:00Z"), None);
+        assert_eq!(parse_timestamp("2024-05-01T12:60:00Z"), None);
+        assert_eq!(parse_timestamp("2024-05-01T12:00:60Z"), None);
+        assert_eq!(parse_timestamp("2024-05-01T12:00:00+01:60"), None);
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        assert_eq!(
+            format_timestamp(1714566600, "%Y/%m/%d %H:%M:%S"),
+            "2024/05/01 12:30:00"
+        );
+        assert_eq!(format_timestamp(-1, "%Y-%m-%d"), "1969-12-31");
+    }
+}
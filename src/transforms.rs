@@ -0,0 +1,547 @@
+//! Common key transforms, usable directly in `key!`, e.g.
+//! `key!("email", "email", transforms::lowercase)`.
+//!
+//! Absent values stay absent, except under `default`. Values a transform
+//! doesn't apply to pass through unchanged, and strings the `parse_*`
+//! transforms can't parse become null.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use serde_json::{Number, Value};
+
+use crate::format::Format;
+use crate::{TransformError, TryTransform};
+
+fn map_string(value: Option<Value>, func: impl Fn(&str) -> String) -> Option<Value> {
+    match value {
+        Some(Value::String(s)) => Some(Value::String(func(&s))),
+        value => value,
+    }
+}
+
+pub fn trim(value: Option<Value>) -> Option<Value> {
+    map_string(value, |s| s.trim().to_string())
+}
+
+pub fn lowercase(value: Option<Value>) -> Option<Value> {
+    map_string(value, str::to_lowercase)
+}
+
+pub fn uppercase(value: Option<Value>) -> Option<Value> {
+    map_string(value, str::to_uppercase)
+}
+
+/// Trims strings and collapses every run of whitespace inside them to one
+/// space.
+pub fn collapse_whitespace(value: Option<Value>) -> Option<Value> {
+    map_string(value, |s| {
+        s.split_whitespace().collect::<Vec<_>>().join(" ")
+    })
+}
+
+/// Removes HTML tags and comments from strings and decodes character
+/// references, e.g. `<p>Fish &amp; chips</p>` becomes `Fish & chips`.
+/// A `<` is only a tag when a letter, `/` or `!` follows, so `1 < 2` is
+/// text; unterminated tags, and unknown or malformed references, are kept
+/// as written.
+pub fn strip_html(value: Option<Value>) -> Option<Value> {
+    map_string(value, |s| {
+        let mut text = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(start) = rest.find('<') {
+            text.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let tag =
+                rest[1..].starts_with(|c: char| c.is_ascii_alphabetic() || c == '/' || c == '!');
+            let close = if rest.starts_with("<!--") { "-->" } else { ">" };
+            match rest.find(close).filter(|_| tag) {
+                Some(end) => rest = &rest[end + close.len()..],
+                None if tag => break,
+                None => {
+                    text.push('<');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        text.push_str(rest);
+        decode_entities(&text)
+    })
+}
+
+fn decode_entities(s: &str) -> String {
+    let mut text = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('&') {
+        text.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest.find(';').filter(|end| *end <= 10).and_then(|end| {
+            let c = match &rest[1..end] {
+                "amp" => '&',
+                "lt" => '<',
+                "gt" => '>',
+                "quot" => '"',
+                "apos" => '\'',
+                "nbsp" => ' ',
+                name => {
+                    let code = match name.strip_prefix("#x").or(name.strip_prefix("#X")) {
+                        Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                        None => name.strip_prefix('#')?.parse().ok()?,
+                    };
+                    char::from_u32(code)?
+                }
+            };
+            Some((c, end))
+        });
+        match decoded {
+            Some((c, end)) => {
+                text.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                text.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
+
+/// Strips accents and other combining marks from strings, e.g. `Zoë Saldaña`
+/// becomes `Zoe Saldana`, behind the `unicode` feature. Letters that don't
+/// decompose, such as `ø` or `ß`, are kept.
+#[cfg(feature = "unicode")]
+pub fn fold_accents(value: Option<Value>) -> Option<Value> {
+    use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+    map_string(value, |s| {
+        s.nfd()
+            .filter(|c| !is_combining_mark(*c))
+            .collect::<String>()
+            .nfc()
+            .collect()
+    })
+}
+
+/// Strings longer than `max` characters cut to `max`, ending in `…`.
+pub fn truncate(max: usize) -> impl Fn(Option<Value>) -> Option<Value> + Send + Sync {
+    move |value| {
+        map_string(value, |s| match s.char_indices().nth(max) {
+            Some(_) if max == 0 => String::new(),
+            Some(_) => {
+                let end = s.char_indices().nth(max - 1).map_or(0, |(i, _)| i);
+                format!("{}…", &s[..end])
+            }
+            None => s.to_string(),
+        })
+    }
+}
+
+/// Numbers and booleans as their text, objects and arrays as JSON text.
+/// Nulls stay null.
+pub fn to_string(value: Option<Value>) -> Option<Value> {
+    match value {
+        Some(Value::Null) | Some(Value::String(_)) | None => value,
+        Some(other) => Some(Value::String(other.to_string())),
+    }
+}
+
+/// Integers from strings and whole floats; other floats become null.
+pub fn parse_int(value: Option<Value>) -> Option<Value> {
+    let int = match &value {
+        Some(Value::String(s)) => s.trim().parse::<i64>().ok(),
+        Some(Value::Number(n)) if n.is_i64() || n.is_u64() => return value,
+        Some(Value::Number(n)) => n
+            .as_f64()
+            .filter(|f| f.fract() == 0.0 && f.abs() < i64::MAX as f64)
+            .map(|f| f as i64),
+        _ => return value,
+    };
+    Some(int.map_or(Value::Null, Value::from))
+}
+
+pub fn parse_float(value: Option<Value>) -> Option<Value> {
+    let float = match &value {
+        Some(Value::String(s)) => s.trim().parse::<f64>().ok(),
+        Some(Value::Number(n)) => n.as_f64(),
+        _ => return value,
+    };
+    Some(
+        float
+            .and_then(Number::from_f64)
+            .map_or(Value::Null, Value::Number),
+    )
+}
+
+/// `true`/`false`, `t`/`f`, `yes`/`no`, `y`/`n` and `1`/`0` in any case,
+/// and the numbers `1` and `0`.
+pub fn parse_bool(value: Option<Value>) -> Option<Value> {
+    let b = match &value {
+        Some(Value::String(s)) => match s.trim().to_ascii_lowercase().as_str() {
+            "true" | "t" | "yes" | "y" | "1" => Some(true),
+            "false" | "f" | "no" | "n" | "0" => Some(false),
+            _ => None,
+        },
+        Some(Value::Number(n)) => match n.as_f64() {
+            Some(1.0) => Some(true),
+            Some(0.0) => Some(false),
+            _ => None,
+        },
+        _ => return value,
+    };
+    Some(b.map_or(Value::Null, Value::Bool))
+}
+
+/// Empty or all-whitespace strings become null.
+pub fn null_if_empty(value: Option<Value>) -> Option<Value> {
+    match value {
+        Some(Value::String(s)) if s.trim().is_empty() => Some(Value::Null),
+        value => value,
+    }
+}
+
+/// Durations such as `"2h30m"`, `"1.5 s"` or `"500ms"` as seconds; bare
+/// numbers in strings are taken as seconds already. See `duration` for
+/// units.
+pub fn parse_seconds(value: Option<Value>) -> Option<Value> {
+    parse_with(value, |s| duration(s, 1.0))
+}
+
+/// Durations as milliseconds, like `parse_seconds`; bare numbers in
+/// strings are taken as milliseconds already.
+pub fn parse_millis(value: Option<Value>) -> Option<Value> {
+    parse_with(value, |s| duration(s, 1000.0))
+}
+
+/// Sizes such as `"1.5 GiB"`, `"10MB"` or `"512k"` as whole bytes. Units
+/// are case-insensitive and the trailing `B` optional: `k`, `M`, `G`, `T`
+/// and `P` are powers of 1000, `Ki`, `Mi`, `Gi`, `Ti` and `Pi` powers of
+/// 1024, and bare numbers bytes.
+pub fn parse_bytes(value: Option<Value>) -> Option<Value> {
+    parse_with(value, |s| {
+        let s = s.trim();
+        let split = s
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(s.len());
+        let (amount, unit) = s.split_at(split);
+        let unit = unit.trim().to_ascii_lowercase();
+        let unit = match unit.as_str() {
+            "byte" | "bytes" => "",
+            unit => unit.strip_suffix('b').unwrap_or(unit),
+        };
+        let (base, exponent) = match unit.strip_suffix('i') {
+            Some(unit) => (1024f64, unit),
+            None => (1000f64, unit),
+        };
+        let exponent = match exponent {
+            "" if base == 1000.0 => 0,
+            "k" => 1,
+            "m" => 2,
+            "g" => 3,
+            "t" => 4,
+            "p" => 5,
+            _ => return None,
+        };
+        let factor = base.powi(exponent);
+        Some((amount.parse::<f64>().ok()? * factor).round())
+    })
+}
+
+/// Applies `parse` to strings, the result as an integer when whole; numbers
+/// and other values pass through.
+fn parse_with(value: Option<Value>, parse: impl Fn(&str) -> Option<f64>) -> Option<Value> {
+    let parsed = match &value {
+        Some(Value::String(s)) => parse(s),
+        _ => return value,
+    };
+    Some(match parsed {
+        Some(f) if f.fract() == 0.0 && f.abs() < i64::MAX as f64 => Value::from(f as i64),
+        Some(f) => Number::from_f64(f).map_or(Value::Null, Value::Number),
+        None => Value::Null,
+    })
+}
+
+/// The duration `s` in units of which there are `per_second` in a second:
+/// a sequence of amounts with units `ns`, `us` (or `µs`), `ms`, `s`, `m`
+/// (or `min`), `h`, `d` and `w`, optionally separated by spaces, or a bare
+/// number already in those units. Amounts are added up in whole
+/// nanoseconds, so decimals don't pick up float error on the way, e.g.
+/// `"1.1s"` is 1100 milliseconds rather than 1100.0000000000002.
+fn duration(s: &str, per_second: f64) -> Option<f64> {
+    let s = s.trim();
+    if let Ok(bare) = s.parse::<f64>() {
+        return Some(bare);
+    }
+    if s.is_empty() {
+        return None;
+    }
+    let mut rest = s;
+    let mut nanos = 0.0;
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let amount: f64 = rest[..split].parse().ok()?;
+        rest = rest[split..].trim_start();
+        let split = rest
+            .find(|c: char| !c.is_alphabetic())
+            .unwrap_or(rest.len());
+        let factor = match &rest[..split] {
+            "ns" => 1.0,
+            "us" | "µs" => 1e3,
+            "ms" => 1e6,
+            "s" | "sec" => 1e9,
+            "m" | "min" => 60e9,
+            "h" => 3600e9,
+            "d" => 86400e9,
+            "w" => 604800e9,
+            _ => return None,
+        };
+        nanos += (amount * factor).round();
+        rest = rest[split..].trim_start();
+    }
+    Some(nanos * per_second / 1e9)
+}
+
+/// The ISO 639-3 code of the language of free text, e.g. `"eng"`, behind
+/// the `language` feature. Only detections whatlang deems reliable are
+/// kept: short text, even a whole sentence, tends to become null rather
+/// than a guess, as do non-strings.
+#[cfg(feature = "language")]
+pub fn detect_language(value: Option<Value>) -> Option<Value> {
+    let language = match value? {
+        Value::String(s) => whatlang::detect(&s).filter(|info| info.is_reliable()),
+        _ => None,
+    };
+    Some(language.map_or(Value::Null, |info| Value::from(info.lang().code())))
+}
+
+/// Numbers rounded to `places` decimal places. Unlike `Format::Precision`,
+/// the rounded value is what gets extracted.
+pub fn round(places: usize) -> impl Fn(Option<Value>) -> Option<Value> + Send + Sync {
+    let format = Format::Precision(places);
+    move |value| value.map(|v| format.apply(&v))
+}
+
+/// Replaces absent and null values with `default`.
+pub fn default(default: Value) -> impl Fn(Option<Value>) -> Option<Value> + Send + Sync {
+    move |value| match value {
+        None | Some(Value::Null) => Some(default.clone()),
+        value => value,
+    }
+}
+
+/// When a `chain` stops early.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ChainPolicy {
+    /// Run every step, passing absent values on; an error fails extraction.
+    #[default]
+    PassNone,
+    /// A step returning an absent value ends the chain with it; an error
+    /// fails extraction.
+    StopOnNone,
+    /// Like `StopOnNone`, but an error also ends the chain with an absent
+    /// value instead of failing extraction.
+    NoneOnError,
+}
+
+/// Applies `steps` left to right, e.g.
+/// `chain(vec![fallible(trim), fallible(lowercase)], ChainPolicy::StopOnNone)`
+/// with `crate::fallible` wrapping plain transforms.
+pub fn chain(steps: Vec<TryTransform>, policy: ChainPolicy) -> TryTransform {
+    Box::new(move |mut value| {
+        for step in steps.iter() {
+            if value.is_none() && policy != ChainPolicy::PassNone {
+                break;
+            }
+            value = match step(value) {
+                Err(_) if policy == ChainPolicy::NoneOnError => return Ok(None),
+                result => result?,
+            };
+        }
+        Ok(value)
+    })
+}
+
+/// Memoizes `func` by input value, for expensive transforms such as
+/// user-agent parsing or geo lookups over feeds that repeat the same values.
+/// Errors are cached too. Holds up to `capacity` results before clearing
+/// and starting over; the lock isn't held while `func` runs, so concurrent
+/// misses on the same value may each call it.
+pub fn cached<F>(
+    func: F,
+    capacity: usize,
+) -> impl Fn(Option<Value>) -> Result<Option<Value>, TransformError> + Send + Sync
+where
+    F: Fn(Option<Value>) -> Result<Option<Value>, TransformError> + Send + Sync,
+{
+    type Results = HashMap<String, Result<Option<Value>, TransformError>>;
+    let results: Mutex<Results> = Mutex::new(HashMap::new());
+    move |value| {
+        // Serialized values are never empty, leaving "" for absent ones.
+        let key = value.as_ref().map_or(String::new(), Value::to_string);
+        if let Some(result) = results.lock().unwrap_or_else(|e| e.into_inner()).get(&key) {
+            return result.clone();
+        }
+        let result = func(value);
+        let mut results = results.lock().unwrap_or_else(|e| e.into_inner());
+        if results.len() >= capacity {
+            results.clear();
+        }
+        results.insert(key, result.clone());
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn string_and_parse_transforms() {
+        assert_eq!(trim(Some(json!(" a "))), Some(json!("a")));
+        assert_eq!(uppercase(Some(json!(1))), Some(json!(1)));
+        assert_eq!(to_string(Some(json!([1]))), Some(json!("[1]")));
+        assert_eq!(parse_int(Some(json!(" 42 "))), Some(json!(42)));
+        assert_eq!(parse_int(Some(json!(2.5))), Some(Value::Null));
+        assert_eq!(parse_float(Some(json!("1.5"))), Some(json!(1.5)));
+        assert_eq!(parse_bool(Some(json!("Yes"))), Some(json!(true)));
+        assert_eq!(parse_bool(Some(json!("maybe"))), Some(Value::Null));
+        assert_eq!(null_if_empty(Some(json!("  "))), Some(Value::Null));
+        assert_eq!(parse_int(None), None);
+    }
+
+    #[test]
+    fn parses_durations_and_sizes() {
+        assert_eq!(parse_seconds(Some(json!("2h30m"))), Some(json!(9000)));
+        assert_eq!(parse_seconds(Some(json!("500ms"))), Some(json!(0.5)));
+        assert_eq!(parse_seconds(Some(json!("1m 1.5s"))), Some(json!(61.5)));
+        assert_eq!(parse_seconds(Some(json!(" 90 "))), Some(json!(90)));
+        assert_eq!(parse_millis(Some(json!("1.5s"))), Some(json!(1500)));
+        assert_eq!(parse_millis(Some(json!("0.7s"))), Some(json!(700)));
+        assert_eq!(parse_millis(Some(json!("1.1s 0.3ms"))), Some(json!(1100.3)));
+        assert_eq!(parse_seconds(Some(json!("0.1s 0.2s"))), Some(json!(0.3)));
+        assert_eq!(parse_millis(Some(json!("250"))), Some(json!(250)));
+        assert_eq!(
+            parse_seconds(Some(json!("2 fortnights"))),
+            Some(Value::Null)
+        );
+        assert_eq!(parse_seconds(Some(json!("h"))), Some(Value::Null));
+        assert_eq!(parse_seconds(Some(json!(""))), Some(Value::Null));
+        assert_eq!(parse_seconds(Some(json!(3))), Some(json!(3)));
+
+        assert_eq!(parse_bytes(Some(json!("1.5 GiB"))), Some(json!(1610612736)));
+        assert_eq!(parse_bytes(Some(json!("10MB"))), Some(json!(10_000_000)));
+        assert_eq!(parse_bytes(Some(json!("512k"))), Some(json!(512_000)));
+        assert_eq!(parse_bytes(Some(json!("2 Ki"))), Some(json!(2048)));
+        assert_eq!(parse_bytes(Some(json!("7 bytes"))), Some(json!(7)));
+        assert_eq!(parse_bytes(Some(json!("3 XB"))), Some(Value::Null));
+        assert_eq!(parse_bytes(None), None);
+    }
+
+    #[cfg(feature = "language")]
+    #[test]
+    fn detects_languages() {
+        let text = "The delivery arrived two days late and the box was damaged, \
+                    but the support team replaced everything without any questions.";
+        assert_eq!(detect_language(Some(json!(text))), Some(json!("eng")));
+        let pangram = "the quick brown fox jumps over the lazy dog";
+        assert_eq!(detect_language(Some(json!(pangram))), Some(Value::Null));
+        assert_eq!(detect_language(Some(json!(""))), Some(Value::Null));
+        assert_eq!(detect_language(Some(json!(1))), Some(Value::Null));
+        assert_eq!(detect_language(None), None);
+    }
+
+    #[test]
+    fn text_normalization() {
+        assert_eq!(
+            collapse_whitespace(Some(json!(" a \t b\n\nc "))),
+            Some(json!("a b c"))
+        );
+        assert_eq!(
+            strip_html(Some(json!(
+                "<p class=\"x\">Fish &amp; chips<!-- <b>not</b> --></p> &#233;&#xE9; &bogus; 1 < 2 <3"
+            ))),
+            Some(json!("Fish & chips éé &bogus; 1 < 2 <3"))
+        );
+        assert_eq!(
+            strip_html(Some(json!("a <b>b</b> <i class=\"x"))),
+            Some(json!("a b <i class=\"x"))
+        );
+        let short = truncate(5);
+        assert_eq!(short(Some(json!("abcdef"))), Some(json!("abcd…")));
+        assert_eq!(short(Some(json!("abcde"))), Some(json!("abcde")));
+        assert_eq!(truncate(0)(Some(json!("a"))), Some(json!("")));
+    }
+
+    #[cfg(feature = "unicode")]
+    #[test]
+    fn folds_accents() {
+        assert_eq!(
+            fold_accents(Some(json!("Müller café"))),
+            Some(json!("Muller cafe"))
+        );
+    }
+
+    #[test]
+    fn configured_transforms() {
+        assert_eq!(round(1)(Some(json!(2.46))), Some(json!(2.5)));
+        let unknown = default(json!("unknown"));
+        assert_eq!(unknown(None), Some(json!("unknown")));
+        assert_eq!(unknown(Some(json!("x"))), Some(json!("x")));
+    }
+
+    #[test]
+    fn chains_apply_left_to_right() {
+        let steps = || -> Vec<TryTransform> {
+            vec![
+                crate::fallible(|_| None),
+                crate::fallible(default(json!(0))),
+                Box::new(|_| Err(crate::TransformError::new("no"))),
+            ]
+        };
+        assert!(chain(steps(), ChainPolicy::PassNone)(None).is_err());
+        assert_eq!(
+            chain(steps(), ChainPolicy::StopOnNone)(Some(json!(1))),
+            Ok(None)
+        );
+
+        let steps = vec![
+            crate::fallible(trim),
+            crate::fallible(lowercase),
+            Box::new(|_| Err(crate::TransformError::new("no"))) as TryTransform,
+        ];
+        assert_eq!(
+            chain(steps, ChainPolicy::NoneOnError)(Some(json!(" A "))),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn cached_transforms_run_once_per_value() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let calls = AtomicUsize::new(0);
+        let lookup = cached(
+            |value: Option<Value>| {
+                calls.fetch_add(1, Ordering::Relaxed);
+                match value {
+                    Some(Value::String(s)) => Ok(Some(json!(s.len()))),
+                    Some(_) => Err(TransformError::new("not a string")),
+                    None => Ok(None),
+                }
+            },
+            2,
+        );
+
+        for _ in 0..3 {
+            assert_eq!(lookup(Some(json!("abc"))), Ok(Some(json!(3))));
+            assert_eq!(lookup(None), Ok(None));
+        }
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+        assert!(lookup(Some(json!(1))).is_err());
+        assert!(lookup(Some(json!(1))).is_err());
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+    }
+}
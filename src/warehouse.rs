@@ -0,0 +1,416 @@
+//! Type-mapping profiles for loading flattened records into a warehouse:
+//! column types for DDL and text renderings its loaders accept.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use serde_json::{json, Value};
+
+use crate::format::Format;
+use crate::{time, Record};
+
+/// A column type, inferred from values or declared through a `Format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Boolean,
+    Integer,
+    Float,
+    String,
+    /// Strings `time::parse_timestamp` accepts.
+    Timestamp,
+    /// Objects and arrays.
+    Json,
+}
+
+impl ColumnType {
+    /// Type of a single value, or `None` for null.
+    pub fn of(value: &Value) -> Option<Self> {
+        match value {
+            Value::Null => None,
+            Value::Bool(_) => Some(Self::Boolean),
+            Value::Number(n) if n.is_f64() => Some(Self::Float),
+            Value::Number(_) => Some(Self::Integer),
+            Value::String(s) if time::parse_timestamp(s).is_some() => Some(Self::Timestamp),
+            Value::String(_) => Some(Self::String),
+            Value::Array(_) | Value::Object(_) => Some(Self::Json),
+        }
+    }
+
+    /// Type of the column as a sink renders it with `format`.
+    pub fn declared(format: &Format) -> Self {
+        match format {
+            Format::Date(_) | Format::PadLeft(_, _) | Format::PadRight(_, _) => Self::String,
+            Format::Precision(_) => Self::Float,
+            Format::BoolAsInt => Self::Integer,
+        }
+    }
+
+    /// The narrowest type holding values of both types.
+    pub fn widen(self, other: Self) -> Self {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (Self::Integer, Self::Float) | (Self::Float, Self::Integer) => Self::Float,
+            (Self::Json, _) | (_, Self::Json) => Self::Json,
+            _ => Self::String,
+        }
+    }
+}
+
+/// Infers column types from records as they stream past. Columns declared
+/// with a `Format` keep the declared type.
+#[derive(Debug, Default)]
+pub struct TypeInference {
+    columns: Vec<String>,
+    types: HashMap<String, ColumnType>,
+    declared: HashMap<String, ColumnType>,
+}
+
+impl TypeInference {
+    /// Starts from `columns` in schema order, usually `SubSchema::columns()`
+    /// and `SubSchema::formats()`.
+    pub fn new(columns: Vec<String>, formats: &HashMap<String, Format>) -> Self {
+        TypeInference {
+            columns,
+            types: HashMap::new(),
+            declared: formats
+                .iter()
+                .map(|(column, format)| (column.clone(), ColumnType::declared(format)))
+                .collect(),
+        }
+    }
+
+    pub fn observe(&mut self, record: &Record) {
+        for (name, value) in record {
+            let ty = match value.as_ref().and_then(ColumnType::of) {
+                Some(ty) => ty,
+                None => continue,
+            };
+            if !self.columns.contains(name) {
+                self.columns.push(name.clone());
+            }
+            self.types
+                .entry(name.clone())
+                .and_modify(|seen| *seen = seen.widen(ty))
+                .or_insert(ty);
+        }
+    }
+
+    /// Every column with its type; columns only ever null are `String`.
+    pub fn types(&self) -> Vec<(String, ColumnType)> {
+        self.columns
+            .iter()
+            .map(|column| {
+                let ty = self
+                    .declared
+                    .get(column)
+                    .or_else(|| self.types.get(column))
+                    .copied()
+                    .unwrap_or(ColumnType::String);
+                (column.clone(), ty)
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Warehouse {
+    BigQuery,
+    Snowflake,
+    Redshift,
+    Postgres,
+}
+
+impl FromStr for Warehouse {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "bigquery" => Ok(Self::BigQuery),
+            "snowflake" => Ok(Self::Snowflake),
+            "redshift" => Ok(Self::Redshift),
+            "postgres" | "postgresql" => Ok(Self::Postgres),
+            _ => Err(format!("unknown warehouse {s:?}")),
+        }
+    }
+}
+
+impl Warehouse {
+    pub fn type_name(&self, ty: ColumnType) -> &'static str {
+        use ColumnType::*;
+        match (self, ty) {
+            (Self::BigQuery, Boolean) => "BOOL",
+            (Self::BigQuery, Integer) => "INT64",
+            (Self::BigQuery, Float) => "FLOAT64",
+            (Self::BigQuery, String) => "STRING",
+            (Self::BigQuery, Timestamp) => "TIMESTAMP",
+            (Self::BigQuery, Json) => "JSON",
+            (Self::Snowflake, Boolean) => "BOOLEAN",
+            (Self::Snowflake, Integer) => "NUMBER(38,0)",
+            (Self::Snowflake, Float) => "FLOAT",
+            (Self::Snowflake, String) => "VARCHAR",
+            (Self::Snowflake, Timestamp) => "TIMESTAMP_TZ",
+            (Self::Snowflake, Json) => "VARIANT",
+            (Self::Redshift, Boolean) => "BOOLEAN",
+            (Self::Redshift, Integer) => "BIGINT",
+            (Self::Redshift, Float) => "DOUBLE PRECISION",
+            (Self::Redshift, String) => "VARCHAR(65535)",
+            (Self::Redshift, Timestamp) => "TIMESTAMPTZ",
+            (Self::Redshift, Json) => "SUPER",
+            (Self::Postgres, Boolean) => "boolean",
+            (Self::Postgres, Integer) => "bigint",
+            (Self::Postgres, Float) => "double precision",
+            (Self::Postgres, String) => "text",
+            (Self::Postgres, Timestamp) => "timestamptz",
+            (Self::Postgres, Json) => "jsonb",
+        }
+    }
+
+    /// Quotes an identifier for use in this warehouse's SQL.
+    pub fn quote(&self, identifier: &str) -> String {
+        match self {
+            Self::BigQuery => format!("`{}`", identifier.replace('`', "\\`")),
+            _ => format!("\"{}\"", identifier.replace('"', "\"\"")),
+        }
+    }
+
+    /// A `CREATE TABLE` statement for `columns`, e.g. from
+    /// `TypeInference::types`. Names are quoted, not sanitized; see
+    /// `naming::Target` for that.
+    pub fn ddl(&self, table: &str, columns: &[(String, ColumnType)]) -> String {
+        let columns: Vec<String> = columns
+            .iter()
+            .map(|(name, ty)| format!("  {} {}", self.quote(name), self.type_name(*ty)))
+            .collect();
+        format!(
+            "CREATE TABLE {} (\n{}\n);\n",
+            self.quote(table),
+            columns.join(",\n")
+        )
+    }
+
+    /// Migration hints for `diff`: an `ALTER TABLE ... ADD COLUMN` per added
+    /// column, typed from `types` (else `String`), and a comment per
+    /// removed column, which is left for a person to drop once nothing
+    /// reads it.
+    pub fn migration(
+        &self,
+        table: &str,
+        diff: &ColumnDiff,
+        types: &[(String, ColumnType)],
+    ) -> String {
+        let mut statements = String::new();
+        for column in diff.added.iter() {
+            let ty = types
+                .iter()
+                .find(|(name, _)| name == column)
+                .map_or(ColumnType::String, |(_, ty)| *ty);
+            statements.push_str(&format!(
+                "ALTER TABLE {} ADD COLUMN {} {};\n",
+                self.quote(table),
+                self.quote(column),
+                self.type_name(ty)
+            ));
+        }
+        for column in diff.removed.iter() {
+            statements.push_str(&format!(
+                "-- {} is no longer extracted; drop it once nothing reads it\n",
+                self.quote(column)
+            ));
+        }
+        statements
+    }
+
+    /// Renders a value of a column of type `ty` the way the warehouse's
+    /// bulk loader reads it, or `None` where the default text rendering
+    /// already works. Timestamps are written in UTC.
+    pub fn render(&self, ty: ColumnType, value: &Value) -> Option<String> {
+        match (ty, value) {
+            (ColumnType::Timestamp, _) => {
+                let pattern = match self {
+                    Self::BigQuery => "%Y-%m-%d %H:%M:%S UTC",
+                    Self::Snowflake => "%Y-%m-%dT%H:%M:%SZ",
+                    Self::Redshift | Self::Postgres => "%Y-%m-%d %H:%M:%S+00",
+                };
+                time::timestamp_of(value).map(|secs| time::format_timestamp(secs, pattern))
+            }
+            (ColumnType::Boolean, Value::Bool(b)) => Some(match self {
+                Self::BigQuery => b.to_string(),
+                Self::Snowflake => b.to_string().to_uppercase(),
+                Self::Redshift | Self::Postgres => if *b { "t" } else { "f" }.to_string(),
+            }),
+            (ColumnType::Json, _) | (_, Value::Array(_) | Value::Object(_)) => {
+                Some(value.to_string())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Columns added and removed between two versions of a schema, in schema
+/// order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ColumnDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl ColumnDiff {
+    pub fn new(previous: &[String], current: &[String]) -> Self {
+        ColumnDiff {
+            added: current
+                .iter()
+                .filter(|column| !previous.contains(column))
+                .cloned()
+                .collect(),
+            removed: previous
+                .iter()
+                .filter(|column| !current.contains(column))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// The configuration of a BigQuery load job (the body of `jobs.insert`)
+/// appending newline-delimited JSON at `source_uris` to
+/// `project.dataset.table`, with the table schema taken from `columns`.
+/// Submitting the job is left to the caller's Google Cloud client.
+pub fn bigquery_load_job(
+    project: &str,
+    dataset: &str,
+    table: &str,
+    columns: &[(String, ColumnType)],
+    source_uris: &[String],
+) -> Value {
+    let fields: Vec<Value> = columns
+        .iter()
+        .map(|(name, ty)| {
+            json!({
+                "name": name.clone(),
+                "type": Warehouse::BigQuery.type_name(*ty),
+                "mode": "NULLABLE",
+            })
+        })
+        .collect();
+    json!({
+        "configuration": {
+            "load": {
+                "destinationTable": {
+                    "projectId": project,
+                    "datasetId": dataset,
+                    "tableId": table,
+                },
+                "sourceUris": source_uris.to_vec(),
+                "sourceFormat": "NEWLINE_DELIMITED_JSON",
+                "schema": {"fields": fields},
+                "createDisposition": "CREATE_IF_NEEDED",
+                "writeDisposition": "WRITE_APPEND",
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn infers_and_renders_ddl() {
+        let records: Vec<Record> = vec![
+            vec![
+                ("id".into(), Some(json!(1))),
+                ("score".into(), Some(json!(2))),
+                ("at".into(), Some(json!("2024-05-01T12:30:00Z"))),
+                ("tags".into(), None),
+            ],
+            vec![
+                ("id".into(), Some(json!(2))),
+                ("score".into(), Some(json!(2.5))),
+                ("at".into(), Some(json!("2024-05-02"))),
+                ("tags".into(), Some(json!(["a"]))),
+            ],
+        ];
+        let formats = HashMap::from([("id".to_string(), Format::PadLeft(8, '0'))]);
+        let mut inference = TypeInference::new(vec!["id".into()], &formats);
+        for record in &records {
+            inference.observe(record);
+        }
+
+        assert_eq!(
+            Warehouse::BigQuery.ddl("events", &inference.types()),
+            "CREATE TABLE `events` (\n  `id` STRING,\n  `score` FLOAT64,\n  \
+             `at` TIMESTAMP,\n  `tags` JSON\n);\n"
+        );
+        assert_eq!(
+            "postgres"
+                .parse::<Warehouse>()
+                .unwrap()
+                .type_name(ColumnType::Json),
+            "jsonb"
+        );
+    }
+
+    #[test]
+    fn renders_values_for_loaders() {
+        let at = json!("2024-05-01T14:30:00+02:00");
+        assert_eq!(
+            Warehouse::BigQuery.render(ColumnType::Timestamp, &at),
+            Some("2024-05-01 12:30:00 UTC".into())
+        );
+        assert_eq!(
+            Warehouse::Redshift.render(ColumnType::Timestamp, &at),
+            Some("2024-05-01 12:30:00+00".into())
+        );
+        assert_eq!(
+            Warehouse::Postgres.render(ColumnType::Boolean, &json!(true)),
+            Some("t".into())
+        );
+        assert_eq!(
+            Warehouse::Snowflake.render(ColumnType::Json, &json!({"a": 1})),
+            Some("{\"a\":1}".into())
+        );
+        assert_eq!(
+            Warehouse::Snowflake.render(ColumnType::Integer, &json!(1)),
+            None
+        );
+    }
+
+    #[test]
+    fn migration_hints_from_column_diff() {
+        let previous = vec!["id".to_string(), "fax".to_string()];
+        let current = vec!["id".to_string(), "score".to_string(), "tags".to_string()];
+        let diff = ColumnDiff::new(&previous, &current);
+        assert_eq!(diff.added, vec!["score", "tags"]);
+
+        let types = vec![("score".to_string(), ColumnType::Float)];
+        assert_eq!(
+            Warehouse::Postgres.migration("events", &diff, &types),
+            "ALTER TABLE \"events\" ADD COLUMN \"score\" double precision;\n\
+             ALTER TABLE \"events\" ADD COLUMN \"tags\" text;\n\
+             -- \"fax\" is no longer extracted; drop it once nothing reads it\n"
+        );
+        assert!(ColumnDiff::new(&current, &current).is_empty());
+    }
+
+    #[test]
+    fn bigquery_load_job_schema() {
+        let columns = vec![
+            ("id".to_string(), ColumnType::Integer),
+            ("at".to_string(), ColumnType::Timestamp),
+        ];
+        let uris = vec!["gs://feeds/events-*.jsonl".to_string()];
+        let job = bigquery_load_job("acme", "raw", "events", &columns, &uris);
+
+        let load = &job["configuration"]["load"];
+        assert_eq!(load["destinationTable"]["tableId"], json!("events"));
+        assert_eq!(
+            load["schema"]["fields"][1],
+            json!({"name": "at", "type": "TIMESTAMP", "mode": "NULLABLE"})
+        );
+    }
+}
@@ -0,0 +1,698 @@
+//! Runs the `flatten` binary on files in a scratch directory per test.
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use serde_json::{json, Value};
+use serde_test::checksum;
+
+/// A scratch directory named after the test, emptied first.
+fn scratch(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("flatten-{test}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Runs `flatten` in `dir`, returning its exit code, stdout and stderr.
+fn flatten(dir: &PathBuf, args: &[&str]) -> (i32, String, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_flatten"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap();
+    (
+        output.status.code().unwrap(),
+        String::from_utf8(output.stdout).unwrap(),
+        String::from_utf8(output.stderr).unwrap(),
+    )
+}
+
+#[test]
+fn replays_a_recorded_run_to_a_new_output() {
+    let dir = scratch("replay");
+    fs::write(dir.join("schema.yaml"), "children:\n  - key: id\n").unwrap();
+    fs::write(dir.join("in.jsonl"), "{\"id\": 1}\n{\"id\": 2}\n").unwrap();
+    let run = [
+        "--schema",
+        "schema.yaml",
+        "--input",
+        "in.jsonl",
+        "--output",
+        "out.csv",
+        "--run-manifest",
+        "run.json",
+    ];
+    assert_eq!(flatten(&dir, &run).0, 0);
+
+    let (code, _, stderr) = flatten(
+        &dir,
+        &[
+            "replay",
+            "--manifest",
+            "run.json",
+            "--output",
+            "replayed.csv",
+        ],
+    );
+    assert_eq!(code, 0, "{stderr}");
+    let original = fs::read(dir.join("out.csv")).unwrap();
+    assert_eq!(original, b"id\n1\n2\n");
+    assert_eq!(fs::read(dir.join("replayed.csv")).unwrap(), original);
+
+    fs::write(dir.join("in.jsonl"), "{\"id\": 3}\n").unwrap();
+    let (code, _, stderr) = flatten(&dir, &["replay", "--manifest", "run.json"]);
+    assert_eq!(code, 1);
+    assert!(
+        stderr.contains("in.jsonl changed since the recorded run"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn exits_with_the_kind_of_failure() {
+    let dir = scratch("exit-codes");
+    fs::write(
+        dir.join("schema.yaml"),
+        "children:\n  - key: id\n    required: true\n",
+    )
+    .unwrap();
+    fs::write(dir.join("broken.yaml"), "children: [").unwrap();
+    fs::write(dir.join("good.jsonl"), "{\"id\": 1}\n").unwrap();
+    fs::write(dir.join("some.jsonl"), "{\"id\": 1}\nnot json\n").unwrap();
+    fs::write(dir.join("none.json"), "{\"id\": 1}\n{\n  \"id\": 2\n").unwrap();
+    fs::write(dir.join("missing.jsonl"), "{\"other\": 1}\n").unwrap();
+    fs::write(dir.join("repeated.jsonl"), "{\"id\": 1, \"id\": 2}\n").unwrap();
+    fs::write(dir.join("repeated.json"), "[{\"id\": 1, \"id\": 2}]").unwrap();
+    fs::create_dir_all(dir.join("taken")).unwrap();
+    let run = |input: &str, extra: &[&str]| {
+        let args = [
+            "--schema",
+            "schema.yaml",
+            "--input",
+            input,
+            "--output",
+            "out.csv",
+        ];
+        flatten(&dir, &[&args[..], extra].concat()).0
+    };
+
+    assert_eq!(run("good.jsonl", &[]), 0);
+    assert_eq!(run("absent.jsonl", &[]), 1);
+    assert_eq!(run("good.jsonl", &["--no-such-flag"]), 2);
+    assert_eq!(run("none.json", &[]), 3);
+    assert_eq!(run("some.jsonl", &["--strict"]), 3);
+    let (code, _, _) = flatten(&dir, &["--schema", "broken.yaml", "--input", "good.jsonl"]);
+    assert_eq!(code, 4);
+    assert_eq!(run("missing.jsonl", &["--strict"]), 5);
+    assert_eq!(run("repeated.jsonl", &["--strict"]), 5);
+    assert_eq!(run("repeated.json", &["--strict"]), 5);
+    assert_eq!(run("some.jsonl", &["--max-failures", "0"]), 5);
+    let (code, _, _) = flatten(
+        &dir,
+        &[
+            "--schema",
+            "schema.yaml",
+            "--input",
+            "good.jsonl",
+            "--output",
+            "taken",
+        ],
+    );
+    assert_eq!(code, 6);
+    assert_eq!(run("some.jsonl", &[]), 7);
+    assert_eq!(fs::read_to_string(dir.join("out.csv")).unwrap(), "id\n1\n");
+}
+
+#[test]
+fn lists_the_output_in_its_manifest() {
+    let dir = scratch("output-manifest");
+    fs::write(dir.join("schema.yaml"), "children:\n  - key: id\n").unwrap();
+    fs::write(
+        dir.join("in.json"),
+        "[{\"id\": 1}, {\"id\": 2}, {\"id\": 3}]",
+    )
+    .unwrap();
+    let args = [
+        "--schema",
+        "schema.yaml",
+        "--input",
+        "in.json",
+        "--output",
+        "out.csv",
+        "--output-manifest",
+        "manifest.json",
+    ];
+    let (code, _, stderr) = flatten(&dir, &args);
+    assert_eq!(code, 0, "{stderr}");
+
+    let text = fs::read_to_string(dir.join("manifest.json")).unwrap();
+    let manifest: Value = serde_json::from_str(&text).unwrap();
+    let output = dir.join("out.csv");
+    assert_eq!(
+        manifest,
+        json!({"files": [{
+            "path": "out.csv",
+            "records": 3,
+            "bytes": fs::metadata(&output).unwrap().len(),
+            "sha256": checksum::sha256(&output).unwrap(),
+        }]})
+    );
+}
+
+#[test]
+fn runs_a_schemas_tests() {
+    let dir = scratch("schema-tests");
+    let schema = "children:
+  - key: id
+tests:
+  - name: one
+    input: {id: 1}
+    expect: [{id: 1}]
+";
+    fs::write(dir.join("passing.yaml"), schema).unwrap();
+    fs::write(
+        dir.join("failing.yaml"),
+        schema.to_string() + "  - name: two\n    input: {id: 2}\n    expect: [{id: 3}]\n",
+    )
+    .unwrap();
+
+    let (code, stdout, _) = flatten(&dir, &["test", "passing.yaml"]);
+    assert_eq!((code, stdout.as_str()), (0, "ok one\n"));
+    let (code, stdout, stderr) = flatten(&dir, &["test", "failing.yaml"]);
+    assert_eq!(code, 5);
+    assert!(stdout.starts_with("ok one\nFAILED two: "), "{stdout}");
+    assert!(stderr.contains("1 of 2 schema tests failed"), "{stderr}");
+}
+
+#[test]
+fn writes_sparse_rows() {
+    let dir = scratch("sparse");
+    fs::write(
+        dir.join("schema.yaml"),
+        "children:\n  - key: id\n  - key: name\n",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("in.jsonl"),
+        "{\"id\": 1, \"name\": \"a\"}\n{\"id\": 2}\n",
+    )
+    .unwrap();
+    let args = ["--schema", "schema.yaml", "--input", "in.jsonl", "--sparse"];
+    let (code, stdout, stderr) = flatten(&dir, &args);
+    assert_eq!(code, 0, "{stderr}");
+    assert_eq!(
+        stdout,
+        "row_id,column,value\n0-0,id,1\n0-0,name,a\n1-0,id,2\n"
+    );
+
+    // Ids are source positions, so skipped documents leave gaps.
+    fs::write(dir.join("in.jsonl"), "{\"id\": 1}\nnot json\n{\"id\": 3}\n").unwrap();
+    let ndjson = [&args[..], &["--ndjson"]].concat();
+    let (code, stdout, _) = flatten(&dir, &ndjson);
+    assert_eq!(code, 7);
+    assert_eq!(stdout, "row_id,column,value\n0-0,id,1\n2-0,id,3\n");
+}
+
+#[test]
+fn skips_documents_not_matching_the_filter() {
+    let dir = scratch("filter-doc");
+    fs::write(dir.join("schema.yaml"), "children:\n  - key: id\n").unwrap();
+    fs::write(
+        dir.join("in.json"),
+        "[{\"id\": 1}, {\"id\": 2}, {\"id\": 3}]",
+    )
+    .unwrap();
+    let args = ["--schema", "schema.yaml", "--input", "in.json"];
+    let (code, stdout, stderr) = flatten(&dir, &[&args[..], &["--filter-doc", "id >= 2"]].concat());
+    assert_eq!(code, 0, "{stderr}");
+    assert_eq!(stdout, "id\n2\n3\n");
+    let (code, _, stderr) = flatten(&dir, &[&args[..], &["--filter-doc", "id >="]].concat());
+    assert_eq!(code, 1);
+    assert!(stderr.contains("--filter-doc: "), "{stderr}");
+}
+
+#[test]
+fn reports_node_metrics_and_columns() {
+    let dir = scratch("report");
+    let schema = "children:\n  - key: id\n  - key: name\n    pii: name\n  - key: email\n";
+    fs::write(dir.join("schema.yaml"), schema).unwrap();
+    let input = "{\"id\": 1, \"name\": \"Ann\"}\n{\"id\": 2, \"name\": \"Bob\"}\nnot json\n";
+    fs::write(dir.join("in.jsonl"), input).unwrap();
+    let args = [
+        "--schema",
+        "schema.yaml",
+        "--input",
+        "in.jsonl",
+        "--report",
+        "report.json",
+        "--report-examples",
+        "2",
+    ];
+    let (code, _, stderr) = flatten(&dir, &args);
+    assert_eq!(code, 7, "{stderr}");
+
+    let report: Value =
+        serde_json::from_str(&fs::read_to_string(dir.join("report.json")).unwrap()).unwrap();
+    assert_eq!(
+        (&report["documents"], &report["records"], &report["skipped"]),
+        (&json!(3), &json!(2), &json!(1))
+    );
+    assert_eq!(
+        report["nodes"]["email"],
+        json!({"matched": 0, "missing": 2, "records": 0, "failed": 0})
+    );
+    let columns = &report["columns"];
+    assert_eq!(columns["id"]["distinct"], json!(2.0));
+    assert_eq!(columns["id"]["max"], json!(2.0));
+    assert_eq!(columns["id"]["examples"], json!([1, 2]));
+    assert_eq!(columns["name"]["examples"], json!(["***", "***"]));
+}
+
+#[test]
+fn refuses_raw_pii_when_asked() {
+    let dir = scratch("raw-pii");
+    let schema = "children:\n  - key: name\n    pii: name\n    anonymize: uppercase\n";
+    fs::write(dir.join("anonymized.yaml"), schema).unwrap();
+    fs::write(
+        dir.join("raw.yaml"),
+        schema.replace("anonymize", "transform"),
+    )
+    .unwrap();
+    fs::write(dir.join("in.jsonl"), "{\"name\": \"ann\"}\n").unwrap();
+    let run = |schema: &str| {
+        let args = ["--schema", schema, "--input", "in.jsonl", "--no-raw-pii"];
+        flatten(&dir, &args)
+    };
+
+    assert_eq!(run("anonymized.yaml").1, "name\nANN\n");
+    let (code, stdout, stderr) = run("raw.yaml");
+    assert_eq!((code, stdout.as_str()), (4, ""));
+    assert!(
+        stderr.contains("PII columns not anonymized [\"name\"]"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn checks_a_configs_assertions() {
+    let dir = scratch("assertions");
+    let schema = "children:\n  - key: id\nassertions:\n  unique: [id]\n";
+    fs::write(dir.join("schema.yaml"), schema).unwrap();
+    fs::write(dir.join("unique.jsonl"), "{\"id\": 1}\n{\"id\": 2}\n").unwrap();
+    fs::write(dir.join("repeated.jsonl"), "{\"id\": 1}\n{\"id\": 1}\n").unwrap();
+    let run = |input: &str| {
+        let args = [
+            "--schema",
+            "schema.yaml",
+            "--input",
+            input,
+            "--output",
+            "out.csv",
+            "--output-manifest",
+            "manifest.json",
+        ];
+        flatten(&dir, &args)
+    };
+
+    assert_eq!(run("unique.jsonl").0, 0);
+    fs::remove_file(dir.join("manifest.json")).unwrap();
+    let (code, _, stderr) = run("repeated.jsonl");
+    assert_eq!(code, 5);
+    assert!(
+        stderr.contains("assertions failed: id: 1 duplicate values"),
+        "{stderr}"
+    );
+    assert!(!dir.join("manifest.json").exists());
+}
+
+#[test]
+fn samples_records_by_seed() {
+    let dir = scratch("sample");
+    fs::write(dir.join("schema.yaml"), "children:\n  - key: id\n").unwrap();
+    let input: String = (0..100).map(|id| format!("{{\"id\": {id}}}\n")).collect();
+    fs::write(dir.join("in.jsonl"), input).unwrap();
+    let run = |seed: &str| {
+        let args = [
+            "--schema",
+            "schema.yaml",
+            "--input",
+            "in.jsonl",
+            "--sample-rate",
+            "0.3",
+        ];
+        flatten(&dir, &[&args[..], &["--seed", seed]].concat()).1
+    };
+
+    let sampled = run("7");
+    assert_eq!(sampled, run("7"));
+    assert_ne!(sampled, run("8"));
+    let rows = sampled.lines().count() - 1;
+    assert!((15..45).contains(&rows), "{rows}");
+    let args = ["--schema", "schema.yaml", "--sample-rate", "1.5"];
+    assert_eq!(flatten(&dir, &args).0, 2);
+}
+
+#[test]
+fn skips_documents_out_of_the_time_range() {
+    let dir = scratch("time-range");
+    fs::write(dir.join("schema.yaml"), "children:\n  - key: id\n").unwrap();
+    let input = "{\"id\": 1, \"at\": \"2024-04-30T23:59:59Z\"}
+{\"id\": 2, \"at\": \"2024-05-01T00:00:00Z\"}
+{\"id\": 3, \"at\": \"2024-05-02T00:00:00Z\"}
+{\"id\": 4}
+";
+    fs::write(dir.join("in.jsonl"), input).unwrap();
+    let args = [
+        "--schema",
+        "schema.yaml",
+        "--input",
+        "in.jsonl",
+        "--time-field",
+        "at",
+        "--since",
+        "2024-05-01",
+        "--until",
+        "2024-05-02T00:00:00Z",
+    ];
+    let (code, stdout, stderr) = flatten(&dir, &args);
+    assert_eq!(code, 0, "{stderr}");
+    assert_eq!(stdout, "id\n2\n");
+    let args = ["--schema", "schema.yaml", "--since", "2024-05-01"];
+    assert_eq!(flatten(&dir, &args).0, 2);
+}
+
+#[test]
+fn renders_csv_for_the_warehouse() {
+    let dir = scratch("warehouse");
+    fs::write(
+        dir.join("schema.yaml"),
+        "children:\n  - key: at\n  - key: ok\n",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("in.jsonl"),
+        "{\"at\": \"2024-05-01T12:30:00Z\", \"ok\": true}\n",
+    )
+    .unwrap();
+    let args = ["--schema", "schema.yaml", "--input", "in.jsonl"];
+    assert_eq!(flatten(&dir, &args).1, "at,ok\n2024-05-01T12:30:00Z,true\n");
+    let bigquery = [&args[..], &["--warehouse", "bigquery"]].concat();
+    assert_eq!(
+        flatten(&dir, &bigquery).1,
+        "at,ok\n2024-05-01 12:30:00 UTC,true\n"
+    );
+    let postgres = [&args[..], &["--warehouse", "postgres"]].concat();
+    assert_eq!(
+        flatten(&dir, &postgres).1,
+        "at,ok\n2024-05-01 12:30:00+00,t\n"
+    );
+}
+
+#[test]
+fn writes_csv_in_the_requested_dialect() {
+    let dir = scratch("dialect");
+    fs::write(
+        dir.join("schema.yaml"),
+        "children:\n  - key: a\n  - key: b\n",
+    )
+    .unwrap();
+    fs::write(dir.join("in.jsonl"), "{\"a\": \"x\", \"b\": null}\n").unwrap();
+    let args = [
+        "--schema",
+        "schema.yaml",
+        "--input",
+        "in.jsonl",
+        "--delimiter",
+        "|",
+        "--null",
+        "\\N",
+        "--crlf",
+    ];
+    assert_eq!(flatten(&dir, &args).1, "a|b\r\nx|\\N\r\n");
+    let bare = [&args[..], &["--no-header", "--quote-all"]].concat();
+    assert_eq!(flatten(&dir, &bare).1, "\"x\"|\\N\r\n");
+}
+
+#[test]
+fn sanitizes_strings_for_spreadsheets() {
+    let dir = scratch("sanitize");
+    fs::write(dir.join("schema.yaml"), "children:\n  - key: a\n").unwrap();
+    fs::write(dir.join("in.jsonl"), "{\"a\": \"=1+1\\u0007\"}\n").unwrap();
+    let args = ["--schema", "schema.yaml", "--input", "in.jsonl"];
+    assert_eq!(flatten(&dir, &args).1, "a\n=1+1\u{7}\n");
+    let safe = [&args[..], &["--sanitize-formulas", "--strip-control-chars"]].concat();
+    assert_eq!(flatten(&dir, &safe).1, "a\n'=1+1\n");
+    let json = [&safe[..], &["--format", "json"]].concat();
+    assert_eq!(flatten(&dir, &json).1, "{\"a\":\"'=1+1\"}\n");
+}
+
+#[test]
+fn encodes_csv_output() {
+    let dir = scratch("encoding");
+    fs::write(dir.join("schema.yaml"), "children:\n  - key: a\n").unwrap();
+    fs::write(dir.join("in.jsonl"), "{\"a\": \"cafe\\u0301 \\u2603\"}\n").unwrap();
+    let args = [
+        "--schema",
+        "schema.yaml",
+        "--input",
+        "in.jsonl",
+        "--output",
+        "out.csv",
+    ];
+    let latin1 = [&args[..], &["--encoding", "latin1"]].concat();
+    assert_eq!(flatten(&dir, &latin1).0, 0);
+    assert_eq!(fs::read(dir.join("out.csv")).unwrap(), b"a\ncafe? ?\n");
+    let nfc = [&latin1[..], &["--nfc"]].concat();
+    if cfg!(feature = "unicode") {
+        assert_eq!(flatten(&dir, &nfc).0, 0);
+        assert_eq!(fs::read(dir.join("out.csv")).unwrap(), b"a\ncaf\xe9 ?\n");
+    } else {
+        assert_eq!(flatten(&dir, &nfc).0, 1);
+    }
+    let json = [&latin1[..], &["--format", "json"]].concat();
+    assert_eq!(flatten(&dir, &json).0, 1);
+}
+
+#[test]
+fn commits_concurrent_runs_to_a_shared_output_dir() {
+    let dir = scratch("output-dir");
+    fs::write(dir.join("schema.yaml"), "children:\n  - key: a\n").unwrap();
+    fs::write(dir.join("in.jsonl"), "{\"a\": 1}\n{\"a\": 2}\n").unwrap();
+    let args = [
+        "--schema",
+        "schema.yaml",
+        "--input",
+        "in.jsonl",
+        "--output-dir",
+        "out",
+    ];
+    let runs: Vec<_> = (0..4)
+        .map(|_| {
+            Command::new(env!("CARGO_BIN_EXE_flatten"))
+                .current_dir(&dir)
+                .args(args)
+                .args(["--run-id", "nightly"])
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    for mut run in runs {
+        assert!(run.wait().unwrap().success());
+    }
+    let manifest = fs::read_to_string(dir.join("out/_manifest.jsonl")).unwrap();
+    let mut files: Vec<String> = manifest
+        .lines()
+        .map(|line| {
+            let entry: Value = serde_json::from_str(line).unwrap();
+            assert_eq!(
+                (&entry["run"], &entry["records"]),
+                (&json!("nightly"), &json!(2))
+            );
+            entry["file"].as_str().unwrap().to_string()
+        })
+        .collect();
+    files.sort();
+    assert_eq!(
+        files,
+        (0..4)
+            .map(|n| format!("part-nightly-0000{n}.csv"))
+            .collect::<Vec<_>>()
+    );
+    let part = fs::read_to_string(dir.join("out").join(&files[0])).unwrap();
+    assert_eq!(part, "a\n1\n2\n");
+    assert!(!dir.join("out/_manifest.lock").exists());
+
+    // Runs failing their assertions leave their part out of the manifest.
+    fs::write(
+        dir.join("schema.yaml"),
+        "children:\n  - key: a\nassertions:\n  min_records: 3\n",
+    )
+    .unwrap();
+    assert_eq!(flatten(&dir, &args).0, 5);
+    let after = fs::read_to_string(dir.join("out/_manifest.jsonl")).unwrap();
+    assert_eq!(after, manifest);
+    assert_eq!(
+        flatten(&dir, &[&args[..], &["--output", "x.csv"]].concat()).0,
+        2
+    );
+}
+
+#[test]
+fn appends_to_existing_output() {
+    let dir = scratch("append");
+    fs::write(
+        dir.join("schema.yaml"),
+        "children:\n  - key: a\n  - key: b\n",
+    )
+    .unwrap();
+    fs::write(dir.join("in.jsonl"), "{\"a\": 1, \"b\": 2}\n").unwrap();
+    let args = [
+        "--schema",
+        "schema.yaml",
+        "--input",
+        "in.jsonl",
+        "--output",
+        "out.csv",
+    ];
+    let append = [&args[..], &["--append"]].concat();
+    assert_eq!(flatten(&dir, &append).0, 0);
+    assert_eq!(flatten(&dir, &append).0, 0);
+    assert_eq!(
+        fs::read_to_string(dir.join("out.csv")).unwrap(),
+        "a,b\n1,2\n1,2\n"
+    );
+
+    // The schema gained `c` and lost `b` since the file was started.
+    fs::write(
+        dir.join("schema.yaml"),
+        "children:\n  - key: a\n  - key: c\n",
+    )
+    .unwrap();
+    let (code, _, stderr) = flatten(&dir, &append);
+    assert_eq!(code, 6);
+    assert!(stderr.contains("added [c], removed [b]"), "{stderr}");
+    let add_null = [&append[..], &["--evolution", "add-null-column"]].concat();
+    assert_eq!(flatten(&dir, &add_null).0, 6);
+    let drop_extra = [&append[..], &["--evolution", "drop-extra"]].concat();
+    assert_eq!(flatten(&dir, &drop_extra).0, 0);
+    assert_eq!(
+        fs::read_to_string(dir.join("out.csv")).unwrap(),
+        "a,b\n1,2\n1,2\n1,\n"
+    );
+
+    assert_eq!(
+        flatten(&dir, &[&args[..], &["--evolution", "fail"]].concat()).0,
+        2
+    );
+}
+
+#[test]
+fn writes_each_table_to_its_own_file() {
+    let dir = scratch("tables");
+    fs::write(
+        dir.join("schema.yaml"),
+        "children:
+  - key: id
+  - sub: items
+    children:
+      - key: sku
+  - sub: payments
+    children:
+      - key: amount
+tables:
+  items: [id, items]
+  payments: [id, payments]
+",
+    )
+    .unwrap();
+    let order =
+        json!({"id": 1, "items": [{"sku": "a"}, {"sku": "b"}], "payments": [{"amount": 5}]});
+    fs::write(dir.join("in.json"), order.to_string()).unwrap();
+    let args = ["--schema", "schema.yaml", "--input", "in.json"];
+    let (code, _, stderr) = flatten(&dir, &[&args[..], &["--tables-dir", "out"]].concat());
+    assert_eq!(code, 0, "{stderr}");
+    let items = fs::read_to_string(dir.join("out/items.csv")).unwrap();
+    assert_eq!(items, "id,items_sku\n1,a\n1,b\n");
+    let payments = fs::read_to_string(dir.join("out/payments.csv")).unwrap();
+    assert_eq!(payments, "id,payments_amount\n1,5\n");
+    assert_eq!(flatten(&dir, &args).0, 1);
+
+    fs::write(
+        dir.join("schema.yaml"),
+        "children:
+  - key: kind
+route:
+  column: kind
+  tables: {click: clicks, view: views}
+",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("in.jsonl"),
+        "{\"kind\": \"click\"}\n{\"kind\": \"buy\"}\n{\"kind\": \"view\"}\n{\"kind\": \"click\"}\n",
+    )
+    .unwrap();
+    let args = [
+        "--schema",
+        "schema.yaml",
+        "--input",
+        "in.jsonl",
+        "--format",
+        "json",
+    ];
+    assert_eq!(
+        flatten(&dir, &[&args[..], &["--tables-dir", "out"]].concat()).0,
+        0
+    );
+    let clicks = fs::read_to_string(dir.join("out/clicks.jsonl")).unwrap();
+    assert_eq!(clicks, "{\"kind\":\"click\"}\n{\"kind\":\"click\"}\n");
+    let views = fs::read_to_string(dir.join("out/views.jsonl")).unwrap();
+    assert_eq!(views, "{\"kind\":\"view\"}\n");
+}
+
+#[test]
+fn skips_inputs_already_processed() {
+    let dir = scratch("idempotent");
+    fs::write(dir.join("schema.yaml"), "children:\n  - key: a\n").unwrap();
+    fs::write(dir.join("in.jsonl"), "{\"a\": 1}\n").unwrap();
+    let args = [
+        "--schema",
+        "schema.yaml",
+        "--input",
+        "in.jsonl",
+        "--idempotent",
+        "state.jsonl",
+        "--output-dir",
+        "out",
+    ];
+    assert_eq!(flatten(&dir, &args).0, 0);
+    assert_eq!(flatten(&dir, &args).0, 0);
+    let manifest = fs::read_to_string(dir.join("out/_manifest.jsonl")).unwrap();
+    assert_eq!(manifest.lines().count(), 1);
+    let state = fs::read_to_string(dir.join("state.jsonl")).unwrap();
+    let entry: Value = serde_json::from_str(state.trim()).unwrap();
+    assert_eq!(
+        entry["sha256"],
+        json!(checksum::sha256(&dir.join("in.jsonl")).unwrap())
+    );
+    assert_eq!(entry["records"], json!(1));
+
+    // A changed input, or the same one through a changed schema, is new.
+    fs::write(dir.join("in.jsonl"), "{\"a\": 2}\n").unwrap();
+    assert_eq!(flatten(&dir, &args).0, 0);
+    fs::write(
+        dir.join("schema.yaml"),
+        "children:\n  - key: a\n  - key: b\n",
+    )
+    .unwrap();
+    assert_eq!(flatten(&dir, &args).0, 0);
+    let manifest = fs::read_to_string(dir.join("out/_manifest.jsonl")).unwrap();
+    assert_eq!(manifest.lines().count(), 3);
+
+    // Inputs whose documents all failed are retried.
+    fs::write(dir.join("in.jsonl"), "not json\n").unwrap();
+    assert_eq!(flatten(&dir, &args).0, 3);
+    assert_eq!(flatten(&dir, &args).0, 3);
+}